@@ -19,26 +19,104 @@ extern char scriptfile[];
 /* default or set in the program arguments */
 extern uint32_t tick;
 
+/* number of unknown or malformed commands, see evt_parse_buf */
+uint32_t cli_errors;
+
+/* operating mode requested with the o command, applied by the FSMs */
+volatile uint32_t cli_mode;
+
+/* highest mode the program has, set by the program before the CLI runs */
+uint32_t cli_mode_max;
+
+/* how a duplicate worker name is handled, see worker_name_set */
+enum dup_policy dup_policy = DUP_ERROR;
+
+/* debug trace selection and format, see utils.h */
+volatile uint32_t debug_flag;
+bool dbg_json;
+
+/* the worker list and its hooks, see workers.h */
+workers_t workers;
+const char *workers_trace;
+void (*workers_run_fn)(void);
+void (*worker_exit_fn)(struct worker *w_p);
+__thread worker_t *worker_cur;
+worker_t *(*workers_spawn_fn)(const char *name);
+
+/* set by a signal handler (e.g. SIGINT), the CLI stops as on the x command */
+volatile sig_atomic_t cli_stop;
+
+/* set by a signal handler (e.g. SIGUSR1), the CLI shows the FSM status */
+volatile sig_atomic_t cli_dump;
+
 /* max number of epoll events to wait for */
 #define MAX_WAIT_EVENTS 1
 
+/**
+ * show_status - show the timers and the state of each worker FSM
+ */
+static void show_status(void)
+{
+	printf("*** FSM status\n");
+	show_timers();
+	show_workers();
+	printf("*** END FSM status\n");
+}
+
+/**
+ * cli_signals - do the CLI work requested by a signal handler
+ *
+ * Called by the CLI thread between commands and when a wait is
+ * interrupted, the handler itself cannot use printf.
+ *
+ * Return: true if the CLI must stop, see cli_stop
+ */
+static bool cli_signals(void)
+{
+	if (cli_dump) {
+		cli_dump = 0;
+		show_status();
+	}
+	return(cli_stop);
+}
+
+/**
+ * cli_nap - sleep the CLI thread
+ * @ms: number of msecs
+ *
+ * A signal interrupts the nap: a status dump is shown and the nap goes
+ * on for the remaining time, a stop ends it.
+ */
+static void cli_nap(uint32_t ms)
+{
+	struct timespec t;
+
+	t.tv_sec = ms / 1000;
+	t.tv_nsec = (ms % 1000) * 1000000L;
+	while (-1 == nanosleep(&t, &t) && EINTR == errno && !cli_signals())
+		;
+}
+
 /**
  * evt_script - load events from a file to added to event queue
  *
  * The input file is reference by the global scriptfile var.
  * 
- * This is just a shell to read a file for symbolic events
+ * This is just a shell to read a file for symbolic events.  The script
+ * ends at its x (or q) command.
+ *
+ * Return: 1 if the script ended with x, 0 at the end of the file
  */
-void evt_script(void)
+int evt_script(void)
 {
 	FILE *fin;
-	int len, i;
+	int len, i, done = 0;
 	char buf[120];
 	
 	if (NULL == (fin=fopen(scriptfile, "r")))
 		die("unknown fname");
 
-	while (NULL != fgets(buf, sizeof(buf), fin)) {
+	while (!cli_signals() && NULL != fgets(buf, sizeof(buf), fin)) {
 
 		/* skip empty lines */
 		if (buf[0] == '\n')
@@ -57,10 +135,75 @@ void evt_script(void)
 			printf("%s: len=%d buf=%s", __func__, len, buf);
 
 		/* call event parser */
-		evt_parse_buf(buf);
+		if ((done = evt_parse_buf(buf)))
+			break;
 	}
 
 	fclose(fin);
+	return(done || cli_stop);
+}
+
+/**
+ * parse_timer - parse the timer id or name following a timer command
+ * @sp_p: pointer to the command char, updated to the last char parsed
+ *
+ * A timer is either a single digit id (e.g. t0) or its name (e.g. tlight).
+ * A missing or unknown timer is a CLI error.
+ *
+ * Return: the timer id or -1 if the timer is unknown
+ */
+static int parse_timer(const char **sp_p)
+{
+	const char *sp = *sp_p + 1;
+	char cmd = **sp_p, name[TIMER_NAME_LEN];
+	fsmtimer_t *timer_p;
+	int i = 0;
+
+	if (isdigit(*sp)) {
+		*sp_p = sp;
+		if (NULL == find_timer_by_id(*sp - 0x30)) {
+			cli_errors++;
+			printf("%c%c: unknown timer\n", cmd, *sp);
+			return(-1);
+		}
+		return(*sp - 0x30);
+	}
+
+	while (isalpha(*sp) && i < sizeof(name)-1)
+		name[i++] = *sp++;
+	name[i] = '\0';
+	*sp_p = sp - 1;
+
+	if (0 == i) {
+		cli_errors++;
+		printf("%c: missing timer id or name\n", cmd);
+		return(-1);
+	}
+	if (NULL == (timer_p = find_timer_by_name(name))) {
+		cli_errors++;
+		printf("%s: unknown timer\n", name);
+		return(-1);
+	}
+	return(timer_p->timerid);
+}
+
+/**
+ * parse_name - copy a worker or group name from a command
+ * @sp: the first char of the name
+ * @name: updated with the name, empty if none
+ * @size: size of @name
+ *
+ * Return: the number of chars in the name
+ */
+static int parse_name(const char *sp, char *name, int size)
+{
+	int len;
+
+	for (len = 0; (isalnum(sp[len]) || (sp[len] && strchr("-_/", sp[len]))) &&
+		     len < size - 1; len++)
+		name[len] = sp[len];
+	name[len] = '\0';
+	return(len);
 }
 
 /**
@@ -78,35 +221,53 @@ int evt_parse_buf(const char const *buf)
 	int ret = 0;
 	const char *sp = buf;
 
-	while (*sp) {
+	while (*sp && !cli_signals()) {
 		if (isalnum(*sp)) {
 			switch(*sp) {
 			case 'h':
 				printf("\tx,q: exit producer and workers (gracefully)\n");
 				printf("\tw: show workers and curr state\n");
 				printf("\tb: crosswalk button push\n");
+				printf("\tbN: crosswalk button N push, N is the event payload\n");
 				printf("\tg: go %s\n", evt_name[E_INIT]);
 				printf("\teN: send event id N\n");
+				printf("\teN:name,...: send event id N to the named workers,\n"
+				       "\t  up to 8, a name ending in / is a group (e.g. e10:intersection1/)\n");
 				printf("\tf: set timer fast\n");
-				printf("\ttN: toggle timer N\n");
+				printf("\ttN: toggle timer N (id or name)\n");
+				printf("\tkN: kick timer N, send its event now\n");
+				printf("\tcN:T[:J]: change timer N period to T ticks (0 stops),\n"
+				       "\t  with J msecs random jitter\n");
 				printf("\tr: run event input script %s\n", scriptfile);
 				printf("\ts: show current FSM state\n");
+				printf("\ta: show FSM action profile (-P)\n");
+				printf("\tm: explain each FSM machine\n");
+				printf("\tuNAME,STATE,N[:PRIO][,NEXT]: on event id N in STATE worker\n"
+				       "\t  NAME goes to NEXT, without NEXT the transition is removed\n");
+				printf("\tl: show event routing load per worker\n");
+				printf("\td: show the dead letters, events that were not run\n");
+				printf("\tiNAME: spawn a worker NAME (a crosswalk in fsmdemo)\n");
+				printf("\tzNAME: tear down worker NAME\n");
+				printf("\tvNAME: toggle the transition trace of worker NAME\n");
 				printf("\tnN: main thread nap N ticks\n"
-				      "(worker/timer threads keep running)\n");
+				      "(worker/timer threads keep running, with -v the\n"
+				      " simulated time advances N ticks)\n");
+				printf("\toN: request operating mode N (e.g. 1: school hours)\n");
 				printf("\tp: pause CLI thread\n");
 				printf("\tdefault: unknown command\n");
 				break;
 			case 'x':
 			case 'q':
-				/* exit event threads and main */
-				workers_evt_broadcast(E_DONE);
+				/* exit main, which stops the workers, see
+				 * workers_shutdown
+				 */
 				ret = 1;
 				break;
 			case 'w':
 				show_workers();
 				break;
 			case 'g':
-				workers_evt_broadcast(E_INIT);
+				workers_start();
 				break;
 			case 'f':
 			{
@@ -121,52 +282,269 @@ int evt_parse_buf(const char const *buf)
 			}
 			break;
 			case 'b':
-				workers_evt_broadcast(E_BUTTON);
-				break;
+			{
+				uint32_t button = 0;
+
+				while (isdigit(sp[1]))
+					button = button * 10 + (*++sp - '0');
+				workers_evt_broadcast_data(E_BUTTON, button);
+			}
+			break;
 			case 's':
-				printf("*** FSM status\n");
-				show_timers();
-				show_workers();
-				printf("*** END FSM status\n");
+				show_status();
+				break;
+			case 'a':
+				show_prof();
+				break;
+			case 'm':
+				show_explain();
+				break;
+			case 'u':
+			{
+				/* worker, state, event id with an optional priority
+				 * and optional next state, the rest of the line as a
+				 * state name has spaces
+				 */
+				char buf[128], *fld[4], *fp, *ep;
+				uint32_t evtid;
+				int prio = 0;
+				fsm_state_t *st_p, *next_p = NULL;
+				worker_t *w_p;
+				int len, n, rc;
+
+				len = strcspn(sp + 1, "\r\n");
+				snprintf(buf, sizeof(buf), "%.*s", len, sp + 1);
+				sp += len;
+				for (n = 0, fp = buf; fp && n < 4; n++)
+					fld[n] = strsep(&fp, ",");
+				if (n < 3) {
+					cli_errors++;
+					printf("u: expected uNAME,STATE,N[:PRIO][,NEXT]\n");
+					break;
+				}
+				if (NULL == (w_p = worker_find_by_name(fld[0])) || NULL == w_p->fsm_p) {
+					cli_errors++;
+					printf("%s: unknown FSM worker\n", fld[0]);
+					break;
+				}
+				evtid = strtoul(fld[2], &ep, 10);
+				if (':' == *ep)
+					prio = strtol(ep + 1, &ep, 10);
+				if (ep == fld[2] || *ep) {
+					cli_errors++;
+					printf("u: bad event %s\n", fld[2]);
+					break;
+				}
+				if (NULL == (st_p = fsm_state_find(w_p->fsm_p, fld[1])) ||
+				    (n == 4 && NULL == (next_p = fsm_state_find(w_p->fsm_p, fld[3])))) {
+					cli_errors++;
+					printf("%s: unknown state %s\n", fld[0],
+					       st_p ? fld[3] : fld[1]);
+					break;
+				}
+
+				/* replace an existing transition, else add it */
+				if (NULL == next_p)
+					rc = fsm_trans_remove(w_p->fsm_p, st_p, evtid, prio);
+				else if ((rc = fsm_trans_replace(w_p->fsm_p, st_p, evtid, prio,
+								 NULL, next_p)))
+					rc = fsm_trans_add(w_p->fsm_p, st_p, evtid, prio, NULL, next_p);
+				if (rc) {
+					cli_errors++;
+					printf("%s: %s e%u: transition change failed\n",
+					       fld[0], st_p->name, evtid);
+				} else
+					printf("%s: %s e%u to %s\n", fld[0], st_p->name, evtid,
+					       next_p ? next_p->name : "(removed)");
+			}
+			break;
+			case 'd':
+				show_dead();
+				break;
+			case 'i':
+			case 'z':
+			case 'v':
+			{
+				char name[32];
+				worker_t *w_p;
+				char cmd = *sp;
+
+				sp += parse_name(sp + 1, name, sizeof(name));
+				if (!name[0]) {
+					cli_errors++;
+					printf("%c: missing worker name\n", cmd);
+					break;
+				}
+				if ('i' == cmd) {
+					if (NULL == workers_spawn_fn || NULL == workers_spawn_fn(name)) {
+						cli_errors++;
+						printf("%s: spawn failed\n", name);
+					}
+				} else if (NULL == (w_p = worker_find_by_name(name))) {
+					cli_errors++;
+					printf("%s: unknown worker\n", name);
+				} else if ('z' == cmd) {
+					if (worker_teardown(w_p)) {
+						cli_errors++;
+						printf("%s: teardown failed\n", name);
+					}
+				} else {
+					bool quiet = !__atomic_load_n(&w_p->quiet, __ATOMIC_RELAXED);
+
+					__atomic_store_n(&w_p->quiet, quiet, __ATOMIC_RELAXED);
+					printf("%s: trace %s\n", name, quiet ? "off" : "on");
+				}
+			}
+			break;
+			case 'l':
+				show_router();
 				break;
 			case 'e':
 			{
-				/* get next char and convert to int */
-				uint32_t evtid = (uint32_t)(*++sp - 0x30);
-				workers_evt_broadcast(evtid);
+				uint32_t evtid = 0;
+				struct worker_send sends[8];
+				char names[8][32];
+				int n = 0, len;
+
+				/* decimal event id */
+				if (!isdigit(sp[1])) {
+					cli_errors++;
+					printf("e: missing event id\n");
+					break;
+				}
+				while (isdigit(sp[1]))
+					evtid = evtid * 10 + (*++sp - '0');
+				if (evtid >= E_LAST) {
+					cli_errors++;
+					printf("e%u: unknown event\n", evtid);
+					break;
+				}
+
+				if (sp[1] != ':') {
+					workers_evt_broadcast(evtid);
+					break;
+				}
+
+				/* eN:name,name send to the named workers */
+				do {
+					sp += 2;
+					len = parse_name(sp, names[n], sizeof(names[0]));
+					sends[n].name = names[n];
+					sends[n].evt_id = evtid;
+					n++;
+					sp += len - 1;
+				} while (sp[1] == ',' && n < 8);
+
+				if (sp[1] == ',') {
+					/* skip the rest of the list, send nothing */
+					while (sp[1] == ',') {
+						sp += 2;
+						for (len = 0; isalnum(sp[len]) ||
+							     (sp[len] && strchr("-_/", sp[len])); len++)
+							;
+						sp += len - 1;
+					}
+					cli_errors++;
+					printf("e%u: more than 8 targets\n", evtid);
+					break;
+				}
+
+				if (workers_evt_send_all(sends, n))
+					cli_errors++;
 			}
 			break;
 			case 't':
 			{
-				/* get next char and convert to int */
-				uint32_t timerid = (uint32_t)(*++sp - 0x30);
-				toggle_timer(timerid);
+				int timerid = parse_timer(&sp);
+				if (timerid >= 0)
+					toggle_timer(timerid);
+			}
+			break;
+			case 'k':
+			{
+				int timerid = parse_timer(&sp);
+				if (timerid >= 0)
+					fire_timer(timerid);
+			}
+			break;
+			case 'c':
+			{
+				/* timer id or name, colon, number of ticks,
+				 * optional colon and jitter msecs
+				 */
+				int timerid = parse_timer(&sp);
+				char *end_p;
+				uint64_t ticks;
+				uint32_t jitter = 0;
+
+				if (*++sp != ':') {
+					/* an unknown timer is already an error */
+					if (timerid >= 0) {
+						cli_errors++;
+						printf("c%d: missing :ticks\n", timerid);
+					}
+					break;
+				}
+				ticks = strtoul(sp+1, &end_p, 10);
+				if (*end_p == ':')
+					jitter = strtoul(end_p+1, &end_p, 10);
+				sp = end_p - 1;
+				if (timerid >= 0) {
+					jitter_timer(timerid, jitter);
+					set_timer(timerid, ticks*tick);
+				}
 			}
 			break;
 			case 'r':
-				evt_script();
+				ret = evt_script();
 				break;
 			case 'n':
 			{
-				/* get next char and convert to int */
-				uint32_t len = (uint32_t)(*++sp - 0x30);
+				/* number of ticks */
+				uint32_t len = 0;
+
+				while (isdigit(*(sp+1)))
+					len = len * 10 + (*++sp - '0');
 				dbg_verbose("begin nap");
-				nap(len*tick);
+				/* on the sim clock the nap is the time advance */
+				if (clock_is_sim())
+					timer_advance(len*tick);
+				else
+					cli_nap(len*tick);
 				dbg_verbose("after nap");
 			}
 			break;
+			case 'o':
+				if (!isdigit(sp[1])) {
+					cli_errors++;
+					printf("o: missing mode\n");
+					break;
+				}
+				if ((uint32_t)(sp[1] - '0') > cli_mode_max) {
+					cli_errors++;
+					printf("o%c: unknown mode, max %u\n", sp[1], cli_mode_max);
+					sp++;
+					break;
+				}
+				cli_mode = (uint32_t)(*++sp - 0x30);
+				break;
 			case 'p':
 				relax();
 				break;
 			default:
+				cli_errors++;
 				printf("%c: unknown cmd\n", *sp);
 				break;
 			} /* switch */
+
+			/* no worker threads, run the events of the command */
+			if (workers_run_fn)
+				workers_run_fn();
 		} /* if isalnum */
 		sp++;  /* next char */
 	} /* while */
 
-	return(ret);
+	return(ret || cli_stop);
 }
 
 
@@ -202,7 +580,7 @@ void evt_producer(void)
 	/* event loop */
 	printf("%s: Enter commands (g:start FSMs, h:help, x:exit)\n", __func__);
 	fflush(stdout);
-	while (!done) {
+	while (!done && !cli_signals()) {
 		int nfds; /* number of ready file descriptors */
 
 		dbg_verbose("poll_wait");
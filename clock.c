@@ -0,0 +1,98 @@
+/*
+ * SPDX-License-Identifier: GPL-2.0
+ * Copyright (C) 2021 Dahetral Systems
+ * Author: David Turvene (dturvene@dahetral.com)
+ *
+ * real and simulated run clocks
+ */
+
+#include <utils.h>
+#include <clock.h>
+
+/* origin of the timestamps, see utils.h */
+struct timespec ts_start;
+
+/*
+ * sim_ms - simulated msecs since the start of the run
+ * sim_mutex, sim_cond - wake the threads in sim_nap when sim_ms moves
+ */
+static uint64_t sim_ms;
+static pthread_mutex_t sim_mutex = PTHREAD_MUTEX_INITIALIZER;
+static pthread_cond_t sim_cond = PTHREAD_COND_INITIALIZER;
+
+static void real_now(struct timespec *ts_p)
+{
+	clock_gettime(CLOCK_MONOTONIC, ts_p);
+}
+
+static void sim_now(struct timespec *ts_p)
+{
+	uint64_t ms = sim_clock_ms();
+
+	ts_p->tv_sec = ms / 1000;
+	ts_p->tv_nsec = (ms % 1000) * 1000000L;
+}
+
+/**
+ * sim_nap - wait for the sim clock to advance
+ * @ms: number of simulated msecs
+ */
+static void sim_nap(uint32_t ms)
+{
+	uint64_t until;
+
+	pthread_mutex_lock(&sim_mutex);
+	until = sim_ms + ms;
+	while (sim_ms < until)
+		pthread_cond_wait(&sim_cond, &sim_mutex);
+	pthread_mutex_unlock(&sim_mutex);
+}
+
+struct run_clock real_clock = {"real", real_now, nap};
+struct run_clock sim_clock = {"sim", sim_now, sim_nap};
+
+/* the clock in use, must be selected before ts_anchor */
+struct run_clock *run_clock = &real_clock;
+
+/**
+ * clock_use - select the run clock
+ * @clock_p: real_clock, sim_clock or a test clock
+ *
+ * Must be called before any thread is started.
+ */
+void clock_use(struct run_clock *clock_p)
+{
+	run_clock = clock_p;
+}
+
+/**
+ * clock_is_sim - check if the run is on the sim clock
+ */
+bool clock_is_sim(void)
+{
+	return(run_clock == &sim_clock);
+}
+
+/**
+ * sim_clock_ms - simulated msecs since the start of the run
+ */
+uint64_t sim_clock_ms(void)
+{
+	return(__atomic_load_n(&sim_ms, __ATOMIC_ACQUIRE));
+}
+
+/**
+ * sim_clock_set - move the sim clock forward
+ * @ms: the new simulated time, ignored if it is in the past
+ *
+ * Wakes the threads in clock_nap whose time has come.  The clock moves
+ * instantly, a test advances hours of FSM time without sleeping.
+ */
+void sim_clock_set(uint64_t ms)
+{
+	pthread_mutex_lock(&sim_mutex);
+	if (ms > sim_ms)
+		__atomic_store_n(&sim_ms, ms, __ATOMIC_RELEASE);
+	pthread_cond_broadcast(&sim_cond);
+	pthread_mutex_unlock(&sim_mutex);
+}
@@ -0,0 +1,65 @@
+/*
+ * SPDX-License-Identifier: GPL-2.0
+ * Copyright (C) 2021 Dahetral Systems
+ * Author: David Turvene (dturvene@dahetral.com)
+ *
+ * run clock: the time source of the timestamps, timers and naps
+ */
+
+#ifndef _CLOCK_H
+#define _CLOCK_H
+
+#include <stdbool.h>     /* bool type and true, false values */
+#include <inttypes.h>    /* include stdint.h, PRI macros, integer conversions */
+#include <time.h>        /* clock_gettime */
+
+/**
+ * struct run_clock - a time source
+ * @name: for debugging
+ * @now: read the current time
+ * @nap: wait until the clock has advanced a number of msecs
+ *
+ * The real clock is CLOCK_MONOTONIC.  The sim clock only moves when
+ * sim_clock_set is called (see timer_advance) so a run does not depend
+ * on the scheduling of the threads or the speed of the host.
+ */
+struct run_clock {
+	const char *name;
+	void (*now)(struct timespec *ts_p);
+	void (*nap)(uint32_t ms);
+};
+
+extern struct run_clock real_clock;
+extern struct run_clock sim_clock;
+extern struct run_clock *run_clock;
+
+extern void clock_use(struct run_clock *clock_p);
+extern bool clock_is_sim(void);
+extern uint64_t sim_clock_ms(void);
+extern void sim_clock_set(uint64_t ms);
+
+/**
+ * clock_now - current time of the run clock
+ * @ts_p: updated with the time
+ *
+ * Use this instead of clock_gettime for anything that is part of the
+ * FSM behaviour or its trace: timestamps, event ages, timer expiries.
+ */
+static inline void clock_now(struct timespec *ts_p)
+{
+	run_clock->now(ts_p);
+}
+
+/**
+ * clock_nap - wait a number of msecs of the run clock
+ * @ms: number of msecs
+ *
+ * Unlike nap, with the sim clock this blocks until the simulated time
+ * has advanced by @ms.
+ */
+static inline void clock_nap(uint32_t ms)
+{
+	run_clock->nap(ms);
+}
+
+#endif /* _CLOCK_H */
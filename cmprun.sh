@@ -0,0 +1,132 @@
+#!/bin/sh
+# SPDX-License-Identifier: GPL-2.0
+# Copyright (C) 2021 Dahetral Systems
+#
+# compare two fsmdemo runs recorded with the -c option and their JSON
+# transition trace, e.g.
+#  ./fsmdemo -n -t 100 -c /tmp/base -d 0x01 -T json > /tmp/base.trace
+#  ./fsmdemo -n -t 50 -c /tmp/fast -d 0x01 -T json > /tmp/fast.trace
+#  ./cmprun.sh /tmp/base /tmp/fast
+#
+# For each run the report shows:
+# - mean dwell time and visits per FSM state (e.g. the S:WALK window)
+# - mean cycle length per FSM, time between entries to its first state
+# - invariant margins, see margins
+# - events taken, refused by a guard or ignored (no transition) per FSM,
+#   e.g. BUTTON presses, counted from the trace.  An internal transition
+#   is taken.  Workers left out of the trace (FSM_TRACE) are not counted.
+
+# margins - FROM>TO pairs, comma separated: the time from an FSM entering
+# FROM to an FSM entering TO next, e.g. how long the walk signal lags the
+# green light.  The report shows the smallest and the mean margin.
+margins="S:BLINKING WALK>S:GREEN,S:GREEN>S:DONT_WALK"
+
+if [ $# -ne 2 ]; then
+	echo "Usage: $0 prefix1 prefix2" >&2
+	exit 1
+fi
+
+for p in "$1" "$2"; do
+	for f in "${p}_trans.csv" "${p}.trace"; do
+		if [ ! -r "$f" ]; then
+			echo "$f: not found" >&2
+			exit 1
+		fi
+	done
+done
+
+# outcome trace - fsm,event,outcome rows of the transition trace
+outcome() {
+	jq -rR 'fromjson? | select(.type == "trans" or .type == "guarded" or
+		.type == "reject") | "\(.fsm),\(.event),\(.type)"' "$1"
+}
+
+out1=$(mktemp)
+out2=$(mktemp)
+outcome "${1}.trace" > "$out1"
+outcome "${2}.trace" > "$out2"
+
+awk -F, -v r1="$1" -v o1="$out1" -v margins="$margins" '
+BEGIN {
+	nm = split(margins, pairs, ",")
+	for (i = 1; i <= nm; i++) {
+		split(pairs[i], a, ">")
+		mfrom[i] = a[1]; mto[i] = a[2]
+	}
+}
+# run index from the file being read
+FNR == 1 {
+	run = (FILENAME == r1 "_trans.csv" || FILENAME == o1) ? 1 : 2
+	trans = (FILENAME ~ /_trans\.csv$/)
+	if (trans)
+		next
+}
+trans {
+	fsm = $2; to = $5
+	# dwell time of the state being left
+	if ($6 != "") {
+		key = fsm SUBSEP $4
+		dwell[run, key] += $6; visits[run, key]++
+		keys[key] = 1
+	}
+	# cycle: time between entries to the first state after init
+	if (!((run, fsm) in first)) {
+		first[run, fsm] = to; last[run, fsm] = $1
+	} else if (to == first[run, fsm]) {
+		cycle[run, fsm] += $1 - last[run, fsm]; ncycle[run, fsm]++
+		last[run, fsm] = $1
+	}
+	# margin: the latest entry to FROM, paired with the next entry to TO
+	for (i = 1; i <= nm; i++) {
+		if (to == mto[i] && (run, i) in mstart) {
+			m = $1 - mstart[run, i]
+			msum[run, i] += m; mn[run, i]++
+			if (!((run, i) in mmin) || m < mmin[run, i])
+				mmin[run, i] = m
+			delete mstart[run, i]
+		}
+		if (to == mfrom[i])
+			mstart[run, i] = $1
+	}
+	fsms[fsm] = 1
+	next
+}
+{
+	key = $1 SUBSEP $2
+	count[run, key, $3]++
+	evts[key] = 1
+}
+function avg(sum, n) { return n ? sprintf("%.1f", sum / n) : "-" }
+function low(run, i) { return mn[run, i] ? sprintf("%.1f", mmin[run, i]) : "-" }
+END {
+	printf("%-12s %-16s %12s %12s %8s %8s\n", "fsm", "state dwell",
+	       "run1 ms", "run2 ms", "visits1", "visits2")
+	for (k in keys) {
+		split(k, a, SUBSEP)
+		printf("%-12s %-16s %12s %12s %8d %8d\n", a[1], a[2],
+		       avg(dwell[1, k], visits[1, k]), avg(dwell[2, k], visits[2, k]),
+		       visits[1, k], visits[2, k])
+	}
+	printf("\n%-12s %-16s %12s %12s\n", "fsm", "cycle", "run1 ms", "run2 ms")
+	for (f in fsms)
+		printf("%-12s %-16s %12s %12s\n", f, first[1, f],
+		       avg(cycle[1, f], ncycle[1, f]), avg(cycle[2, f], ncycle[2, f]))
+	printf("\n%-34s %12s %12s %12s %12s\n", "margin", "run1 min", "run2 min",
+	       "run1 mean", "run2 mean")
+	for (i = 1; i <= nm; i++)
+		printf("%-34s %12s %12s %12s %12s\n", mfrom[i] " > " mto[i],
+		       low(1, i), low(2, i),
+		       avg(msum[1, i], mn[1, i]), avg(msum[2, i], mn[2, i]))
+	printf("\n%-12s %-16s %8s %8s %8s %8s %8s %8s\n", "fsm", "event",
+	       "taken1", "taken2", "refused1", "refused2", "ignored1", "ignored2")
+	for (k in evts) {
+		split(k, a, SUBSEP)
+		printf("%-12s %-16s %8d %8d %8d %8d %8d %8d\n", a[1], a[2],
+		       count[1, k, "trans"], count[2, k, "trans"],
+		       count[1, k, "guarded"], count[2, k, "guarded"],
+		       count[1, k, "reject"], count[2, k, "reject"])
+	}
+}' "${1}_trans.csv" "$out1" "${2}_trans.csv" "$out2"
+ret=$?
+rm -f "$out1" "$out2"
+exit $ret
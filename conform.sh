@@ -0,0 +1,135 @@
+#!/bin/sh
+# SPDX-License-Identifier: GPL-2.0
+# Copyright (C) 2021 Dahetral Systems
+#
+# conformance suite: run each conform/NAME.script event script and
+# compare the FSM transition trace against conform/NAME.trace, e.g.
+#  ./conform.sh
+#  ./conform.sh -g              # regenerate the expected traces
+#  ./conform.sh ./other_fsmdemo # validate another executor
+#
+# The machines are the FSM1/FSM2 tables compiled into the executor.  An
+# executor must accept the fsmdemo -n -t -d -s options and print the
+# DBG_TRANS lines, e.g.
+#  stoplight:ts=89.600 evt=INIT trans S:INIT to S:GREEN usec=4
+#
+# The scripts drive the FSMs with timer kicks (kN) at a long tick so no
+# timer expires on its own.  Only state changes are compared: timestamps,
+# durations (usec=) and ignored events ("to no next") are removed and the
+# trace is grouped by FSM, keeping the order of each FSM, because the
+# interleaving of the FSM and CLI threads is not part of the semantics.
+#
+# An optional conform/NAME.opts holds extra executor options, given after
+# the default ones so it can change them.  With -v (virtual time) or -1
+# (single-threaded, on virtual time) the timers run on the simulated
+# clock, the timestamps are exact and are compared too.  The durations
+# are always on the real clock and never compared.
+#
+# Some cases run again with extra options and are compared with the same
+# trace, see reruns.  With -T json every trace line must be a JSON object
+# that parses (jq -e) and no text trace line is left.  The trans and
+# guarded objects are then compared as the text trace, without the
+# timestamps.
+#
+# With -O the lines of the fsmdemo observer (see fsm_observe) are compared
+# instead of the trace, converted to the trace form, e.g.
+#  stoplight: observe evt=INIT S:INIT to S:GREEN
+
+gen=0
+if [ "$1" = "-g" ]; then
+	gen=1
+	shift
+fi
+exe="${1:-./fsmdemo}"
+dir=$(dirname "$0")/conform
+tick=500
+failed=0
+
+# reruns - NAME:OPTS, run the NAME case again with the extra OPTS
+reruns="cycle:-T json -d 0x09
+button:-O"
+
+# strip_ts opts - remove the timestamps unless on the simulated clock
+strip_ts() {
+	case " $1 " in
+	*" -v "*|*" -1 "*) cat ;;
+	*) sed 's/:ts=[0-9. ]* evt=/: evt=/' ;;
+	esac
+}
+
+# json_trace raw - check the JSON lines of raw and convert the
+# transitions to the text trace
+json_trace() {
+	if grep -v '^{' "$1" | grep -q ' trans \| state '; then
+		echo "text trace line in JSON output" >&2
+		return 1
+	fi
+	grep '^{' "$1" | jq -e . > /dev/null || return 1
+	grep '^{' "$1" | jq -r 'select(.type == "trans" or .type == "guarded") |
+		"\(.fsm): evt=\(.event) trans \(.from) to \(.to)"'
+}
+
+# run_case name opts label - run conform/NAME.script with opts and compare
+# the trace with conform/NAME.trace, label names the case in the output
+run_case() {
+	script="$dir/$1.script"
+	trace="$dir/$1.trace"
+	out=$(mktemp)
+	raw=$(mktemp)
+
+	LD_LIBRARY_PATH=. timeout 60 stdbuf -oL "$exe" -n -t $tick -d 0x01 $2 -s "$script" > "$raw" 2>&1
+	case " $2 " in
+	*" -T json "*)
+		if ! json_trace "$raw" > "$out"; then
+			echo "$3: FAIL, bad JSON trace"
+			failed=$((failed + 1))
+			rm -f "$out" "$raw"
+			return
+		fi
+		;;
+	*" -O "*)
+		grep ': observe ' "$raw" |
+			sed 's/: observe \(evt=.*\) \(S:[^ ]* to \)/: \1 trans \2/' > "$out"
+		;;
+	*)
+		grep ' trans ' "$raw" | grep -v ' to no next' |
+			sed 's/ usec=[0-9]*$//' | strip_ts "$2" > "$out"
+		;;
+	esac
+	sort -s -t: -k1,1 -o "$out" "$out"
+	rm -f "$raw"
+
+	if [ $gen -eq 1 ]; then
+		mv "$out" "$trace"
+		echo "$3: generated"
+		return
+	fi
+
+	if diff -u "$trace" "$out"; then
+		echo "$3: PASS"
+	else
+		echo "$3: FAIL"
+		failed=$((failed + 1))
+	fi
+	rm -f "$out"
+}
+
+for script in "$dir"/*.script; do
+	name=$(basename "$script" .script)
+	opts=""
+	[ -r "$dir/$name.opts" ] && opts=$(cat "$dir/$name.opts")
+	run_case "$name" "$opts" "$name"
+done
+
+# the expected traces come from the plain runs
+[ $gen -eq 1 ] && exit $failed
+
+while IFS=: read -r name extra; do
+	opts=""
+	[ -r "$dir/$name.opts" ] && opts=$(cat "$dir/$name.opts")
+	run_case "$name" "$opts $extra" "$name $extra"
+done <<EOF
+$reruns
+EOF
+
+exit $failed
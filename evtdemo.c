@@ -61,6 +61,7 @@ char *arguments = "\n"							\
 	" -t tick: timer tick in msec\n"				\
 	" -s scriptfile: read events from file\n"			\
 	" -n: non-interactive mode (only read from scriptfile)\n"	\
+	" -i cmds: run cmds at startup (e.g. -i g)\n"		\
 	" -d level: set debug_flag to hex level\n"			\
 	" -h: this help\n";
 
@@ -84,6 +85,13 @@ char scriptfile[64] = "./evtdemo.script";
  */
 static bool non_interactive = false;
 
+/**
+ * initcmds - CLI commands parsed once all workers are registered and
+ *  before the interactive/script input is read.  This allows a headless
+ *  run to start the FSMs without a human typing 'g'.
+ */
+static char initcmds[64] = "";
+
 /**
  * cmdline_args - parse command line arguments
  * @argc: argument count (from main)
@@ -100,7 +108,7 @@ int cmdline_args(int argc, char *argv[]) {
 	int opt;
 	int argcnt = 0;
 	
-	while((opt = getopt(argc, argv, "t:s:nd:h")) != -1) {
+	while((opt = getopt(argc, argv, "t:s:ni:d:h")) != -1) {
 		switch(opt) {
 		case 't':
 			tick = strtoul(optarg, NULL, 0);
@@ -114,6 +122,9 @@ int cmdline_args(int argc, char *argv[]) {
 		case 'n':
 			non_interactive = true;
 			break;
+		case 'i':
+			strncpy(initcmds, optarg, sizeof(initcmds)-1);
+			break;
 		case 'd':
 			debug_flag = strtoul(optarg, NULL, 0);
 			break;
@@ -181,6 +192,7 @@ void *evt_c1(void *arg)
 
 	/* worker_self() loops through worker_list for match on pthread_self */
 	evtq_self = self_p->evtq_p;
+	worker_ready(self_p);
 	while (true)
 	{
 		evtq_dequeue(evtq_self, &evt_id);
@@ -197,7 +209,7 @@ void *evt_c1(void *arg)
 			break;
 		case E_INIT:
 			dbg("create 2 (E_LIGHT)");
-			create_timer(2, E_LIGHT);
+			create_timer(2, E_LIGHT, "light");
 			dbg("set timer 2 2000");
 			set_timer(2, 2000);		
 			break;
@@ -231,6 +243,7 @@ void *evt_c2(void *arg)
 
 	/* worker_self() loops through worker_list for match on pthread_self */
 	evtq_self = self_p->evtq_p;
+	worker_ready(self_p);
 	while (true)
 	{
 		evtq_dequeue(evtq_self, &evt_id);
@@ -246,7 +259,7 @@ void *evt_c2(void *arg)
 			break;
 		case E_INIT:
 			dbg("create timer 3");
-			create_timer(3, E_BLINK);
+			create_timer(3, E_BLINK, "blink");
 			break;
 		case E_DONE:
 			pthread_exit(NULL);
@@ -280,6 +293,9 @@ int main(int argc, char *argv[])
 
 	parsed_args = cmdline_args(argc, argv);
 
+	/* origin of all timestamps */
+	ts_anchor(debug_flag != DBG_NONE);
+
 	/* show commandline arguments that are NOT handled
 	 * by cmdline_args.
 	 */
@@ -290,25 +306,35 @@ int main(int argc, char *argv[])
 	/* all threads in process use this */
 	set_sig_handlers();
 	
+	timer_init();
 	if (0 != pthread_create(&timer_service, NULL, timer_service_fn, NULL))
 		die("timer_service create");
 
 	worker_list_create();
-	worker_list_add(worker_create(&evt_c1, "consumer1"));
-	worker_list_add(worker_create(&evt_c2, "consumer2"));
+	if (worker_list_add(worker_create(&evt_c1, "consumer1")) ||
+	    worker_list_add(worker_create(&evt_c2, "consumer2")))
+		die("worker_list_add");
 
 	/* create a test timer */
 
+	/* inject startup commands now that all workers are registered */
+	if (initcmds[0])
+		evt_parse_buf(initcmds);
+
 	/* loop until 'x' entered */
-	non_interactive ? evt_script() : evt_producer();
+	if (non_interactive)
+		evt_script();
+	else
+		evt_producer();
+
+	timer_stop_all();
+	dbg("waiting for worker joins\n");
+	workers_shutdown();
 
 	/* cancel timer_service thread */
 	dbg("cancel timer_service and join\n");
 	pthread_cancel(timer_service);
 	pthread_join(timer_service, NULL);
-	
-	dbg("waiting for worker joins\n");
-	join_workers();
 	workers_evtq_destroy();
 
 	dbg("exitting...\n");
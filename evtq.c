@@ -9,6 +9,28 @@
 #include <utils.h>
 #include <evtq.h>
 #include <workers.h>
+#include <errno.h>       /* ETIMEDOUT */
+
+/*
+ * enqueues - number of events enqueued on any queue, see evtq_activity
+ */
+static uint32_t enqueues;
+
+/*
+ * dead - ring of the last EVTQ_DEAD_MAX dead letters
+ * ndead - number of dead letters so far
+ * dead_mutex - dead letters come from any thread
+ */
+static struct dead_letter dead[EVTQ_DEAD_MAX];
+static uint32_t ndead;
+static pthread_mutex_t dead_mutex = PTHREAD_MUTEX_INITIALIZER;
+
+static const char * const dead_name[] = {
+	[DEAD_NO_TARGET] = "no target",
+	[DEAD_DOWN] = "target down",
+	[DEAD_UNHANDLED] = "unhandled",
+	[DEAD_STALE] = "stale",
+};
 
 /**
  * evtq_create - create a queue instance
@@ -18,12 +40,21 @@
  */
 evtq_t* evtq_create(void)
 {
-	evtq_t *q_p = malloc(sizeof(evtq_t));
+	evtq_t *q_p = fsm_malloc(sizeof(evtq_t));
+	pthread_condattr_t attr;
+
+	/* monotonic so a dequeue timeout is not affected by clock changes */
+	pthread_condattr_init(&attr);
+	pthread_condattr_setclock(&attr, CLOCK_MONOTONIC);
 
 	pthread_mutex_init(&q_p->mutex, NULL);
-	pthread_cond_init(&q_p->cond, NULL);	
+	pthread_cond_init(&q_p->cond, &attr);
+	pthread_condattr_destroy(&attr);
 	q_p->len = 0;
+	q_p->max_len = 0;
+	q_p->waiters = 0;
 	NL_INIT_LIST_HEAD(&q_p->head.list);
+	NL_INIT_LIST_HEAD(&q_p->ctl.list);
 
 	return(q_p);
 }
@@ -45,26 +76,33 @@ void evtq_destroy(evtq_t* q_p)
 }
 
 /**
- * evtq_enqueue - add an event to the tail of the queue
+ * evtq_enqueue_data - add an event with a payload to the tail of the queue
  * @evtq_p - pointer to event queue
  * @id - the event id to add
+ * @data - the event payload
  * 
  * lock queue
- * create event, add to queue tail
+ * create event, add to queue tail (control lane tail for a control event)
  * signal condition that there is an new event queued
  * unlock queue
  */
-void evtq_enqueue(evtq_t *evtq_p, fsm_events_t evt_id)
+void evtq_enqueue_data(evtq_t *evtq_p, fsm_events_t evt_id, uint32_t data)
 {
 	struct fsm_event *ep;
-	char msg[80];
 
 	pthread_mutex_lock(&evtq_p->mutex);
 	
-	ep = malloc( sizeof(struct fsm_event) );
+	ep = fsm_malloc( sizeof(struct fsm_event) );
 	ep->event_id = evt_id;
-	nl_list_add_tail(&ep->list, &evtq_p->head.list);
-	evtq_p->len++;
+	ep->data = data;
+	clock_now(&ep->ts);
+	if (evt_is_ctl(evt_id))
+		nl_list_add_tail(&ep->list, &evtq_p->ctl.list);
+	else
+		nl_list_add_tail(&ep->list, &evtq_p->head.list);
+	if (++evtq_p->len > evtq_p->max_len)
+		evtq_p->max_len = evtq_p->len;
+	__atomic_add_fetch(&enqueues, 1, __ATOMIC_RELEASE);
 
 	pthread_cond_signal(&evtq_p->cond);
 	pthread_mutex_unlock(&evtq_p->mutex);
@@ -73,6 +111,27 @@ void evtq_enqueue(evtq_t *evtq_p, fsm_events_t evt_id)
 	relax();
 }
 
+/**
+ * evtq_enqueue - add an event with no payload to the tail of the queue
+ * @evtq_p - pointer to event queue
+ * @id - the event id to add
+ */
+void evtq_enqueue(evtq_t *evtq_p, fsm_events_t evt_id)
+{
+	evtq_enqueue_data(evtq_p, evt_id, 0);
+}
+
+/**
+ * evtq_first - first event to dequeue, control lane first
+ * @evtq_p - pointer to event queue, locked and not empty
+ */
+static struct fsm_event *evtq_first(evtq_t *evtq_p)
+{
+	if (!nl_list_empty(&evtq_p->ctl.list))
+		return nl_list_first_entry(&evtq_p->ctl.list, struct fsm_event, list);
+	return nl_list_first_entry(&evtq_p->head.list, struct fsm_event, list);
+}
+
 /**
  * evtq_dequeue - pop an event from head of queue
  * @evtq_p - pointer to event queue
@@ -102,7 +161,7 @@ void evtq_dequeue(evtq_t *evtq_p, fsm_events_t* id_p)
 		pthread_cond_wait(&evtq_p->cond, &evtq_p->mutex);
 	}
 
-	ep = nl_list_first_entry(&evtq_p->head.list, struct fsm_event, list);
+	ep = evtq_first(evtq_p);
 	nl_list_del(&ep->list);
 	evtq_p->len--;
 	*id_p = ep->event_id;
@@ -113,6 +172,83 @@ void evtq_dequeue(evtq_t *evtq_p, fsm_events_t* id_p)
 	dbg_evts(*id_p);
 }
 
+/**
+ * evtq_wait_cancel - release a queue when its consumer is cancelled
+ * @arg - pointer to event queue
+ *
+ * A pthread cleanup handler around the condition wait, a consumer
+ * cancelled there (e.g. by workers_shutdown) holds the queue mutex.
+ */
+static void evtq_wait_cancel(void *arg)
+{
+	evtq_t *evtq_p = (evtq_t *) arg;
+
+	evtq_p->waiters--;
+	pthread_mutex_unlock(&evtq_p->mutex);
+}
+
+/**
+ * evtq_dequeue_batch - pop up to max events from head of queue
+ * @evtq_p - pointer to event queue
+ * @ids - array updated with the event ids, oldest first
+ * @ages - optional array updated with msecs each event was queued
+ * @data - optional array updated with the event payloads
+ * @max - size of @ids, @ages and @data
+ * @timeout_ms - max msecs to wait for an event, 0 to wait forever
+ *
+ * Return: number of events popped, 0 on timeout
+ *
+ * Like evtq_dequeue, this blocks until the queue is not empty.  All
+ * queued events (up to @max) are then popped under a single lock so a
+ * burst is handled with one wakeup of the consumer.
+ */
+uint32_t evtq_dequeue_batch(evtq_t *evtq_p, fsm_events_t *ids, uint32_t *ages,
+			    uint32_t *data, uint32_t max, uint32_t timeout_ms)
+{
+	struct fsm_event *ep;
+	struct timespec abs, now;
+	uint32_t n = 0;
+
+	clock_gettime(CLOCK_MONOTONIC, &abs);
+	abs.tv_sec += timeout_ms / 1000;
+	abs.tv_nsec += (timeout_ms % 1000) * 1000000L;
+	if (abs.tv_nsec >= 1000000000L) {
+		abs.tv_sec++;
+		abs.tv_nsec -= 1000000000L;
+	}
+
+	pthread_mutex_lock(&evtq_p->mutex);
+
+	evtq_p->waiters++;
+	pthread_cleanup_push(evtq_wait_cancel, evtq_p);
+	while(0 == evtq_p->len) {
+		if (0 == timeout_ms)
+			pthread_cond_wait(&evtq_p->cond, &evtq_p->mutex);
+		else if (ETIMEDOUT == pthread_cond_timedwait(&evtq_p->cond, &evtq_p->mutex, &abs))
+			break;
+	}
+	pthread_cleanup_pop(0);
+	evtq_p->waiters--;
+
+	clock_now(&now);
+	while (evtq_p->len && n < max) {
+		ep = evtq_first(evtq_p);
+		nl_list_del(&ep->list);
+		evtq_p->len--;
+		if (ages)
+			ages[n] = (now.tv_sec - ep->ts.tv_sec) * 1000 +
+				(now.tv_nsec - ep->ts.tv_nsec) / 1000000;
+		if (data)
+			data[n] = ep->data;
+		ids[n++] = ep->event_id;
+		free(ep);
+	}
+
+	pthread_mutex_unlock(&evtq_p->mutex);
+
+	return(n);
+}
+
 /**
  * evtq_len - 
  * @evtq_p - pointer to event queue
@@ -134,4 +270,91 @@ uint32_t evtq_len(evtq_t *evtq_p)
 	return(len);
 }
 
+/**
+ * evtq_idle - check a queue is empty and its consumer is waiting on it
+ * @evtq_p - pointer to event queue
+ *
+ * Return: true if the consumer has run every event and is blocked for
+ * the next one
+ */
+bool evtq_idle(evtq_t *evtq_p)
+{
+	bool idle;
+
+	pthread_mutex_lock(&evtq_p->mutex);
+	idle = (0 == evtq_p->len && evtq_p->waiters > 0);
+	pthread_mutex_unlock(&evtq_p->mutex);
+	return(idle);
+}
+
+/**
+ * evtq_activity - number of events enqueued on any queue so far
+ *
+ * Two equal reads around a check of all the queues mean no event was
+ * sent while checking, see workers_settle.
+ */
+uint32_t evtq_activity(void)
+{
+	return(__atomic_load_n(&enqueues, __ATOMIC_ACQUIRE));
+}
+
+/**
+ * evtq_dead - keep an event that was not run in the dead-letter ring
+ * @target: worker or group name the event was sent to
+ * @evt_id: the event
+ * @data: the event payload
+ * @reason: why it was not run
+ * @detail: optional, must stay allocated (e.g. a state name)
+ *
+ * Undeliverable and rejected events otherwise just vanish.  The ring is
+ * shown with the CLI d command and on exit.
+ */
+void evtq_dead(const char *target, fsm_events_t evt_id, uint32_t data,
+	       enum dead_reason reason, const char *detail)
+{
+	struct dead_letter *d_p;
+
+	pthread_mutex_lock(&dead_mutex);
+	d_p = &dead[ndead++ % EVTQ_DEAD_MAX];
+	clock_now(&d_p->ts);
+	snprintf(d_p->target, sizeof(d_p->target), "%s", target);
+	d_p->evt_id = evt_id;
+	d_p->data = data;
+	d_p->reason = reason;
+	d_p->detail = detail;
+	pthread_mutex_unlock(&dead_mutex);
+}
 
+/**
+ * evtq_dead_count - number of dead letters so far
+ */
+uint32_t evtq_dead_count(void)
+{
+	uint32_t n;
+
+	pthread_mutex_lock(&dead_mutex);
+	n = ndead;
+	pthread_mutex_unlock(&dead_mutex);
+	return(n);
+}
+
+/**
+ * show_dead - print the dead letters, oldest first
+ */
+void show_dead(void)
+{
+	struct dead_letter *d_p;
+	uint32_t i;
+
+	pthread_mutex_lock(&dead_mutex);
+	printf("dead letters %u%s\n", ndead,
+	       ndead > EVTQ_DEAD_MAX ? ", oldest dropped" : "");
+	for (i = ndead > EVTQ_DEAD_MAX ? ndead - EVTQ_DEAD_MAX : 0; i < ndead; i++) {
+		d_p = &dead[i % EVTQ_DEAD_MAX];
+		printf("ts=%.3f %-12s evt=%s data=%u %s%s%s\n", ts_ms(&d_p->ts) / 1e3,
+		       d_p->target, evt_name[d_p->evt_id], d_p->data,
+		       dead_name[d_p->reason], d_p->detail ? " in " : "",
+		       d_p->detail ? d_p->detail : "");
+	}
+	pthread_mutex_unlock(&dead_mutex);
+}
@@ -17,8 +17,12 @@
 #include <stdio.h>
 #include <string.h>
 #include <pthread.h>     /* posix threads */
+#include <signal.h>      /* sig_atomic_t */
 #include <libnl3/netlink/list.h> /* kernel-ish linked list */
 
+/* max number of events popped at once by evtq_dequeue_batch callers */
+#define EVTQ_BATCH 8
+
 /*
  * fsm_events_t - enum containg all events
  */
@@ -33,6 +37,8 @@ typedef enum evt_id {
 	E_BUTTON,
 	E_DONE,
 	E_TIMER,
+	E_MAINT,
+	E_PEER_DOWN,
 	E_LAST,
 } fsm_events_t;
 
@@ -50,6 +56,8 @@ static const char * const evt_name[] = {
 	[E_BUTTON] = "BUTTON",
 	[E_DONE] = "DONE",
 	[E_TIMER] = "TIMER TEST",
+	[E_MAINT] = "MAINT",
+	[E_PEER_DOWN] = "PEER DOWN",
 	[E_LAST] = "LAST",
 };
 
@@ -57,18 +65,36 @@ static const char * const evt_name[] = {
  * struct fsm_event
  * @list: kernel-style linked list node
  * @event_id: one of the valid events
+ * @ts: run clock time the event was enqueued
+ * @data: event payload (e.g. the button number), 0 for none
  */
 struct fsm_event {
 	struct nl_list_head list;
 	fsm_events_t event_id;
+	struct timespec ts;
+	uint32_t data;
 };
 
+/**
+ * evt_is_ctl - control events bypass queued domain events
+ * @evt_id: the event id
+ *
+ * Return: true if the event is delivered on the control lane
+ */
+static inline bool evt_is_ctl(fsm_events_t evt_id)
+{
+	return (evt_id == E_DONE);
+}
+
 /**
  * evtq_t - the
- * @len: number of items on queue
+ * @len: number of items on queue, including @ctl
+ * @max_len: high-water mark of @len
  * @head: head of queue
+ * @ctl: head of the control lane, always dequeued before @head
  * @mutex: mutex guarding access to the queue
  * @cond: condition set when an event is added to queue
+ * @waiters: number of consumers blocked waiting for an event
  *
  * This is user-space implementation of the kernel list management function 
  * https://www.kesrnel.org/doc/html/v5.1/core-api/kernel-api.html#list-management-functions
@@ -76,11 +102,49 @@ struct fsm_event {
  */
 typedef struct {
 	int len;
+	int max_len;
 	struct fsm_event head;
+	struct fsm_event ctl;
 	pthread_mutex_t mutex;
 	pthread_cond_t cond;
+	int waiters;
 } evtq_t;
 
+/* number of dead letters kept, the oldest are overwritten */
+#define EVTQ_DEAD_MAX 32
+
+/**
+ * enum dead_reason - why an event was not run, see evtq_dead
+ * @DEAD_NO_TARGET: sent to an unknown worker or an empty group
+ * @DEAD_DOWN: sent to a worker whose thread has exited
+ * @DEAD_UNHANDLED: the target FSM has no transition for it (-U log, dead)
+ * @DEAD_STALE: queued too long and dropped by the target stale policy
+ */
+enum dead_reason {
+	DEAD_NO_TARGET,
+	DEAD_DOWN,
+	DEAD_UNHANDLED,
+	DEAD_STALE,
+};
+
+/**
+ * struct dead_letter - an event that was not run
+ * @ts: run clock time the event was declared dead
+ * @target: worker or group name the event was sent to
+ * @evt_id: the event
+ * @data: the event payload
+ * @reason: why it is dead
+ * @detail: optional, e.g. the state that did not handle it
+ */
+struct dead_letter {
+	struct timespec ts;
+	char target[32];
+	fsm_events_t evt_id;
+	uint32_t data;
+	enum dead_reason reason;
+	const char *detail;
+};
+
 /**
  * _dbg_evts - create a debug string for event 
  * @func - the current function
@@ -101,10 +165,24 @@ extern evtq_t* evtq_create(void);
 extern void evtq_destroy(evtq_t* q_p);
 extern void evtq_destroy_all(evtq_t** q_pp);
 extern void evtq_enqueue(evtq_t *evtq_p, fsm_events_t id);
+extern void evtq_enqueue_data(evtq_t *evtq_p, fsm_events_t id, uint32_t data);
 extern void evtq_dequeue(evtq_t *evtq_p, fsm_events_t* id_p);
+extern uint32_t evtq_dequeue_batch(evtq_t *evtq_p, fsm_events_t *ids, uint32_t *ages,
+				   uint32_t *data, uint32_t max, uint32_t timeout_ms);
 extern uint32_t evtq_len(evtq_t *evtq_p);
+extern bool evtq_idle(evtq_t *evtq_p);
+extern uint32_t evtq_activity(void);
+extern void evtq_dead(const char *target, fsm_events_t evt_id, uint32_t data,
+		      enum dead_reason reason, const char *detail);
+extern uint32_t evtq_dead_count(void);
+extern void show_dead(void);
+extern uint32_t cli_errors;
+extern volatile uint32_t cli_mode;
+extern uint32_t cli_mode_max;
+extern volatile sig_atomic_t cli_stop;
+extern volatile sig_atomic_t cli_dump;
 extern int evt_parse_buf(const char const *buf);
-extern void evt_script(void);
+extern int evt_script(void);
 extern void evt_producer(void);
 
 #endif /* _EVTQ_H */
@@ -0,0 +1,268 @@
+/*
+ * SPDX-License-Identifier: GPL-2.0
+ * Copyright (C) 2021 Dahetral Systems
+ * Author: David Turvene (dturvene@dahetral.com)
+ *
+ * export FSM run statistics for offline analysis
+ */
+
+#include <utils.h>
+#include <fsm.h>
+#include <workers.h>
+#include <export.h>
+
+/**
+ * export_trans - write the transition history as CSV
+ * @fname - output file name
+ *
+ * One row per transition: ts_ms,fsm,event,from,to,dwell_ms
+ * dwell_ms is the time spent in the from state, empty for the first
+ * transition of each FSM.
+ *
+ * Return: 0 on success, -1 if the file cannot be created
+ */
+static int export_trans(const char *fname)
+{
+	static struct fsm_hist hist[FSM_HIST_LEN];
+	uint32_t i, j, n;
+	FILE *fout;
+
+	if (NULL == (fout = fopen(fname, "w"))) {
+		perror(fname);
+		return(-1);
+	}
+
+	n = fsm_hist_get(hist, FSM_HIST_LEN);
+	fprintf(fout, "ts_ms,fsm,event,from,to,dwell_ms\n");
+	for (i = 0; i < n; i++) {
+		fprintf(fout, "%.3f,%s,%s,%s,%s,", ts_ms(&hist[i].ts),
+			hist[i].name, evt_name[hist[i].event],
+			hist[i].from_p->name, hist[i].to_p->name);
+
+		/* find when this FSM entered the from state */
+		for (j = i; j-- > 0; ) {
+			if (hist[j].name == hist[i].name) {
+				fprintf(fout, "%.3f", ts_ms(&hist[i].ts) - ts_ms(&hist[j].ts));
+				break;
+			}
+		}
+		fprintf(fout, "\n");
+	}
+
+	fclose(fout);
+	return(0);
+}
+
+/**
+ * export_events - write the event count of each worker as CSV
+ * @fname - output file name
+ *
+ * One row per worker and event received: fsm,event,count
+ *
+ * Return: 0 on success, -1 if the file cannot be created
+ */
+static int export_events(const char *fname)
+{
+	worker_t *w_p;
+	FILE *fout;
+	int evt_id;
+
+	if (NULL == (fout = fopen(fname, "w"))) {
+		perror(fname);
+		return(-1);
+	}
+
+	fprintf(fout, "fsm,event,count\n");
+	nl_list_for_each_entry(w_p, &workers.head.list, list) {
+		for (evt_id = E_BAD; evt_id < E_LAST; evt_id++) {
+			if (w_p->evt_cnt[evt_id])
+				fprintf(fout, "%s,%s,%u\n", w_p->name,
+					evt_name[evt_id], w_p->evt_cnt[evt_id]);
+		}
+	}
+
+	fclose(fout);
+	return(0);
+}
+
+/**
+ * export_csv - dump transition history and event counts to CSV files
+ * @prefix - file name prefix
+ *
+ * Creates @prefix_trans.csv and @prefix_events.csv which can be loaded
+ * into pandas, a spreadsheet, etc.
+ *
+ * Return: 0 on success, -1 on any file error
+ */
+int export_csv(const char *prefix)
+{
+	char fname[128];
+	int ret;
+
+	snprintf(fname, sizeof(fname), "%s_trans.csv", prefix);
+	ret = export_trans(fname);
+	snprintf(fname, sizeof(fname), "%s_events.csv", prefix);
+	ret |= export_events(fname);
+
+	return(ret);
+}
+
+/* SVG timeline geometry in pixels */
+#define SVG_LABEL_W 100
+#define SVG_WIDTH 1000
+#define SVG_ROW_H 40
+
+/**
+ * state_color - pick a fill color for a state bar from its name
+ * @name - state name
+ *
+ * The common stoplight states get their natural color, other states a
+ * color derived from the name so it is stable between runs.
+ */
+static const char *state_color(const char *name)
+{
+	static const char * const palette[] = {
+		"#9ecae1", "#c6dbef", "#fdd0a2", "#c7e9c0", "#dadaeb", "#d9d9d9",
+	};
+	uint32_t hash = 0;
+
+	if (strstr(name, "RED"))
+		return "#fb6a4a";
+	if (strstr(name, "YELLOW"))
+		return "#fee391";
+	if (strstr(name, "GREEN"))
+		return "#74c476";
+	if (strstr(name, "BLINK"))
+		return "#fdae6b";
+	while (*name)
+		hash = hash * 31 + *name++;
+	return palette[hash % (sizeof(palette)/sizeof(palette[0]))];
+}
+
+/**
+ * export_svg - write a Gantt-style timeline of the transition history
+ * @fname - output SVG file name, viewable in any browser
+ *
+ * Each worker FSM is one row.  Every state is a bar from the transition
+ * entering it to the next transition leaving it (or the end of the
+ * recording) and each transition has a marker with the event name as
+ * tooltip.
+ *
+ * Return: 0 on success, -1 if the file cannot be created or there is
+ * no history
+ */
+int export_svg(const char *fname)
+{
+	static struct fsm_hist hist[FSM_HIST_LEN];
+	uint32_t i, j, n, rows = 0, row = 0;
+	double start, span, x0, x1;
+	worker_t *w_p;
+	FILE *fout;
+
+	n = fsm_hist_get(hist, FSM_HIST_LEN);
+	if (0 == n)
+		return(-1);
+
+	if (NULL == (fout = fopen(fname, "w"))) {
+		perror(fname);
+		return(-1);
+	}
+
+	nl_list_for_each_entry(w_p, &workers.head.list, list)
+		rows++;
+
+	start = ts_ms(&hist[0].ts);
+	span = ts_ms(&hist[n-1].ts) - start;
+	if (span <= 0)
+		span = 1;
+
+	fprintf(fout, "<svg xmlns=\"http://www.w3.org/2000/svg\" "
+		"width=\"%d\" height=\"%d\" font-family=\"monospace\" font-size=\"10\">\n",
+		SVG_LABEL_W + SVG_WIDTH + 20, (rows + 1) * SVG_ROW_H);
+
+	nl_list_for_each_entry(w_p, &workers.head.list, list) {
+		int y = row * SVG_ROW_H + 10;
+
+		fprintf(fout, "<text x=\"0\" y=\"%d\">%s</text>\n",
+			y + SVG_ROW_H/2, w_p->name);
+
+		for (i = 0; i < n; i++) {
+			if (hist[i].name != w_p->name)
+				continue;
+
+			/* bar lasts until the next transition of this FSM */
+			for (j = i + 1; j < n; j++) {
+				if (hist[j].name == hist[i].name)
+					break;
+			}
+			x0 = SVG_LABEL_W + (ts_ms(&hist[i].ts) - start) * SVG_WIDTH / span;
+			x1 = SVG_LABEL_W + (j < n ? ts_ms(&hist[j].ts) - start : span) * SVG_WIDTH / span;
+
+			fprintf(fout, "<rect x=\"%.1f\" y=\"%d\" width=\"%.1f\" height=\"%d\" "
+				"fill=\"%s\" stroke=\"#636363\"><title>%s %.0f ms</title></rect>\n",
+				x0, y, x1 - x0, SVG_ROW_H - 10,
+				state_color(hist[i].to_p->name), hist[i].to_p->name,
+				(x1 - x0) * span / SVG_WIDTH);
+			fprintf(fout, "<text x=\"%.1f\" y=\"%d\">%s</text>\n",
+				x0 + 2, y + SVG_ROW_H/2, hist[i].to_p->name);
+			fprintf(fout, "<line x1=\"%.1f\" y1=\"%d\" x2=\"%.1f\" y2=\"%d\" "
+				"stroke=\"black\"><title>%s</title></line>\n",
+				x0, y - 4, x0, y, evt_name[hist[i].event]);
+		}
+		row++;
+	}
+
+	fprintf(fout, "<text x=\"%d\" y=\"%d\">0 ms</text>\n",
+		SVG_LABEL_W, row * SVG_ROW_H + 20);
+	fprintf(fout, "<text x=\"%d\" y=\"%d\" text-anchor=\"end\">%.0f ms</text>\n",
+		SVG_LABEL_W + SVG_WIDTH, row * SVG_ROW_H + 20, span);
+	fprintf(fout, "</svg>\n");
+
+	fclose(fout);
+	return(0);
+}
+
+/**
+ * export_json - write a machine-readable summary of the run
+ * @fname - output file name
+ * @exit_code - the process exit code
+ *
+ * Intended for CI pipelines gating on FSM behavior, e.g.
+ *  {"exit_code": 0, "transitions": 12, "protocol_violations": 0,
+ *   "cli_errors": 0, "workers": [{"name": "stoplight", "state": "S:GREEN",
+ *   "events": 20, "shadow_diffs": 0, "ref_diffs": 0}, ...]}
+ *
+ * Return: 0 on success, -1 if the file cannot be created
+ */
+int export_json(const char *fname, int exit_code)
+{
+	worker_t *w_p;
+	FILE *fout;
+	uint32_t events;
+	int evt_id;
+	bool first = true;
+
+	if (NULL == (fout = fopen(fname, "w"))) {
+		perror(fname);
+		return(-1);
+	}
+
+	fprintf(fout, "{\"exit_code\": %d, \"transitions\": %u, "
+		"\"protocol_violations\": %u, \"cli_errors\": %u, \"workers\": [",
+		exit_code, fsm_hist_count(),
+		__atomic_load_n(&fsm_violations, __ATOMIC_RELAXED), cli_errors);
+	nl_list_for_each_entry(w_p, &workers.head.list, list) {
+		for (events = 0, evt_id = E_BAD; evt_id < E_LAST; evt_id++)
+			events += w_p->evt_cnt[evt_id];
+		fprintf(fout, "%s{\"name\": \"%s\", \"state\": \"%s\", "
+			"\"events\": %u, \"shadow_diffs\": %u, \"ref_diffs\": %u}",
+			first ? "" : ", ", w_p->name,
+			w_p->fsm_p ? worker_state(w_p)->name : "",
+			events, w_p->shadow_diffs, w_p->ref_diffs);
+		first = false;
+	}
+	fprintf(fout, "]}\n");
+
+	fclose(fout);
+	return(0);
+}
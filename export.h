@@ -0,0 +1,16 @@
+/*
+ * SPDX-License-Identifier: GPL-2.0
+ * Copyright (C) 2021 Dahetral Systems
+ * Author: David Turvene (dturvene@dahetral.com)
+ *
+ * export FSM run statistics for offline analysis
+ */
+
+#ifndef _EXPORT_H
+#define _EXPORT_H
+
+extern int export_csv(const char *prefix);
+extern int export_svg(const char *fname);
+extern int export_json(const char *fname, int exit_code);
+
+#endif /* _EXPORT_H */
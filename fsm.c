@@ -9,31 +9,263 @@
 #include <utils.h>
 #include <workers.h>
 #include <fsm.h>
+#include <timer.h>
+
+/*
+ * fsm_lock - guard transition table lookup against runtime table
+ * mutation so a change is only seen between events.  The workers only
+ * read the tables, they take it shared and do not wait for each other.
+ */
+static pthread_rwlock_t fsm_lock = PTHREAD_RWLOCK_INITIALIZER;
+
+/*
+ * fsm_spare - current state of the FSM_TRANS_SPARE free entries, never
+ * entered
+ */
+fsm_state_t fsm_spare = {"(free)"};
+
+/*
+ * fsm_hist - ring of the last FSM_HIST_LEN transitions for all FSMs
+ * hist_cnt - total number of transitions recorded
+ */
+static struct fsm_hist fsm_hist[FSM_HIST_LEN];
+static uint32_t hist_cnt;
+static pthread_mutex_t hist_mutex = PTHREAD_MUTEX_INITIALIZER;
+
+/*
+ * fsm_observers - the transition observers, see fsm_observe
+ * observer_cnt - number of fsm_observers, read without the lock
+ * observer_lock - guard fsm_observers, read held during the calls
+ */
+static struct fsm_observer {
+	fsm_trans_t *fsm_p;
+	fsm_observer_fn fn;
+	void *arg;
+} fsm_observers[FSM_OBSERVERS];
+static uint32_t observer_cnt;
+static pthread_rwlock_t observer_lock = PTHREAD_RWLOCK_INITIALIZER;
+
+/*
+ * fsm_violations - number of protocol violations (see check_expect),
+ * counted by every worker so it is only updated and read atomically
+ */
+uint32_t fsm_violations;
+
+/*
+ * fsm_profile - when true, time the exit and entry action calls of each
+ * transition run by fsm_run and count their fsm_malloc and fsm_calloc
+ * calls, see fsm_prof_show
+ */
+bool fsm_profile = false;
+
+__thread uint32_t fsm_allocs;
+
+const char *fsm_phase_name[] = {
+	"idle",
+	"guard",
+	"exit",
+	"trans",
+	"entry",
+	"post",
+};
+
+/**
+ * fsm_phase_get - where the calling worker FSM is in a transition
+ *
+ * An action or guard uses this to know where it is running in the
+ * fsm_run sequence, see enum fsm_phase.
+ *
+ * Return: the phase, PH_IDLE if not called from a worker
+ */
+enum fsm_phase fsm_phase_get(void)
+{
+	worker_t *w_p = worker_self();
+
+	return(w_p ? w_p->phase : PH_IDLE);
+}
+
+/**
+ * fsm_ctx_get - extended state of the calling worker FSM
+ *
+ * Actions and guards are only passed their state or FSM table, this is
+ * how they reach per-FSM data (counters, configuration) set in the
+ * worker @ctx field.
+ *
+ * Return: the context, NULL if none or not called from a worker
+ */
+void *fsm_ctx_get(void)
+{
+	worker_t *w_p = worker_self();
+
+	return(w_p ? w_p->ctx : NULL);
+}
+
+/**
+ * fsm_post - post an internal event to the calling worker FSM
+ * @evt_id - the event
+ *
+ * Unlike a broadcast, the event is only sent to the calling FSM and it is
+ * run to completion: all internal events are run after the current
+ * transition and before the next event on the worker event queue.
+ *
+ * Return: 0 on success, -1 if not called from an FSM worker
+ */
+int fsm_post(fsm_events_t evt_id)
+{
+	worker_t *w_p = worker_self();
+
+	if (NULL == w_p || NULL == w_p->intq_p)
+		return(-1);
+	evtq_enqueue(w_p->intq_p, evt_id);
+	return(0);
+}
+
+/**
+ * fsm_evt_data - payload of the event the calling worker FSM is running
+ *
+ * Transitions match on the event id only, this is how a guard or action
+ * reads the data carried by the event (e.g. which button was pressed.)
+ * An event queued by a reentrant fsm_run keeps the payload of the event
+ * being run, one posted with fsm_post has none.
+ *
+ * Return: the payload, 0 if none or not called from a worker
+ */
+uint32_t fsm_evt_data(void)
+{
+	worker_t *w_p = worker_self();
+
+	return(w_p ? w_p->evt_data : 0);
+}
+
+/**
+ * set_phase - update the worker phase
+ * @w_p - the worker running fsm_run, may be NULL
+ * @phase - the new phase
+ */
+static inline void set_phase(worker_t *w_p, enum fsm_phase phase)
+{
+	if (w_p)
+		w_p->phase = phase;
+}
+
+/*
+ * dbg_trans_fmt - optional DBG_TRANS line template, NULL for the default
+ * line "{fsm}:ts={ts} {desc} usec={usec}".  The fields are:
+ *  {ts}: secs.msecs since the run start, see ts_anchor
+ *  {fsm}: worker name
+ *  {from}: current state
+ *  {event}: event name
+ *  {to}: next state, "no next" if the event is not handled
+ *  {desc}: "evt={event} trans {from} to {to}"
+ *  {usec}: usecs from the first exit action to the state change, 0 when
+ *   the state is not left
+ * Any other text is copied as is.
+ */
+const char *dbg_trans_fmt;
+
+/**
+ * trans_fmt - expand dbg_trans_fmt
+ * @buf - output buffer
+ * @size - size of @buf
+ * @fields - field values, in the order of names below
+ *
+ * Return: the length of the expanded string, truncated to @size
+ */
+static int trans_fmt(char *buf, size_t size, const char * const *fields)
+{
+	static const char * const names[] = {
+		"{ts}", "{fsm}", "{from}", "{event}", "{to}", "{desc}", "{usec}",
+	};
+	const char *sp = dbg_trans_fmt;
+	size_t len = 0, n;
+	int i;
+
+	while (*sp && len < size - 1) {
+		for (i = 0; i < sizeof(names)/sizeof(names[0]); i++) {
+			n = strlen(names[i]);
+			if (0 == strncmp(sp, names[i], n))
+				break;
+		}
+		if (i < sizeof(names)/sizeof(names[0])) {
+			len += snprintf(buf + len, size - len, "%s", fields[i]);
+			sp += n;
+		} else {
+			buf[len++] = *sp++;
+		}
+	}
+	if (len > size - 1)
+		len = size - 1;
+	buf[len] = '\0';
+	return(len);
+}
 
 /**
  * dbg_trans - write to stdout detailed information about the FSM state transition
  * @fsm_p - pointer to FSM context
  * @nextst_p - pointer to presumptive next state (before guard check)
  * @evt_id - event id
+ * @guarded - every transition for @evt_id was refused by its guard
+ * @ns - nsecs from the first exit action to the state change, 0 when the
+ *   state is not left
+ *
+ * string containing thread, timestamp, evtid, currstate to nextstate and
+ * the transition duration, formatted with dbg_trans_fmt when set.
+ * Nothing is written for a quiet worker, see workers_trace.
+ * A transition leaving the state is written once the exit actions have
+ * run, before the current state is updated and the entry actions run.
  *
- * string containing thread, timestamp, evtid, currstate to nextstate
- * This is called before transition guard check.
+ * With dbg_json the line is a JSON object of type "trans", "guarded" or
+ * "reject" (not handled, "to" is null) with the duration in "usec", see
+ * dbg_json_line.
  */
-void dbg_trans(fsm_trans_t *fsm_p, fsm_state_t *nextst_p, fsm_events_t evt_id)
+void dbg_trans(fsm_trans_t *fsm_p, fsm_state_t *nextst_p, fsm_events_t evt_id,
+	       bool guarded, uint64_t ns)
 {
 	struct timespec ts;
-	char buf[120];
+	char buf[136], tsbuf[16], desc[96], usec[24];
+	const char *fields[7];
+	worker_t *w_p;
 	int len;
 
 	if (!(debug_flag & DBG_TRANS))
 		return;
+	if ((w_p = worker_self()) && __atomic_load_n(&w_p->quiet, __ATOMIC_RELAXED))
+		return;
+
+	snprintf(usec, sizeof(usec), "%lu", (unsigned long)(ns / 1000));
+	if (dbg_json) {
+		dbg_json_line(!nextst_p ? "reject" : guarded ? "guarded" : "trans",
+			      "fsm", worker_get_name(),
+			      "from", fsm_p->currst_p->name,
+			      "event", evt_name[evt_id],
+			      "to", nextst_p ? nextst_p->name : NULL,
+			      "usec", usec, NULL);
+		return;
+	}
 			
-	clock_gettime(CLOCK_MONOTONIC, &ts);
-	len=snprintf(buf, sizeof(buf), "%s:ts=%ld.%3ld evt=%s trans %s to %s\n",
-		     worker_get_name(),
-		     ts.tv_sec%100, ts.tv_nsec/(int)1e6,
-		     evt_name[evt_id],
-		     fsm_p->currst_p->name, nextst_p?nextst_p->name:"no next");
+	clock_now(&ts);
+	if (dbg_trans_fmt) {
+		snprintf(tsbuf, sizeof(tsbuf), "%.3f", ts_ms(&ts) / 1e3);
+		snprintf(desc, sizeof(desc), "evt=%s trans %s to %s", evt_name[evt_id],
+			 fsm_p->currst_p->name, nextst_p?nextst_p->name:"no next");
+		fields[0] = tsbuf;
+		fields[1] = worker_get_name();
+		fields[2] = fsm_p->currst_p->name;
+		fields[3] = evt_name[evt_id];
+		fields[4] = nextst_p?nextst_p->name:"no next";
+		fields[5] = desc;
+		fields[6] = usec;
+		len = trans_fmt(buf, sizeof(buf) - 1, fields);
+		buf[len++] = '\n';
+		buf[len] = '\0';
+	} else {
+		len=snprintf(buf, sizeof(buf), "%s:ts=%.3f evt=%s trans %s to %s usec=%s\n",
+			     worker_get_name(),
+			     ts_ms(&ts) / 1e3,
+			     evt_name[evt_id],
+			     fsm_p->currst_p->name, nextst_p?nextst_p->name:"no next",
+			     usec);
+	}
 	
 	/* if cannot fit entire string into buffer, force a newline and null at end */
 	if (len >= sizeof(buf)) {
@@ -44,31 +276,402 @@ void dbg_trans(fsm_trans_t *fsm_p, fsm_state_t *nextst_p, fsm_events_t evt_id)
 	write(1, buf, strlen(buf));
 }
 
+/*
+ * dbg_keepalive_ms - with DBG_CHANGE, publish an unchanged state again
+ * after this many msecs without a publication, 0 for never
+ */
+uint32_t dbg_keepalive_ms;
+
 /**
- * next_state - find next state in FSM table and return it
+ * dbg_change - write to stdout the FSM state when it changes
+ * @w_p - the worker running the FSM, may be NULL
+ * @fsm_p - pointer to FSM context, after the event has run
+ * @from_p - the state before the event
+ * @evt_id - event id
+ *
+ * Unlike dbg_trans, an event with no transition, a failed guard or a
+ * self transition is not written so a reader only sees real changes.
+ * With dbg_keepalive_ms set, an unchanged state is written again once
+ * nothing has been published for the worker in that long.
+ *
+ * With dbg_json the line is a JSON object of type "state", its "cause"
+ * is "change" or "keepalive", see dbg_json_line.
+ */
+static void dbg_change(worker_t *w_p, fsm_trans_t *fsm_p, fsm_state_t *from_p,
+		       fsm_events_t evt_id)
+{
+	struct timespec ts;
+	bool keepalive = false;
+	char buf[120];
+	int len;
+
+	if (!(debug_flag & DBG_CHANGE) || (w_p && __atomic_load_n(&w_p->quiet, __ATOMIC_RELAXED)))
+		return;
+
+	clock_now(&ts);
+	if (fsm_p->currst_p == from_p) {
+		if (NULL == w_p || 0 == dbg_keepalive_ms ||
+		    (ts.tv_sec - w_p->last_pub.tv_sec) * 1000 +
+		    (ts.tv_nsec - w_p->last_pub.tv_nsec) / 1000000 < dbg_keepalive_ms)
+			return;
+		keepalive = true;
+	}
+	if (w_p)
+		w_p->last_pub = ts;
+
+	if (dbg_json) {
+		dbg_json_line("state",
+			      "fsm", worker_get_name(),
+			      "event", evt_name[evt_id],
+			      "state", fsm_p->currst_p->name,
+			      "cause", keepalive ? "keepalive" : "change", NULL);
+		return;
+	}
+
+	len = snprintf(buf, sizeof(buf), "%s:ts=%.3f evt=%s state %s%s\n",
+		       worker_get_name(), ts_ms(&ts) / 1e3,
+		       evt_name[evt_id], fsm_p->currst_p->name,
+		       keepalive ? " (keepalive)" : "");
+	if (len >= sizeof(buf))
+		len = sizeof(buf) - 1;
+	write(1, buf, len);
+}
+
+/* max transitions for one state and event, see match_all */
+#define FSM_MAX_CANDIDATES 8
+
+/**
+ * struct fsm_bucket - one (state, event) key of a transition index
+ * @st_p - the transition current state, NULL if the bucket is empty
+ * @event - the transition event
+ * @head - table offset of its highest priority transition
+ */
+struct fsm_bucket {
+	fsm_state_t *st_p;
+	fsm_events_t event;
+	int head;
+};
+
+/**
+ * struct fsm_index - hash index of an FSM transition table
+ * @fsm_p - the FSM table, its first entry points to the index
+ * @mask - number of buckets - 1, a power of 2 at least twice the table length
+ * @buckets - open addressing hash of the (state, event) keys
+ * @next - for each table entry, the offset of the next transition with
+ *   the same key, -1 at the end of the chain
+ *
+ * A chain is sorted by priority, highest first, and in table order for
+ * the same priority so it is the match_all order.
+ */
+struct fsm_index {
+	fsm_trans_t *fsm_p;
+	uint32_t mask;
+	struct fsm_bucket *buckets;
+	int *next;
+};
+
+/**
+ * index_bucket - find the bucket for a key
+ * @idx_p - the index
+ * @st_p - the state
+ * @evt_id - event id
+ *
+ * Return: the bucket holding the key or the empty bucket where it goes
+ */
+static struct fsm_bucket *index_bucket(struct fsm_index *idx_p, fsm_state_t *st_p,
+				       fsm_events_t evt_id)
+{
+	uint32_t h = ((uint32_t)((uintptr_t)st_p >> 4) * 31 + evt_id) * 2654435761u;
+	struct fsm_bucket *b_p;
+
+	for (h &= idx_p->mask; ; h = (h + 1) & idx_p->mask) {
+		b_p = &idx_p->buckets[h];
+		if (NULL == b_p->st_p || (b_p->st_p == st_p && b_p->event == evt_id))
+			return(b_p);
+	}
+}
+
+/**
+ * index_fill - rebuild an index from its table
+ * @idx_p - the index
+ *
+ * The arrays are sized for the table length, which does not change when
+ * transitions are added or removed, so they are reused.
+ */
+static void index_fill(struct fsm_index *idx_p)
+{
+	fsm_trans_t *fsm_p = idx_p->fsm_p;
+	struct fsm_bucket *b_p;
+	int i, *link_p;
+
+	memset(idx_p->buckets, 0, (idx_p->mask + 1) * sizeof(*idx_p->buckets));
+	for (i = 1; fsm_p[i].currst_p != NULL; i++) {
+		idx_p->next[i] = -1;
+		if (fsm_p[i].event == E_BAD)
+			continue;
+		b_p = index_bucket(idx_p, fsm_p[i].currst_p, fsm_p[i].event);
+		if (NULL == b_p->st_p) {
+			b_p->st_p = fsm_p[i].currst_p;
+			b_p->event = fsm_p[i].event;
+			b_p->head = i;
+			continue;
+		}
+		/* after the transitions with the same or a higher priority */
+		for (link_p = &b_p->head; *link_p != -1 &&
+			     fsm_p[*link_p].prio >= fsm_p[i].prio; link_p = &idx_p->next[*link_p])
+			;
+		idx_p->next[i] = *link_p;
+		*link_p = i;
+	}
+}
+
+/**
+ * index_update - rebuild the index after a runtime table change
+ * @fsm_p - pointer to FSM context
+ *
+ * Called with fsm_lock held for writing.
+ */
+static void index_update(fsm_trans_t *fsm_p)
+{
+	if (fsm_p->index_p)
+		index_fill(fsm_p->index_p);
+}
+
+/**
+ * fsm_index_build - index an FSM table for constant time event dispatch
+ * @fsm_p - pointer to FSM context
+ *
+ * Without an index each event scans the whole table.  The index maps a
+ * state and event to its transitions so the lookup cost does not depend
+ * on the table size.  It is kept up to date by fsm_trans_add,
+ * fsm_trans_remove and fsm_trans_replace.
+ *
+ * Return: 0 on success, -1 if out of memory
+ */
+int fsm_index_build(fsm_trans_t *fsm_p)
+{
+	struct fsm_index *idx_p;
+	uint32_t len, size;
+	int ret = -1;
+
+	pthread_rwlock_wrlock(&fsm_lock);
+	if (fsm_p->index_p) {
+		index_fill(fsm_p->index_p);
+		ret = 0;
+		goto out;
+	}
+
+	for (len = 0; fsm_p[len].currst_p != NULL; len++)
+		;
+	for (size = 4; size < 2 * len; size <<= 1)
+		;
+	if (NULL == (idx_p = fsm_calloc(1, sizeof(*idx_p))))
+		goto out;
+	idx_p->buckets = fsm_calloc(size, sizeof(*idx_p->buckets));
+	idx_p->next = fsm_calloc(len + 1, sizeof(*idx_p->next));
+	if (NULL == idx_p->buckets || NULL == idx_p->next) {
+		free(idx_p->buckets);
+		free(idx_p->next);
+		free(idx_p);
+		goto out;
+	}
+	idx_p->mask = size - 1;
+	idx_p->fsm_p = fsm_p;
+	index_fill(idx_p);
+	fsm_p->index_p = idx_p;
+	ret = 0;
+out:
+	pthread_rwlock_unlock(&fsm_lock);
+	return(ret);
+}
+
+/**
+ * fsm_index_drop - free the index of an FSM table
+ * @fsm_p - pointer to FSM context, no longer run
+ *
+ * The table is scanned again if it is run.
+ */
+void fsm_index_drop(fsm_trans_t *fsm_p)
+{
+	struct fsm_index *idx_p;
+
+	pthread_rwlock_wrlock(&fsm_lock);
+	if ((idx_p = fsm_p->index_p)) {
+		fsm_p->index_p = NULL;
+		free(idx_p->buckets);
+		free(idx_p->next);
+		free(idx_p);
+	}
+	pthread_rwlock_unlock(&fsm_lock);
+}
+
+/**
+ * state_timed - find a state with a timeout in a state and its superstates
+ * @st_p - the state, may be NULL
+ *
+ * Return: the state with a timeout, NULL for none
+ */
+static fsm_state_t *state_timed(fsm_state_t *st_p)
+{
+	for (; st_p; st_p = st_p->parent)
+		if (st_p->timeout.ms_p)
+			return(st_p);
+	return(NULL);
+}
+
+/**
+ * fsm_clone - copy an FSM table for another instance of the machine
+ * @fsm_p - pointer to FSM context
+ * @initst_p - the init state, the current state of the copy
+ *
+ * An FSM table holds its current state so one table is one running
+ * machine.  A copy shares the states and actions but runs on its own,
+ * e.g. a crosswalk for each corner of the intersection, with no history
+ * (see record_history).  The copy is indexed if @fsm_p is.
+ *
+ * A state timeout has one timer, routed to the FSM that entered the
+ * state last (see run_entries), so two instances would take each other's
+ * timeout.  A table with a timeout in any of its states is not copied.
+ *
+ * Return: the copy, to be run by a new worker, or NULL if out of memory
+ * or a state has a timeout
+ */
+fsm_trans_t *fsm_clone(fsm_trans_t *fsm_p, fsm_state_t *initst_p)
+{
+	fsm_trans_t *clone_p;
+	uint32_t len;
+	bool indexed;
+
+	for (len = 0; fsm_p[len].currst_p != NULL; len++) {
+		fsm_state_t *st_p = state_timed(fsm_p[len].currst_p);
+
+		if (!st_p)
+			st_p = state_timed(fsm_p[len].nextst_p);
+		if (!st_p && fsm_p[len].nextst_p)
+			st_p = state_timed(fsm_p[len].nextst_p->default_p);
+		if (st_p) {
+			printf("%s: %s has a timeout, the table cannot be copied\n",
+			       __func__, st_p->name);
+			return(NULL);
+		}
+	}
+	if (NULL == (clone_p = fsm_malloc((len + 1) * sizeof(*clone_p))))
+		return(NULL);
+
+	pthread_rwlock_rdlock(&fsm_lock);
+	memcpy(clone_p, fsm_p, (len + 1) * sizeof(*clone_p));
+	indexed = (NULL != fsm_p->index_p);
+	pthread_rwlock_unlock(&fsm_lock);
+	clone_p[0].currst_p = initst_p;
+	clone_p[0].resume_p = NULL;
+	clone_p[0].index_p = NULL;
+
+	/* out of memory for the index only means the copy is scanned */
+	if (indexed)
+		fsm_index_build(clone_p);
+	return(clone_p);
+}
+
+/**
+ * cand_add - insert a transition into the candidates by priority
+ * @cands - the candidates, highest priority first
+ * @n - number of candidates
+ * @t_p - the transition, after the candidates with the same priority
+ *
+ * Return: the new number of candidates
+ */
+static int cand_add(fsm_trans_t **cands, int n, fsm_trans_t *t_p)
+{
+	int i;
+
+	if (n == FSM_MAX_CANDIDATES)
+		return(n);
+	for (i = n++; i > 0 && cands[i-1]->prio < t_p->prio; i--)
+		cands[i] = cands[i-1];
+	cands[i] = t_p;
+	return(n);
+}
+
+/**
+ * match_all - find the transitions for an event in a state or its superstates
  * @fsm_p - pointer to FSM context
+ * @st_p - the state
  * @evt_id - event id
+ * @cands - updated with the transitions, highest priority first
  *
- * loop through the FSM transition table, matching curr state and evt_id
- * then return a pointer to the next state.
+ * The state is searched first, then each superstate outward so a
+ * substate transition overrides the superstate one.  Only the transitions
+ * of the innermost state with a match are returned, transitions with the
+ * same priority are in table order.
  *
- * Return: pointer to next state or NULL if no match
+ * An indexed table (see fsm_index_build) is not scanned, only the first
+ * entry is checked because its current state changes.
  *
- * TODO: make evt_id match in the transition table more efficient.  We already
- * know current state so just a matter of matching the evt_id.
+ * Return: number of transitions in @cands, 0 if no match
  */
-fsm_state_t *next_state(fsm_trans_t *fsm_p, fsm_events_t evt_id)
+static int match_all(fsm_trans_t *fsm_p, fsm_state_t *st_p, fsm_events_t evt_id,
+		     fsm_trans_t **cands)
 {
-	fsm_trans_t *t_p = fsm_p;
-	char msg[80];
-			
-	while (t_p->currst_p != NULL) {
-		if (t_p->currst_p == fsm_p->currst_p && t_p->event == evt_id) {
-			sprintf(msg, "%s: match %s", fsm_p->currst_p->name, evt_name[evt_id]);
-			dbg_verbose(msg);
-			return (t_p->nextst_p);
+	struct fsm_index *idx_p = fsm_p->index_p;
+	struct fsm_bucket *b_p;
+	fsm_trans_t *t_p;
+	int i, n = 0;
+
+	for (; st_p != NULL && 0 == n; st_p = st_p->parent) {
+		if (NULL == idx_p) {
+			for (t_p = fsm_p; t_p->currst_p != NULL; t_p++) {
+				if (t_p->currst_p == st_p && t_p->event == evt_id &&
+				    t_p->event != E_BAD)
+					n = cand_add(cands, n, t_p);
+			}
+			continue;
 		}
-		t_p++;
+
+		if (fsm_p->currst_p == st_p && fsm_p->event == evt_id)
+			n = cand_add(cands, n, fsm_p);
+		b_p = index_bucket(idx_p, st_p, evt_id);
+		for (i = b_p->st_p ? b_p->head : -1; i != -1; i = idx_p->next[i])
+			n = cand_add(cands, n, &fsm_p[i]);
+	}
+	return(n);
+}
+
+/**
+ * match_from - find the transition for an event in a state or its superstates
+ * @fsm_p - pointer to FSM context
+ * @st_p - the state
+ * @evt_id - event id
+ *
+ * Return: the highest priority transition, see match_all, guards are not
+ * run.  NULL if no match
+ */
+static fsm_trans_t *match_from(fsm_trans_t *fsm_p, fsm_state_t *st_p, fsm_events_t evt_id)
+{
+	fsm_trans_t *cands[FSM_MAX_CANDIDATES];
+
+	return(match_all(fsm_p, st_p, evt_id, cands) ? cands[0] : NULL);
+}
+
+/**
+ * match_trans - find the transition in FSM table for the current state
+ * @fsm_p - pointer to FSM context
+ * @evt_id - event id
+ *
+ * loop through the FSM transition table, matching curr state and evt_id
+ * then return a pointer to the transition.
+ *
+ * Return: pointer to the transition or NULL if no match
+ */
+static fsm_trans_t *match_trans(fsm_trans_t *fsm_p, fsm_events_t evt_id)
+{
+	fsm_trans_t *t_p;
+	char msg[80];
+
+	if ((t_p = match_from(fsm_p, fsm_p->currst_p, evt_id))) {
+		sprintf(msg, "%s: match %s in %s", fsm_p->currst_p->name, evt_name[evt_id],
+			t_p->currst_p->name);
+		dbg_verbose(msg);
+		return (t_p);
 	}
 
 	sprintf(msg, "%s: NO match %s", fsm_p->currst_p->name, evt_name[evt_id]);
@@ -76,60 +679,1424 @@ fsm_state_t *next_state(fsm_trans_t *fsm_p, fsm_events_t evt_id)
 	return(NULL);
 }
 
+/*
+ * fsm_strict - when set, several transitions for one state and event must
+ * have distinct priorities even if they are guarded, see fsm_validate
+ */
+bool fsm_strict = false;
+
 /**
- * fsm_run - crank the FSM once for input event
- * @fsm_p - the FSM context
- * @evt_id - the event id
+ * select_trans - pick the transition to run for the current state
+ * @fsm_p - pointer to FSM context
+ * @evt_id - event id
+ * @guarded_p - set if transitions match but every guard refused
+ * @self_p - the worker running fsm_run, may be NULL
+ * @sel_p - updated with a copy of the returned transition
  *
- * - find the next state
- * - if valid (not NULL), check for a guard
- * - if guard, call it and return if fails (false)
- * - otherwise 
- * -  call exit action of the current state
- * -  move to next state
- * -  call entry action of new current state
+ * The matching transitions (see match_all) are tried highest priority
+ * first and the first with no guard or a passing guard is enabled.  The
+ * guards of the other transitions with its priority are also run: a
+ * second enabled one is a conflict, reported as a protocol violation,
+ * and the first in the table runs.  The guards run in PH_GUARD so a guard
+ * calling fsm_run for its own FSM is caught as reentrant.
+ *
+ * The candidates are copied under fsm_lock and the guards run on the
+ * copies, without the lock: a runtime table change (e.g. fsm_trans_replace)
+ * made while a guard runs is seen on the next event, it cannot mix the
+ * guard of one transition with the next state of another.
  *
- * Return:
- *  -1: FSM failure, 
- *   0: failed transition to next state (guard failure)
- *   1: success transition to next state
+ * Return: the table entry of the enabled transition, the highest priority
+ * one when @guarded_p is set, NULL if no match.  Only @sel_p is to be run
  */
-int fsm_run(fsm_trans_t* fsm_p, fsm_events_t evt_id)
+static fsm_trans_t *select_trans(fsm_trans_t *fsm_p, fsm_events_t evt_id, bool *guarded_p,
+				 worker_t *self_p, fsm_trans_t *sel_p)
 {
-	fsm_state_t *nextst_p;
-	int ret = -1;  /* set to failed */ 
+	fsm_trans_t *cands[FSM_MAX_CANDIDATES];
+	fsm_trans_t copies[FSM_MAX_CANDIDATES];
+	enum fsm_phase phase = self_p ? self_p->phase : PH_IDLE;
+	char buf[120];
+	int i, n, sel = -1;
 
-	nextst_p = next_state(fsm_p, evt_id);
-	dbg_trans(fsm_p, nextst_p, evt_id);
-	
-	if (nextst_p) {
-		/* check if guard and run it, if guard fails set ret to 1 */
-		if (fsm_p->guard && (false == fsm_p->guard(fsm_p)))
-		{
-			dbg_verbose("Guard FAILED");
-			/* set to guard failed */
-			ret = 1;
-		} else {
-			/* before transition to next state, run curr state
-			 * exit action
-			 */
-			if (fsm_p->currst_p->exit_action) {
-				fsm_p->currst_p->exit_action(fsm_p->currst_p);
-			}
+	pthread_rwlock_rdlock(&fsm_lock);
+	n = match_all(fsm_p, fsm_p->currst_p, evt_id, cands);
+	for (i = 0; i < n; i++)
+		copies[i] = *cands[i];
+	pthread_rwlock_unlock(&fsm_lock);
 
-			/* update currst to nextst */
-			fsm_p->currst_p = nextst_p;
+	set_phase(self_p, PH_GUARD);
+	for (i = 0; i < n; i++) {
+		if (sel >= 0 && copies[i].prio != copies[sel].prio)
+			break;
+		if (copies[i].guard && false == copies[i].guard(fsm_p))
+			continue;
+		if (sel < 0) {
+			sel = i;
+			continue;
+		}
+		__atomic_add_fetch(&fsm_violations, 1, __ATOMIC_RELAXED);
+		snprintf(buf, sizeof(buf), "%s: CONFLICT %s in %s enables %s and %s\n",
+			 worker_get_name(), evt_name[evt_id], fsm_p->currst_p->name,
+			 copies[sel].nextst_p->name, copies[i].nextst_p->name);
+		write(1, buf, strlen(buf));
+	}
+	set_phase(self_p, phase);
 
-			/* run currst entry action after state transition */
-			if (fsm_p->currst_p->entry_action) {
-				fsm_p->currst_p->entry_action(fsm_p->currst_p);
-			}
+	*guarded_p = (n && sel < 0);
+	if (*guarded_p)
+		sel = 0;
+	if (sel < 0)
+		return(NULL);
+	*sel_p = copies[sel];
+	return(cands[sel]);
+}
 
-			dbg_verbose("Guard PASSED");
-			/* set to success! */
-			ret = 0;
-		}
+/**
+ * history_resume - the state a history pseudo-state resumes
+ * @st_p - the history pseudo-state
+ * @leaf_p - the leaf state last active in its superstate, NULL if none
+ *
+ * Return: the state to resume, the default state or NULL if there is none
+ */
+static fsm_state_t *history_resume(fsm_state_t *st_p, fsm_state_t *leaf_p)
+{
+	if (NULL == leaf_p)
+		return(st_p->default_p);
+	if (HISTORY_SHALLOW == st_p->history) {
+		while (leaf_p->parent != st_p->parent)
+			leaf_p = leaf_p->parent;
 	}
-	return (ret);
+	return(leaf_p);
 }
 
+/**
+ * resume_save - remember the leaf state in each superstate being left
+ * @res_p - the superstate history
+ * @from_p - the state left
+ * @lca_p - the common superstate, it is not left
+ *
+ * A superstate is given a free entry when first left, past FSM_MAX_SUPER
+ * superstates its history is not kept (see fsm_validate.)
+ */
+static void resume_save(struct fsm_resume *res_p, fsm_state_t *from_p,
+			fsm_state_t *lca_p)
+{
+	fsm_state_t *st_p;
+	int i;
+
+	for (st_p = from_p->parent; st_p != lca_p && st_p != NULL; st_p = st_p->parent) {
+		for (i = 0; i < FSM_MAX_SUPER; i++) {
+			if (res_p->super_p[i] == st_p || NULL == res_p->super_p[i])
+				break;
+		}
+		if (i == FSM_MAX_SUPER)
+			continue;
+		res_p->super_p[i] = st_p;
+		res_p->last_p[i] = from_p;
+	}
+}
+
+/**
+ * resolve_history - map a history pseudo-state to the state to resume
+ * @res_p - the superstate history, NULL if no superstate was left
+ * @st_p - transition next state
+ *
+ * A superstate that has never been left has no history, its default
+ * state is entered (UML 14.2.3.6.1).
+ *
+ * Return: @st_p if not a history pseudo-state, the state to resume, the
+ * default state or NULL if there is none
+ */
+static fsm_state_t *resolve_history(struct fsm_resume *res_p, fsm_state_t *st_p)
+{
+	int i;
+
+	if (HISTORY_NONE == st_p->history)
+		return(st_p);
+	for (i = 0; res_p && i < FSM_MAX_SUPER; i++) {
+		if (res_p->super_p[i] == st_p->parent)
+			return(history_resume(st_p, res_p->last_p[i]));
+	}
+	return(history_resume(st_p, NULL));
+}
+
+/**
+ * trans_next - the state a transition leads to
+ * @res_p - the superstate history of the FSM
+ * @t_p - the transition
+ * @currst_p - the FSM current state
+ *
+ * Return: @currst_p for an internal transition, otherwise the next
+ * state, NULL for a history state with no history and no default (see
+ * resolve_history)
+ */
+static fsm_state_t *trans_next(struct fsm_resume *res_p, fsm_trans_t *t_p,
+			       fsm_state_t *currst_p)
+{
+	if (TRANS_INTERNAL == t_p->kind)
+		return(currst_p);
+	return(resolve_history(res_p, t_p->nextst_p));
+}
+
+/**
+ * next_state - find next state in FSM table and return it
+ * @fsm_p - pointer to FSM context
+ * @evt_id - event id
+ *
+ * Return: pointer to next state or NULL if no match
+ */
+fsm_state_t *next_state(fsm_trans_t *fsm_p, fsm_events_t evt_id)
+{
+	fsm_trans_t *t_p = match_trans(fsm_p, evt_id);
+
+	return(t_p ? trans_next(fsm_p->resume_p, t_p, fsm_p->currst_p) : NULL);
+}
+
+/**
+ * check_expect - report an unhandled event not expected in the current state
+ * @fsm_p - pointer to FSM context
+ * @evt_id - event id with no transition from the current state
+ *
+ * Only states with an @expect list, in the state or a superstate, are
+ * checked, otherwise all unhandled events are silently discarded.
+ */
+static void check_expect(fsm_trans_t *fsm_p, fsm_events_t evt_id)
+{
+	fsm_state_t *st_p;
+	const fsm_events_t *e_p;
+	bool checked = false;
+	char buf[120];
+
+	for (st_p = fsm_p->currst_p; st_p != NULL; st_p = st_p->parent) {
+		if (NULL == (e_p = st_p->expect))
+			continue;
+		checked = true;
+		for (; *e_p != E_BAD; e_p++) {
+			if (*e_p == evt_id)
+				return;
+		}
+	}
+	if (!checked)
+		return;
+
+	__atomic_add_fetch(&fsm_violations, 1, __ATOMIC_RELAXED);
+	snprintf(buf, sizeof(buf), "%s: PROTOCOL violation evt=%s in %s\n",
+		 worker_get_name(), evt_name[evt_id], fsm_p->currst_p->name);
+	write(1, buf, strlen(buf));
+}
+
+/**
+ * check_post - run the transition postcondition after the entry action
+ * @fsm_p - pointer to FSM context
+ * @t_p - the transition just run
+ * @evt_id - event id causing the transition
+ */
+static void check_post(fsm_trans_t *fsm_p, fsm_trans_t *t_p, fsm_events_t evt_id)
+{
+	char buf[120];
+
+	if (NULL == t_p->post || t_p->post(fsm_p))
+		return;
+
+	__atomic_add_fetch(&fsm_violations, 1, __ATOMIC_RELAXED);
+	snprintf(buf, sizeof(buf), "%s: POSTCONDITION failed evt=%s in %s\n",
+		 worker_get_name(), evt_name[evt_id], fsm_p->currst_p->name);
+	write(1, buf, strlen(buf));
+}
+
+/**
+ * struct action_cost - cost of the actions of one transition, see run_action
+ * @ns - thread cpu nsecs in the action calls
+ * @allocs - fsm_malloc and fsm_calloc calls made by the actions
+ */
+struct action_cost {
+	uint64_t ns;
+	uint32_t allocs;
+};
+
+/**
+ * ts_diff_ns - nsecs between two timestamps of the same clock
+ * @start_p - start time
+ * @end_p - end time
+ */
+static inline uint64_t ts_diff_ns(struct timespec *start_p, struct timespec *end_p)
+{
+	return((end_p->tv_sec - start_p->tv_sec) * 1000000000UL +
+	       end_p->tv_nsec - start_p->tv_nsec);
+}
+
+/**
+ * prof_update - add an action execution time to the transition profile
+ * @t_p - the transition
+ * @cost_p - cost of the exit and entry actions
+ */
+static void prof_update(fsm_trans_t *t_p, struct action_cost *cost_p)
+{
+	t_p->prof.count++;
+	t_p->prof.allocs += cost_p->allocs;
+	t_p->prof.total_ns += cost_p->ns;
+	if (cost_p->ns > t_p->prof.max_ns)
+		t_p->prof.max_ns = cost_p->ns;
+}
+
+/**
+ * run_action - run an exit or entry action
+ * @act - the action
+ * @st_p - its state
+ * @cost_p - with fsm_profile, updated with the thread cpu time and the
+ *   allocations of the action
+ *
+ * Only the action call is timed, on the thread cpu clock so the time
+ * the worker is preempted is not charged to the action.
+ */
+static void run_action(action act, fsm_state_t *st_p, struct action_cost *cost_p)
+{
+	struct timespec start, end;
+	uint32_t allocs = fsm_allocs;
+
+	if (!fsm_profile) {
+		act(st_p);
+		return;
+	}
+	clock_gettime(CLOCK_THREAD_CPUTIME_ID, &start);
+	act(st_p);
+	clock_gettime(CLOCK_THREAD_CPUTIME_ID, &end);
+	cost_p->ns += ts_diff_ns(&start, &end);
+	cost_p->allocs += fsm_allocs - allocs;
+}
+
+/**
+ * fsm_prof_show - write the action profile for each FSM transition to stdout
+ * @fsm_p - the FSM context
+ * @name - FSM name for the output
+ *
+ * Only transitions that have run are shown.  The first entry is skipped
+ * because it is shared with the FSM current state.
+ */
+void fsm_prof_show(fsm_trans_t *fsm_p, const char *name)
+{
+	fsm_trans_t *t_p;
+
+	printf("%s action profile\n%-16s %-12s %-16s %6s %10s %10s %8s\n", name,
+	       "state", "event", "next", "count", "avg usec", "max usec",
+	       "allocs");
+	for (t_p = fsm_p; t_p->currst_p != NULL; t_p++) {
+		if (t_p->event == E_BAD || 0 == t_p->prof.count)
+			continue;
+		printf("%-16s %-12s %-16s %6u %10lu %10lu %8u\n",
+		       t_p == fsm_p ? "(init)" : t_p->currst_p->name,
+		       evt_name[t_p->event], t_p->nextst_p->name,
+		       t_p->prof.count,
+		       t_p->prof.total_ns / t_p->prof.count / 1000,
+		       t_p->prof.max_ns / 1000,
+		       t_p->prof.allocs);
+	}
+}
+
+/**
+ * hist_add - record a transition in the history ring
+ * @from_p - state left
+ * @to_p - state entered
+ * @evt_id - event causing the transition
+ * @rec_p - updated with a copy of the record, for the observers
+ */
+static void hist_add(fsm_state_t *from_p, fsm_state_t *to_p, fsm_events_t evt_id,
+		     struct fsm_hist *rec_p)
+{
+	struct fsm_hist *h_p;
+
+	pthread_mutex_lock(&hist_mutex);
+	h_p = &fsm_hist[hist_cnt % FSM_HIST_LEN];
+	clock_now(&h_p->ts);
+	h_p->name = worker_get_name();
+	h_p->event = evt_id;
+	h_p->from_p = from_p;
+	h_p->to_p = to_p;
+	*rec_p = *h_p;
+	hist_cnt++;
+	pthread_mutex_unlock(&hist_mutex);
+}
+
+/**
+ * fsm_observe - call a function on each state change of an FSM
+ * @fsm_p - the FSM table, NULL for every FSM
+ * @fn - the observer
+ * @arg - passed to @fn
+ *
+ * For UIs, metrics and tests watching the FSMs without changing their
+ * actions or parsing the trace.  @fn is called on the worker thread
+ * running the transition, once the current state is updated and before
+ * the entry actions run (as DBG_CHANGE), so it must be quick and not
+ * run the FSM.  An internal transition is not a state change.  A clone
+ * (see fsm_clone) is a different table, observe it or use NULL.
+ * An observer must not call fsm_observe or fsm_unobserve, see
+ * observers_notify.
+ *
+ * Return: 0 on success, -1 if FSM_OBSERVERS are registered
+ */
+int fsm_observe(fsm_trans_t *fsm_p, fsm_observer_fn fn, void *arg)
+{
+	int i, ret = -1;
+
+	pthread_rwlock_wrlock(&observer_lock);
+	for (i = 0; i < FSM_OBSERVERS; i++) {
+		if (NULL == fsm_observers[i].fn) {
+			fsm_observers[i].fsm_p = fsm_p;
+			fsm_observers[i].arg = arg;
+			fsm_observers[i].fn = fn;
+			__atomic_add_fetch(&observer_cnt, 1, __ATOMIC_RELEASE);
+			ret = 0;
+			break;
+		}
+	}
+	pthread_rwlock_unlock(&observer_lock);
+	return(ret);
+}
+
+/**
+ * fsm_unobserve - remove an observer added with fsm_observe
+ * @fn - the observer
+ * @arg - its argument
+ *
+ * This waits for the calls already started on the worker threads, once
+ * it returns @fn is not running and @arg can be freed.
+ *
+ * Return: 0 on success, -1 if not registered
+ */
+int fsm_unobserve(fsm_observer_fn fn, void *arg)
+{
+	int i, ret = -1;
+
+	pthread_rwlock_wrlock(&observer_lock);
+	for (i = 0; i < FSM_OBSERVERS; i++) {
+		if (fsm_observers[i].fn == fn && fsm_observers[i].arg == arg) {
+			fsm_observers[i].fn = NULL;
+			__atomic_sub_fetch(&observer_cnt, 1, __ATOMIC_RELEASE);
+			ret = 0;
+			break;
+		}
+	}
+	pthread_rwlock_unlock(&observer_lock);
+	return(ret);
+}
+
+/**
+ * observers_notify - call the observers of a state change
+ * @fsm_p - the FSM
+ * @rec_p - the transition record
+ *
+ * Nothing is locked when no observer is registered.  The observers run
+ * with observer_lock read held so the workers call them at the same time
+ * and fsm_unobserve waits for them, an observer adding or removing an
+ * observer would deadlock.
+ */
+static void observers_notify(fsm_trans_t *fsm_p, const struct fsm_hist *rec_p)
+{
+	int i;
+
+	if (0 == __atomic_load_n(&observer_cnt, __ATOMIC_ACQUIRE))
+		return;
+
+	pthread_rwlock_rdlock(&observer_lock);
+	for (i = 0; i < FSM_OBSERVERS; i++) {
+		if (fsm_observers[i].fn &&
+		    (NULL == fsm_observers[i].fsm_p || fsm_observers[i].fsm_p == fsm_p))
+			fsm_observers[i].fn(rec_p, fsm_observers[i].arg);
+	}
+	pthread_rwlock_unlock(&observer_lock);
+}
+
+/**
+ * fsm_hist_get - copy the transition history, oldest first
+ * @hist - array to fill
+ * @max - size of @hist
+ *
+ * Return: number of records copied
+ */
+uint32_t fsm_hist_get(struct fsm_hist *hist, uint32_t max)
+{
+	uint32_t i, n, first;
+
+	pthread_mutex_lock(&hist_mutex);
+	n = hist_cnt < FSM_HIST_LEN ? hist_cnt : FSM_HIST_LEN;
+	if (n > max)
+		n = max;
+	first = hist_cnt - n;
+	for (i = 0; i < n; i++)
+		hist[i] = fsm_hist[(first + i) % FSM_HIST_LEN];
+	pthread_mutex_unlock(&hist_mutex);
+
+	return(n);
+}
+
+/**
+ * fsm_hist_count - total number of transitions run by all FSMs
+ */
+uint32_t fsm_hist_count(void)
+{
+	uint32_t n;
+
+	pthread_mutex_lock(&hist_mutex);
+	n = hist_cnt;
+	pthread_mutex_unlock(&hist_mutex);
+	return(n);
+}
+
+/**
+ * fsm_hist_dump - write the last transitions to a file descriptor
+ * @fd - open file descriptor
+ * @max - max number of transitions to write
+ *
+ * This is for post-mortem use from a signal handler so it does not
+ * take the history mutex and only uses async-signal-safe functions (see
+ * sig_cat.)  The output is best effort.
+ */
+void fsm_hist_dump(int fd, uint32_t max)
+{
+	struct fsm_hist *h_p;
+	uint32_t i, n;
+	int64_t ms;
+	char buf[120];
+	size_t len;
+
+	n = hist_cnt < FSM_HIST_LEN ? hist_cnt : FSM_HIST_LEN;
+	if (n > max)
+		n = max;
+	for (i = hist_cnt - n; i != hist_cnt; i++) {
+		h_p = &fsm_hist[i % FSM_HIST_LEN];
+		ms = (h_p->ts.tv_sec - ts_start.tv_sec) * 1000 +
+			(h_p->ts.tv_nsec - ts_start.tv_nsec) / 1000000;
+		if (ms < 0)
+			ms = 0;
+		len = sig_utoa(buf, sizeof(buf), 0, ms / 1000, 1);
+		len = sig_cat(buf, sizeof(buf), len, ".");
+		len = sig_utoa(buf, sizeof(buf), len, ms % 1000, 3);
+		len = sig_cat(buf, sizeof(buf), len, " ");
+		len = sig_cat(buf, sizeof(buf), len, h_p->name ? h_p->name : "?");
+		len = sig_cat(buf, sizeof(buf), len, " evt=");
+		len = sig_cat(buf, sizeof(buf), len, evt_name[h_p->event]);
+		len = sig_cat(buf, sizeof(buf), len, " ");
+		len = sig_cat(buf, sizeof(buf), len, h_p->from_p->name);
+		len = sig_cat(buf, sizeof(buf), len, " to ");
+		len = sig_cat(buf, sizeof(buf), len, h_p->to_p->name);
+		if (len == sizeof(buf))
+			len--;
+		buf[len++] = '\n';
+		write(fd, buf, len);
+	}
+}
+
+/* max superstate nesting depth */
+#define FSM_MAX_DEPTH 8
+
+/**
+ * trans_lca - the superstate containing both sides of a transition
+ * @from_p - the state left
+ * @to_p - the state entered
+ *
+ * This is the innermost superstate strictly containing both states, so a
+ * self transition (or one to a superstate) exits and re-enters the state.
+ *
+ * Return: the common superstate, NULL at the top level
+ */
+static fsm_state_t *trans_lca(fsm_state_t *from_p, fsm_state_t *to_p)
+{
+	fsm_state_t *a_p, *b_p;
+
+	for (a_p = from_p->parent; a_p != NULL; a_p = a_p->parent) {
+		for (b_p = to_p->parent; b_p != NULL; b_p = b_p->parent) {
+			if (a_p == b_p)
+				return(a_p);
+		}
+	}
+	return(NULL);
+}
+
+/**
+ * record_history - remember the leaf state in each superstate being left
+ * @fsm_p - the FSM context, holding the history
+ * @from_p - the state left
+ * @lca_p - the common superstate, it is not left
+ *
+ * The history is allocated when a superstate is first left, out of memory
+ * it is not kept.  Only the worker running the FSM reads and writes it,
+ * it is not part of the transitions changed at runtime.
+ */
+static void record_history(fsm_trans_t *fsm_p, fsm_state_t *from_p, fsm_state_t *lca_p)
+{
+	if (from_p->parent == lca_p)
+		return;
+	if (NULL == fsm_p->resume_p)
+		fsm_p->resume_p = fsm_calloc(1, sizeof(*fsm_p->resume_p));
+	if (fsm_p->resume_p)
+		resume_save(fsm_p->resume_p, from_p, lca_p);
+}
+
+/**
+ * run_exits - run exit actions from a state out to a superstate
+ * @fsm_p - the FSM context
+ * @from_p - the state left
+ * @lca_p - the common superstate, its exit action is not run
+ * @cost_p - cost of the actions, see run_action
+ *
+ * The timeout of each state left is cancelled before its exit action.
+ */
+static void run_exits(fsm_trans_t *fsm_p, fsm_state_t *from_p, fsm_state_t *lca_p,
+		      struct action_cost *cost_p)
+{
+	fsm_state_t *st_p;
+
+	record_history(fsm_p, from_p, lca_p);
+	for (st_p = from_p; st_p != lca_p && st_p != NULL; st_p = st_p->parent) {
+		if (st_p->timeout.ms_p)
+			cancel_timer(st_p->timeout.timerid);
+		if (st_p->exit_action)
+			run_action(st_p->exit_action, st_p, cost_p);
+	}
+}
+
+/**
+ * run_entries - run entry actions from a superstate in to a state
+ * @to_p - the state entered
+ * @lca_p - the common superstate, its entry action is not run
+ * @self_p - the worker running fsm_run, may be NULL
+ * @cost_p - cost of the actions, see run_action
+ *
+ * The timeout of each state entered is started before its entry action,
+ * so the action and the events it sends see the timer running.  The
+ * timeout event is only for this FSM, it is routed to the worker queue
+ * (see route_timer) rather than broadcast.
+ */
+static void run_entries(fsm_state_t *to_p, fsm_state_t *lca_p, worker_t *self_p,
+			struct action_cost *cost_p)
+{
+	fsm_state_t *path[FSM_MAX_DEPTH];
+	fsm_state_t *st_p;
+	int n = 0;
+
+	for (st_p = to_p; st_p != lca_p && st_p != NULL && n < FSM_MAX_DEPTH; st_p = st_p->parent)
+		path[n++] = st_p;
+	while (n--) {
+		if (path[n]->timeout.ms_p) {
+			if (self_p)
+				route_timer(path[n]->timeout.timerid, self_p->evtq_p);
+			start_timer(path[n]->timeout.timerid, *path[n]->timeout.ms_p,
+				    path[n]->timeout.evt_id, false);
+		}
+		if (path[n]->entry_action)
+			run_action(path[n]->entry_action, path[n], cost_p);
+	}
+}
+
+/**
+ * fsm_run - crank the FSM once for input event
+ * @fsm_p - the FSM context
+ * @evt_id - the event id
+ * @out_p - optional outcome, filled in before returning
+ *
+ * - find the enabled transition, running the guards (see select_trans)
+ * - return if they all fail (false)
+ * - for an internal transition only check the postcondition
+ * - otherwise 
+ * -  call exit action of the current state, then of each superstate
+ *    being left (innermost first)
+ * -  move to next state
+ * -  call entry action of each superstate being entered (outermost
+ *    first), then of the new current state
+ * -  check the transition postcondition
+ *
+ * This order is guaranteed: an entry action sees the new current state
+ * and fsm_phase_get returns the step being run.
+ *
+ * An action or guard calling fsm_run for its own FSM would start a transition
+ * before the current one completes.  The event is instead posted to the
+ * worker internal queue, see fsm_post, with the payload of the event
+ * being run.
+ *
+ * A transition into a state whose entry action calls worker_exit
+ * (e.g. S:DONE) does not return.
+ *
+ * Return: see enum fsm_result, also set in @out_p
+ */
+enum fsm_result fsm_run(fsm_trans_t* fsm_p, fsm_events_t evt_id, struct fsm_outcome *out_p)
+{
+	fsm_trans_t *t_p = NULL;
+	fsm_trans_t trans;
+	fsm_state_t *nextst_p;
+	struct action_cost cost = {0, 0};
+	struct timespec start, end;
+	struct fsm_hist rec;
+	worker_t *self_p = worker_self();
+	fsm_state_t *from_p = fsm_p->currst_p;
+	fsm_state_t *lca_p;
+	enum fsm_result ret = FSM_UNHANDLED;
+	bool guarded;
+
+	if (self_p && self_p->fsm_p == fsm_p && self_p->phase != PH_IDLE) {
+		dbg_verbose("reentrant fsm_run, event queued");
+		evtq_enqueue_data(self_p->intq_p, evt_id, self_p->evt_data);
+		ret = FSM_QUEUED;
+		goto out;
+	}
+
+	t_p = select_trans(fsm_p, evt_id, &guarded, self_p, &trans);
+	nextst_p = t_p ? trans_next(fsm_p->resume_p, &trans, from_p) : NULL;
+	/* a transition leaving the state is traced with its duration below */
+	if (!nextst_p || guarded || TRANS_INTERNAL == trans.kind)
+		dbg_trans(fsm_p, nextst_p, evt_id, guarded, 0);
+
+	if (NULL == nextst_p) {
+		check_expect(fsm_p, evt_id);
+		dbg_change(self_p, fsm_p, from_p, evt_id);
+	}
+	
+	if (nextst_p) {
+		/* every matching transition was refused by its guard */
+		if (guarded)
+		{
+			dbg_verbose("Guard FAILED");
+			dbg_change(self_p, fsm_p, from_p, evt_id);
+			ret = FSM_GUARDED;
+		} else if (TRANS_INTERNAL == trans.kind) {
+			/* the current state is not left, only check the
+			 * postcondition
+			 */
+			dbg_change(self_p, fsm_p, from_p, evt_id);
+			set_phase(self_p, PH_POST);
+			check_post(fsm_p, &trans, evt_id);
+			set_phase(self_p, PH_IDLE);
+			ret = FSM_TRANSITION;
+		} else {
+			/* before transition to next state, run curr state
+			 * exit action
+			 */
+			clock_gettime(CLOCK_MONOTONIC, &start);
+			set_phase(self_p, PH_EXIT);
+			lca_p = trans_lca(fsm_p->currst_p, nextst_p);
+			run_exits(fsm_p, fsm_p->currst_p, lca_p, &cost);
+
+			/* update currst to nextst */
+			set_phase(self_p, PH_TRANS);
+			hist_add(fsm_p->currst_p, nextst_p, evt_id, &rec);
+			clock_gettime(CLOCK_MONOTONIC, &end);
+			dbg_trans(fsm_p, nextst_p, evt_id, false,
+				  ts_diff_ns(&start, &end));
+			fsm_p->currst_p = nextst_p;
+			dbg_change(self_p, fsm_p, from_p, evt_id);
+			observers_notify(fsm_p, &rec);
+
+			/* run currst entry action after state transition */
+			set_phase(self_p, PH_ENTRY);
+			run_entries(fsm_p->currst_p, lca_p, self_p, &cost);
+			if (fsm_profile)
+				prof_update(t_p, &cost);
+
+			set_phase(self_p, PH_POST);
+			check_post(fsm_p, &trans, evt_id);
+			set_phase(self_p, PH_IDLE);
+
+			dbg_verbose("Guard PASSED");
+			ret = FSM_TRANSITION;
+		}
+	}
+
+out:
+	if (out_p) {
+		out_p->result = ret;
+		out_p->evt_id = evt_id;
+		out_p->from_p = from_p;
+		out_p->to_p = fsm_p->currst_p;
+		out_p->trans_p = t_p;
+	}
+	return (ret);
+}
+
+
+/**
+ * fsm_shadow_run - crank a shadow FSM once for input event
+ * @fsm_p - the shadow FSM context
+ * @evt_id - the event id
+ *
+ * A shadow FSM is a modified definition run alongside the live FSM.  It
+ * only tracks the next state: no guard, entry or exit action is run so the
+ * shadow cannot act (set timers, broadcast events.)  The superstates it
+ * leaves are recorded in its own history, as by fsm_run.
+ *
+ * Return: the shadow FSM current state after the event
+ */
+fsm_state_t *fsm_shadow_run(fsm_trans_t *fsm_p, fsm_events_t evt_id)
+{
+	fsm_trans_t *t_p, trans;
+	fsm_state_t *nextst_p;
+
+	pthread_rwlock_rdlock(&fsm_lock);
+	if (NULL != (t_p = match_trans(fsm_p, evt_id)))
+		trans = *t_p;
+	pthread_rwlock_unlock(&fsm_lock);
+
+	if (NULL == t_p || TRANS_INTERNAL == trans.kind)
+		return(fsm_p->currst_p);
+	nextst_p = trans_next(fsm_p->resume_p, &trans, fsm_p->currst_p);
+	if (nextst_p) {
+		record_history(fsm_p, fsm_p->currst_p,
+			       trans_lca(fsm_p->currst_p, nextst_p));
+		fsm_p->currst_p = nextst_p;
+	}
+	return(fsm_p->currst_p);
+}
+
+/**
+ * find_trans - find the transition entry for a state, event and priority
+ * @fsm_p - the FSM context
+ * @currst_p - the state
+ * @evt_id - the event id
+ * @prio - the transition priority
+ *
+ * The first entry is skipped because its currst_p is the FSM current state.
+ *
+ * Return: pointer to the transition or NULL if no match
+ */
+static fsm_trans_t *find_trans(fsm_trans_t *fsm_p, fsm_state_t *currst_p, fsm_events_t evt_id,
+			       int prio)
+{
+	fsm_trans_t *t_p;
+
+	for (t_p = fsm_p + 1; t_p->currst_p != NULL; t_p++) {
+		if (t_p->currst_p == currst_p && t_p->event == evt_id && t_p->prio == prio)
+			return(t_p);
+	}
+	return(NULL);
+}
+
+/**
+ * known_state - check a state is used somewhere in the FSM table
+ * @fsm_p - the FSM context
+ * @st_p - the state
+ *
+ * Return: true if @st_p is a current or next state of any transition
+ */
+static bool known_state(fsm_trans_t *fsm_p, fsm_state_t *st_p)
+{
+	fsm_trans_t *t_p;
+
+	for (t_p = fsm_p; t_p->currst_p != NULL; t_p++) {
+		if (t_p->event == E_BAD)
+			continue;
+		if (t_p->currst_p == st_p || t_p->nextst_p == st_p)
+			return(true);
+	}
+	return(false);
+}
+
+/**
+ * trans_valid - revalidate a transition before changing the table
+ * @fsm_p - the FSM context
+ * @currst_p - the current state
+ * @evt_id - the event id
+ * @nextst_p - the next state
+ *
+ * Both states must be non-NULL and already be part of the FSM, and the
+ * event must be a valid event.
+ *
+ * Return: true if the transition can be applied
+ */
+static bool trans_valid(fsm_trans_t *fsm_p, fsm_state_t *currst_p,
+			fsm_events_t evt_id, fsm_state_t *nextst_p)
+{
+	char msg[80];
+
+	if (!currst_p || !nextst_p || evt_id <= E_BAD || evt_id >= E_LAST) {
+		dbg("invalid transition");
+		return(false);
+	}
+	if (!known_state(fsm_p, currst_p) || !known_state(fsm_p, nextst_p)) {
+		sprintf(msg, "%s to %s: unknown state", currst_p->name, nextst_p->name);
+		dbg(msg);
+		return(false);
+	}
+	return(true);
+}
+
+/**
+ * trans_commit - check a changed FSM table, else undo the change
+ * @fsm_p - the FSM context
+ * @t_p - the changed entry
+ * @old_p - the entry before the change
+ *
+ * Called with fsm_lock held for writing.  trans_valid only checks the
+ * changed transition, the whole table is checked as at startup (see
+ * fsm_validate) so a change leaving a state unreachable or a dead end is
+ * refused.
+ *
+ * Return: 0 if the table is valid, -1 if the change was undone
+ */
+static int trans_commit(fsm_trans_t *fsm_p, fsm_trans_t *t_p, fsm_trans_t *old_p)
+{
+	int ret = 0;
+
+	if (fsm_validate(fsm_p, "runtime change")) {
+		*t_p = *old_p;
+		ret = -1;
+	}
+	index_update(fsm_p);
+	return(ret);
+}
+
+/**
+ * fsm_trans_add - add a transition to an FSM at runtime
+ * @fsm_p - the FSM context
+ * @currst_p - the current state
+ * @evt_id - the event id
+ * @prio - the priority, see fsm_trans
+ * @guard - optional guard
+ * @nextst_p - the next state
+ *
+ * The transition is written to a free (E_BAD) slot, the FSM table
+ * must have been defined with one (FSM_TRANS_SPARE) or have had a
+ * transition removed.  Another transition for the state and event is a
+ * variant when its priority differs, e.g. a guarded one tried first.
+ * The changed table must pass fsm_validate, see trans_commit.
+ *
+ * Return: 0 on success, -1 if invalid, a duplicate, no free slot or the
+ * table is no longer valid
+ */
+int fsm_trans_add(fsm_trans_t *fsm_p, fsm_state_t *currst_p, fsm_events_t evt_id,
+		  int prio, constraint guard, fsm_state_t *nextst_p)
+{
+	fsm_trans_t *t_p, old;
+	int ret = -1;
+
+	pthread_rwlock_wrlock(&fsm_lock);
+	if (trans_valid(fsm_p, currst_p, evt_id, nextst_p) &&
+	    NULL == find_trans(fsm_p, currst_p, evt_id, prio)) {
+		for (t_p = fsm_p + 1; t_p->currst_p != NULL; t_p++) {
+			if (t_p->event == E_BAD) {
+				old = *t_p;
+				t_p->guard = guard;
+				t_p->post = NULL;
+				memset(&t_p->prof, 0, sizeof(t_p->prof));
+				t_p->kind = TRANS_EXTERNAL;
+				t_p->prio = prio;
+				t_p->nextst_p = nextst_p;
+				t_p->currst_p = currst_p;
+				t_p->event = evt_id;
+				ret = trans_commit(fsm_p, t_p, &old);
+				break;
+			}
+		}
+	}
+	pthread_rwlock_unlock(&fsm_lock);
+	return(ret);
+}
+
+/**
+ * fsm_state_find - lookup a state of an FSM by name
+ * @fsm_p - the FSM context
+ * @name - the state name, e.g. "S:GREEN"
+ *
+ * The first entry is searched too, its current state is the initial state
+ * until the FSM starts.
+ *
+ * Return: the first state with @name used by a transition, NULL if none
+ */
+fsm_state_t *fsm_state_find(fsm_trans_t *fsm_p, const char *name)
+{
+	fsm_trans_t *t_p;
+	fsm_state_t *st_p = NULL;
+
+	pthread_rwlock_rdlock(&fsm_lock);
+	for (t_p = fsm_p; t_p->currst_p != NULL && NULL == st_p; t_p++) {
+		if (t_p->event == E_BAD)
+			continue;
+		if (0 == strcmp(t_p->currst_p->name, name))
+			st_p = t_p->currst_p;
+		else if (0 == strcmp(t_p->nextst_p->name, name))
+			st_p = t_p->nextst_p;
+	}
+	pthread_rwlock_unlock(&fsm_lock);
+	return(st_p);
+}
+
+/**
+ * fsm_trans_remove - remove a transition from an FSM at runtime
+ * @fsm_p - the FSM context
+ * @currst_p - the current state
+ * @evt_id - the event id
+ * @prio - the transition priority
+ *
+ * The entry is marked free (E_BAD) rather than shifting the table so
+ * it can be reused by fsm_trans_add.  The changed table must pass
+ * fsm_validate, see trans_commit.
+ *
+ * Return: 0 on success, -1 if the transition does not exist or the table
+ * is no longer valid
+ */
+int fsm_trans_remove(fsm_trans_t *fsm_p, fsm_state_t *currst_p, fsm_events_t evt_id,
+		     int prio)
+{
+	fsm_trans_t *t_p, old;
+	int ret = -1;
+
+	pthread_rwlock_wrlock(&fsm_lock);
+	if ((t_p = find_trans(fsm_p, currst_p, evt_id, prio))) {
+		old = *t_p;
+		t_p->event = E_BAD;
+		ret = trans_commit(fsm_p, t_p, &old);
+	}
+	pthread_rwlock_unlock(&fsm_lock);
+	return(ret);
+}
+
+/**
+ * fsm_trans_replace - replace the guard and next state of a transition
+ * @fsm_p - the FSM context
+ * @currst_p - the current state
+ * @evt_id - the event id
+ * @prio - the transition priority
+ * @guard - optional guard
+ * @nextst_p - the new next state
+ *
+ * The action profile of the transition starts again, it measures the
+ * new next state.  The changed table must pass fsm_validate, see
+ * trans_commit.
+ *
+ * Return: 0 on success, -1 if invalid, the transition does not exist or
+ * the table is no longer valid
+ */
+int fsm_trans_replace(fsm_trans_t *fsm_p, fsm_state_t *currst_p, fsm_events_t evt_id,
+		      int prio, constraint guard, fsm_state_t *nextst_p)
+{
+	fsm_trans_t *t_p, old;
+	int ret = -1;
+
+	pthread_rwlock_wrlock(&fsm_lock);
+	if (trans_valid(fsm_p, currst_p, evt_id, nextst_p) &&
+	    (t_p = find_trans(fsm_p, currst_p, evt_id, prio))) {
+		old = *t_p;
+		t_p->guard = guard;
+		t_p->nextst_p = nextst_p;
+		memset(&t_p->prof, 0, sizeof(t_p->prof));
+		ret = trans_commit(fsm_p, t_p, &old);
+	}
+	pthread_rwlock_unlock(&fsm_lock);
+	return(ret);
+}
+
+/**
+ * state_handles - check if a state or its superstates handle an event
+ * @fsm_p - the FSM context
+ * @st_p - the state
+ * @event - the event, E_BAD for any event
+ */
+static bool state_handles(fsm_trans_t *fsm_p, fsm_state_t *st_p, fsm_events_t event)
+{
+	fsm_trans_t *t_p;
+
+	for (; st_p != NULL; st_p = st_p->parent) {
+		for (t_p = fsm_p + 1; t_p->currst_p != NULL; t_p++) {
+			if (t_p->currst_p == st_p && t_p->event != E_BAD &&
+			    (t_p->event == event || event == E_BAD))
+				return(true);
+		}
+	}
+	return(false);
+}
+
+/**
+ * explain_add - add a state to the list of states if not already on it
+ * @states - array of states, sized for every state, see explain_states
+ * @n - number of states in @states
+ * @st_p - state to add
+ *
+ * Return: the updated number of states
+ */
+static int explain_add(fsm_state_t **states, int n, fsm_state_t *st_p)
+{
+	int i;
+
+	for (i = 0; i < n; i++) {
+		if (states[i] == st_p)
+			return(n);
+	}
+	states[n++] = st_p;
+	return(n);
+}
+
+/**
+ * explain_states - list the distinct states of an FSM table
+ * @fsm_p - the FSM context
+ * @first_p - the state listed first, e.g. the init state
+ * @n_p - updated with the number of states
+ *
+ * The list is sized for @first_p and both states of every entry, so no
+ * state is left out whatever the table length.
+ *
+ * Return: the list, to be freed, NULL if out of memory
+ */
+static fsm_state_t **explain_states(fsm_trans_t *fsm_p, fsm_state_t *first_p, int *n_p)
+{
+	fsm_state_t **states;
+	fsm_trans_t *t_p;
+	uint32_t len;
+	int n = 0;
+
+	for (len = 0; fsm_p[len].currst_p != NULL; len++)
+		;
+	if (NULL == (states = fsm_calloc(2 * len + 1, sizeof(*states))))
+		return(NULL);
+
+	n = explain_add(states, n, first_p);
+	for (t_p = fsm_p + 1; t_p->currst_p != NULL; t_p++) {
+		if (t_p->event == E_BAD)
+			continue;
+		n = explain_add(states, n, t_p->currst_p);
+		n = explain_add(states, n, t_p->nextst_p);
+	}
+	*n_p = n;
+	return(states);
+}
+
+/**
+ * fsm_explain - write a readable narrative of an FSM to stdout
+ * @fsm_p - the FSM context
+ * @initst_p - the FSM init state, the first table entry tracks the
+ *   current state so the init state must be supplied by the caller
+ * @currst_p - the FSM current state, e.g. worker_state for a running
+ *   worker because the table entry is only read by the FSM thread
+ * @name - FSM name for the output
+ *
+ * The narrative is generated from the transition table: for each state,
+ * its actions, how it reacts to each event and which events it ignores by
+ * design (the @expect list.)  Useful for onboarding and reviews.
+ */
+void fsm_explain(fsm_trans_t *fsm_p, fsm_state_t *initst_p,
+		 fsm_state_t *currst_p, const char *name)
+{
+	fsm_state_t **states;
+	const fsm_events_t *e_p;
+	fsm_trans_t *t_p;
+	int i, n = 0, nout;
+
+	pthread_rwlock_rdlock(&fsm_lock);
+	if (NULL == (states = explain_states(fsm_p, initst_p, &n))) {
+		pthread_rwlock_unlock(&fsm_lock);
+		printf("%s: out of memory\n", name);
+		return;
+	}
+
+	printf("%s starts in %s, currently in %s, and has %d states.\n",
+	       name, initst_p->name, currst_p->name, n);
+
+	for (i = 0; i < n; i++) {
+		printf("%s:\n", states[i]->name);
+		if (states[i]->history) {
+			printf("  resumes the %s state last active in %s",
+			       (HISTORY_DEEP == states[i]->history) ? "leaf" : "sub",
+			       states[i]->parent->name);
+			if (states[i]->default_p)
+				printf(", else %s", states[i]->default_p->name);
+			printf("\n");
+			continue;
+		}
+		if (states[i]->parent)
+			printf("  is in %s and handles its events too\n",
+			       states[i]->parent->name);
+		printf("  on entry %s, on exit %s\n",
+		       states[i]->entry_action ? "runs an action" : "does nothing",
+		       states[i]->exit_action ? "runs an action" : "does nothing");
+		if (states[i]->timeout.ms_p)
+			printf("  times out after %u msecs with %s\n",
+			       *states[i]->timeout.ms_p, evt_name[states[i]->timeout.evt_id]);
+
+		nout = 0;
+		for (t_p = fsm_p; t_p->currst_p != NULL; t_p++) {
+			fsm_state_t *currst_p = (t_p == fsm_p) ? initst_p : t_p->currst_p;
+
+			if (currst_p != states[i] || t_p->event == E_BAD)
+				continue;
+			if (TRANS_INTERNAL == t_p->kind)
+				printf("  handles %s without leaving the state%s\n",
+				       evt_name[t_p->event],
+				       t_p->guard ? " if its guard passes" : "");
+			else
+				printf("  on %s goes to %s%s\n", evt_name[t_p->event],
+				       t_p->nextst_p->name,
+				       t_p->guard ? " if its guard passes" : "");
+			nout++;
+		}
+		if (0 == nout && !state_handles(fsm_p, states[i], E_BAD))
+			printf("  is final, no event leaves it\n");
+
+		if (states[i]->expect) {
+			printf("  ignores");
+			for (e_p = states[i]->expect; *e_p != E_BAD; e_p++)
+				printf(" %s", evt_name[*e_p]);
+			printf(", any other event is a protocol violation\n");
+		} else if (nout) {
+			printf("  discards any other event\n");
+		}
+	}
+	pthread_rwlock_unlock(&fsm_lock);
+	free(states);
+}
+
+/**
+ * in_state - check if a state is, or is a substate of, another state
+ * @st_p - the state
+ * @super_p - the possible superstate
+ */
+static bool in_state(fsm_state_t *st_p, fsm_state_t *super_p)
+{
+	for (; st_p != NULL; st_p = st_p->parent) {
+		if (st_p == super_p)
+			return(true);
+	}
+	return(false);
+}
+
+/**
+ * fsm_validate - check an FSM table for definition errors
+ * @fsm_p - the FSM context, before the FSM is started or on a runtime
+ *   change (see trans_commit)
+ * @name - FSM name for the output
+ *
+ * Each problem is written to stdout:
+ * - two transitions for a state and event with the same priority, unless
+ *   both are guarded (the guards must then exclude each other.)  With
+ *   fsm_strict, guarded transitions also need distinct priorities
+ * - a state that cannot be reached from the init transition
+ * - a transition to a state no event leaves, except on E_DONE to the
+ *   final state
+ * - a history state in an FSM with more than FSM_MAX_SUPER superstates,
+ *   see record_history
+ *
+ * A substate transition overriding the same event on its superstate is
+ * not a conflict, the substate always wins (see match_from.)
+ *
+ * Return: the number of problems, 0 if the table is valid
+ */
+int fsm_validate(fsm_trans_t *fsm_p, const char *name)
+{
+	fsm_state_t **states;
+	bool *reached;
+	fsm_state_t *supers[FSM_MAX_SUPER + 1], *st_p;
+	fsm_trans_t *t_p, *u_p;
+	int i, j, n = 0, nsuper, errors = 0;
+	bool more, hist;
+
+	states = explain_states(fsm_p, fsm_p->nextst_p, &n);
+	reached = fsm_calloc(n ? n : 1, sizeof(*reached));
+	if (NULL == states || NULL == reached) {
+		printf("%s: out of memory to validate the table\n", name);
+		free(states);
+		free(reached);
+		return(1);
+	}
+
+	for (t_p = fsm_p + 1; t_p->currst_p != NULL; t_p++) {
+		if (t_p->event == E_BAD)
+			continue;
+
+		for (u_p = fsm_p + 1; u_p != t_p; u_p++) {
+			if (u_p->currst_p == t_p->currst_p && u_p->event == t_p->event &&
+			    u_p->prio == t_p->prio &&
+			    (fsm_strict || !u_p->guard || !t_p->guard)) {
+				printf("%s: %s on %s: conflicting transitions with priority %d\n",
+				       name, t_p->currst_p->name, evt_name[t_p->event],
+				       t_p->prio);
+				errors++;
+				break;
+			}
+		}
+
+		if (t_p->event != E_DONE && HISTORY_NONE == t_p->nextst_p->history &&
+		    !state_handles(fsm_p, t_p->nextst_p, E_BAD)) {
+			printf("%s: %s on %s: %s is a dead end\n", name,
+			       t_p->currst_p->name, evt_name[t_p->event],
+			       t_p->nextst_p->name);
+			errors++;
+		}
+	}
+
+	/* a transition is taken from a reached state or its superstates, the
+	 * current state (the init state until the FSM starts) is reached
+	 */
+	for (i = 0; i < n; i++)
+		reached[i] = (states[i] == fsm_p->nextst_p || states[i] == fsm_p->currst_p);
+	do {
+		more = false;
+		for (t_p = fsm_p + 1; t_p->currst_p != NULL; t_p++) {
+			if (t_p->event == E_BAD)
+				continue;
+			for (i = 0; i < n; i++) {
+				if (reached[i] && in_state(states[i], t_p->currst_p))
+					break;
+			}
+			if (i == n)
+				continue;
+			for (j = 0; j < n; j++) {
+				if (states[j] == t_p->nextst_p && !reached[j])
+					reached[j] = more = true;
+			}
+		}
+	} while (more);
+
+	for (i = 0; i < n; i++) {
+		if (reached[i] || HISTORY_NONE != states[i]->history)
+			continue;
+		/* a superstate is reached through its substates */
+		for (j = 0; j < n; j++) {
+			if (states[j]->parent == states[i])
+				break;
+		}
+		if (j < n)
+			continue;
+		printf("%s: %s is unreachable from %s\n", name, states[i]->name,
+		       fsm_p->nextst_p->name);
+		errors++;
+	}
+
+	/* the history is kept for FSM_MAX_SUPER superstates */
+	for (i = 0, nsuper = 0, hist = false; i < n; i++) {
+		hist |= (HISTORY_NONE != states[i]->history);
+		for (st_p = states[i]->parent; st_p != NULL; st_p = st_p->parent) {
+			for (j = 0; j < nsuper && supers[j] != st_p; j++)
+				;
+			if (j == nsuper && nsuper <= FSM_MAX_SUPER)
+				supers[nsuper++] = st_p;
+		}
+	}
+	if (hist && nsuper > FSM_MAX_SUPER) {
+		printf("%s: more than %d superstates to keep the history of\n",
+		       name, FSM_MAX_SUPER);
+		errors++;
+	}
+
+	free(states);
+	free(reached);
+	return(errors);
+}
+
+/* weight of a transition not yet covered by fsm_walk, covered ones are 1 */
+#define WALK_UNCOVERED_WEIGHT 8
+
+/**
+ * walk_takes - check the walk can take a transition from a state
+ * @fsm_p - the FSM context
+ * @res_p - the walk history
+ * @currst_p - the walk current state
+ * @t_p - the transition
+ *
+ * Guards are not run, so any candidate for the event (see match_all) can
+ * be taken up to the first unguarded one, which always wins over the
+ * lower priority ones.  A transition into a final state is not taken.
+ * Called with fsm_lock held.
+ */
+static bool walk_takes(fsm_trans_t *fsm_p, struct fsm_resume *res_p,
+		       fsm_state_t *currst_p, fsm_trans_t *t_p)
+{
+	fsm_trans_t *cands[FSM_MAX_CANDIDATES];
+	int i, n;
+
+	if (t_p->event == E_BAD || NULL == trans_next(res_p, t_p, currst_p) ||
+	    !state_handles(fsm_p, t_p->nextst_p, E_BAD))
+		return(false);
+	n = match_all(fsm_p, currst_p, t_p->event, cands);
+	for (i = 0; i < n; i++) {
+		if (cands[i] == t_p)
+			return(true);
+		if (NULL == cands[i]->guard)
+			break;
+	}
+	return(false);
+}
+
+/**
+ * fsm_walk - write a random walk over the FSM transition graph as a script
+ * @fsm_p - the FSM context
+ * @name - name of the worker running the FSM, each event is sent to it
+ * @steps - number of events in the walk
+ * @nap - nap ticks after each event so the FSM can run it
+ *
+ * The walk follows the transition table from the init state without
+ * running any guard or action.  At each step an outgoing transition is
+ * picked at random, weighted toward transitions not yet covered, so a short
+ * walk exercises most of the table rather than sending events the FSM
+ * ignores.  Transitions into a final state (e.g. S:DONE) are not taken and
+ * the walk runs past @steps until it reaches a state handling E_DONE, so
+ * the caller can end the script with the x command.
+ *
+ * The output is event commands for evt_script (the -s argument) on
+ * stdout, sent to @name only (eN:name) so the walks of several FSMs do not
+ * drive each other.  A guarded transition may be refused when the script
+ * is run.
+ */
+void fsm_walk(fsm_trans_t *fsm_p, const char *name, uint32_t steps,
+	      uint32_t nap)
+{
+	fsm_trans_t *t_p;
+	fsm_state_t *currst_p, *nextst_p;
+	struct fsm_resume res;
+	uint32_t *visits;
+	uint32_t ntrans = 0, nused = 0, covered = 0, total, pick, step;
+	int i;
+
+	/* free (E_BAD) entries are not transitions */
+	pthread_rwlock_rdlock(&fsm_lock);
+	for (t_p = fsm_p; t_p->currst_p != NULL; t_p++) {
+		ntrans++;
+		if (t_p->event != E_BAD)
+			nused++;
+	}
+	if (NULL == (visits = fsm_calloc(ntrans, sizeof(*visits)))) {
+		pthread_rwlock_unlock(&fsm_lock);
+		printf("# %s: out of memory\n", name);
+		return;
+	}
+	/* the walk does not run the FSM, it keeps its own history */
+	memset(&res, 0, sizeof(res));
+
+	printf("# random walk over %s, %u steps\n", name, steps);
+
+	/* the first entry is the init transition run by the g command */
+	currst_p = fsm_p->nextst_p;
+	visits[0]++;
+	covered++;
+
+	for (step = 0; step < steps * 2; step++) {
+		if (step >= steps && state_handles(fsm_p, currst_p, E_DONE))
+			break;
+
+		/* sum weights of the transitions leaving the current state */
+		total = 0;
+		for (i = 1; i < ntrans; i++) {
+			t_p = &fsm_p[i];
+			if (!walk_takes(fsm_p, &res, currst_p, t_p))
+				continue;
+			total += visits[i] ? 1 : WALK_UNCOVERED_WEIGHT;
+		}
+		if (0 == total)
+			break;
+
+		pick = random() % total;
+		for (i = 1; i < ntrans; i++) {
+			t_p = &fsm_p[i];
+			if (!walk_takes(fsm_p, &res, currst_p, t_p))
+				continue;
+			if (pick < (visits[i] ? 1 : WALK_UNCOVERED_WEIGHT))
+				break;
+			pick -= visits[i] ? 1 : WALK_UNCOVERED_WEIGHT;
+		}
+
+		if (0 == visits[i]++)
+			covered++;
+		nextst_p = trans_next(&res, t_p, currst_p);
+		printf("# %s -> %s\ne%d:%s n%u\n", currst_p->name, nextst_p->name,
+		       t_p->event, name, nap);
+		resume_save(&res, currst_p, trans_lca(currst_p, nextst_p));
+		currst_p = nextst_p;
+	}
+
+	pthread_rwlock_unlock(&fsm_lock);
+	printf("# %s covered %u of %u transitions\n", name, covered, nused);
+	free(visits);
+}
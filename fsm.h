@@ -18,16 +18,65 @@
  */
 typedef void (*action)(void *arg);
 
+/**
+ * enum fsm_history - history pseudo-state kind
+ * @HISTORY_NONE: a normal state
+ * @HISTORY_SHALLOW: resume the substate of @parent that was last active
+ * @HISTORY_DEEP: resume the leaf state of @parent that was last active
+ *
+ * The two only differ when superstates are nested: a shallow history
+ * resumes the direct substate of @parent, which must then be a leaf.
+ */
+enum fsm_history {
+	HISTORY_NONE,
+	HISTORY_SHALLOW,
+	HISTORY_DEEP,
+};
+
+/**
+ * struct fsm_timeout - state timeout, see fsm_state
+ * @ms_p: timeout in msecs, NULL for none.  It is read each time the
+ *   state is entered so it can be changed at runtime (e.g. scaled by
+ *   the tick)
+ * @evt_id: event sent when the timeout expires
+ * @timerid: timer running the timeout, created with create_timer
+ */
+struct fsm_timeout {
+	const uint32_t *ms_p;
+	fsm_events_t evt_id;
+	uint32_t timerid;
+};
+
 /**
  * typedef fsm_state - definition of one FSM state
  * @name: string name of state for debugging
  * @entry_action: function to run when state is entered
  * @exit_action: function to run when state is exitting
+ * @expect: optional E_BAD terminated list of events that are ignored by
+ *   design in this state.  When set, an event with no transition that is
+ *   not on the list is reported as a protocol violation.
+ * @parent: optional superstate.  An event with no transition from this
+ *   state is matched against the superstate transitions, so a transition
+ *   shared by all substates is only defined once.  A transition must
+ *   target a leaf state or a history pseudo-state.
+ * @history: when set, this is a history pseudo-state of @parent.  A
+ *   transition to it resumes the state active when @parent was last left.
+ * @default_p: for a history pseudo-state, the state entered when @parent
+ *   has never been left, e.g. its initial substate
+ * @timeout: optional timeout, the timer is started one-shot before the
+ *   entry action and cancelled before the exit action.  Its event is
+ *   only sent to the FSM entering the state, so a table with a timeout
+ *   has one instance (see fsm_clone)
  */
 typedef struct fsm_state {
 	const char * const name;
 	action entry_action;
 	action exit_action;
+	const fsm_events_t *expect;
+	struct fsm_state *parent;
+	enum fsm_history history;
+	struct fsm_state *default_p;
+	struct fsm_timeout timeout;
 } fsm_state_t;
 
 /**
@@ -38,27 +87,196 @@ typedef struct fsm_state {
  */
 typedef bool (*constraint)(void *arg);
 
+/**
+ * typedef fsm_model - reference model of an FSM specification
+ * @st_p: the reference state before the event
+ * @evt_id: the event
+ *
+ * A pure function of the state and event, independent of the transition
+ * table and with no guards or actions.
+ *
+ * Return: the expected state after the event, @st_p if it is ignored
+ */
+typedef fsm_state_t *(*fsm_model)(fsm_state_t *st_p, fsm_events_t evt_id);
+
+/**
+ * struct fsm_prof - action execution time for a transition
+ * @count: number of times the transition has run
+ * @total_ns: total thread cpu nsecs in the exit and entry action calls,
+ *   the engine work between them (history, trace, observers) is not counted
+ * @max_ns: worst case nsecs, the likely source of jitter
+ * @allocs: fsm_malloc and fsm_calloc calls made by the actions, e.g. an
+ *   event sent from an action allocates its queue entry.  An allocation
+ *   made directly by the action code is not seen
+ */
+struct fsm_prof {
+	uint32_t count;
+	uint64_t total_ns;
+	uint64_t max_ns;
+	uint32_t allocs;
+};
+
+/* max superstates an FSM keeps the history of, see fsm_validate */
+#define FSM_MAX_SUPER 8
+
+/**
+ * struct fsm_resume - superstate history of an FSM
+ * @super_p: the superstates left so far
+ * @last_p: for each of @super_p, the leaf state active when it was last
+ *   left, see record_history
+ */
+struct fsm_resume {
+	fsm_state_t *super_p[FSM_MAX_SUPER];
+	fsm_state_t *last_p[FSM_MAX_SUPER];
+};
+
+/**
+ * enum fsm_trans_kind - what a transition does to its source state
+ * @TRANS_EXTERNAL: exit the current state and enter the next state, a
+ *   self transition re-runs the exit and entry actions
+ * @TRANS_INTERNAL: the event is handled without leaving the current
+ *   state, no exit or entry action is run (UML 14.5.11)
+ */
+enum fsm_trans_kind {
+	TRANS_EXTERNAL,
+	TRANS_INTERNAL,
+};
+
 /**
  * typedef fsm_trans - FSM transition
  * @currst_p - pointer to current state
  * @event - one of defined events
  * @guard - boolean to allow, prevent transition
  * @nextst_p - pointer to the next state if transition succeeds
+ * @post - optional postcondition checked after the next state entry action,
+ *   a failure is reported as a protocol violation
+ * @kind - external (default) or internal, for an internal transition
+ *   @nextst_p must be @currst_p
+ * @prio - priority among the transitions for the same state and event,
+ *   the highest enabled one runs (default 0)
+ * @prof - action profile, updated when fsm_profile is set
+ * @resume_p - first entry only, the superstate history, allocated when a
+ *   superstate is first left.  It is kept with the table, not the shared
+ *   states, so a clone or a shadow FSM has its own history
+ * @index_p - first entry only, the transition index, see fsm_index_build
+ *
+ * Several transitions for one state and event must have distinct @prio
+ * or guards, see fsm_validate.
+ *
+ * An FSM is an array of transitions ending with a NULL @currst_p entry.
+ * The first entry is the init transition and its @currst_p tracks the
+ * FSM current state.  An entry with @event E_BAD is a free slot,
+ * see fsm_trans_remove and fsm_trans_add.
  */ 
 typedef struct fsm_trans {
 	fsm_state_t *currst_p;
 	fsm_events_t event;
 	constraint guard;
 	fsm_state_t *nextst_p;
+	constraint post;
+	enum fsm_trans_kind kind;
+	int prio;
+	struct fsm_prof prof;
+	struct fsm_resume *resume_p;
+	struct fsm_index *index_p;
 } fsm_trans_t;
 
+/**
+ * enum fsm_result - what fsm_run did with an event
+ * @FSM_TRANSITION: the transition was run
+ * @FSM_GUARDED: a transition matched but its guard refused it
+ * @FSM_UNHANDLED: no transition for the event in the current state
+ * @FSM_QUEUED: reentrant call from an action or guard, the event is queued
+ */
+enum fsm_result {
+	FSM_TRANSITION,
+	FSM_GUARDED,
+	FSM_UNHANDLED,
+	FSM_QUEUED,
+};
+
+/**
+ * struct fsm_outcome - detailed result of fsm_run
+ * @result: what was done with the event
+ * @evt_id: the event
+ * @from_p: the current state before the event
+ * @to_p: the current state after the event, @from_p unless a transition ran
+ * @trans_p: the matched transition, NULL if @result is FSM_UNHANDLED
+ *   or FSM_QUEUED
+ */
+struct fsm_outcome {
+	enum fsm_result result;
+	fsm_events_t evt_id;
+	fsm_state_t *from_p;
+	fsm_state_t *to_p;
+	fsm_trans_t *trans_p;
+};
+
+/**
+ * enum fsm_phase - where fsm_run is in a transition, in order
+ * @PH_IDLE: not running a transition
+ * @PH_GUARD: running the guards of the matching transitions
+ * @PH_EXIT: running the current state exit action
+ * @PH_TRANS: updating the current state to the next state
+ * @PH_ENTRY: running the next state entry action, the current state
+ *   is already the next state
+ * @PH_POST: checking the transition postcondition
+ */
+enum fsm_phase {
+	PH_IDLE,
+	PH_GUARD,
+	PH_EXIT,
+	PH_TRANS,
+	PH_ENTRY,
+	PH_POST,
+};
+
+/* number of transitions kept in the history ring */
+#define FSM_HIST_LEN 1024
+
+/**
+ * struct fsm_hist - one transition in the history ring
+ * @ts: run clock time of the transition
+ * @name: name of the worker running the FSM
+ * @event: event causing the transition
+ * @from_p: state left
+ * @to_p: state entered
+ */
+struct fsm_hist {
+	struct timespec ts;
+	const char *name;
+	fsm_events_t event;
+	fsm_state_t *from_p;
+	fsm_state_t *to_p;
+};
+
+/* max number of registered transition observers, see fsm_observe */
+#define FSM_OBSERVERS 8
+
+/**
+ * typedef fsm_observer_fn - called for each FSM state change
+ * @rec_p: the transition, as recorded in the history ring, only valid
+ *   during the call
+ * @arg: the argument given to fsm_observe
+ */
+typedef void (*fsm_observer_fn)(const struct fsm_hist *rec_p, void *arg);
+
+/*
+ * FSM_TRANS_SPARE - a free entry for fsm_trans_add, put before the end
+ *   of an FSM table that can gain transitions at runtime.  Its state only
+ *   keeps the entry from ending the table.
+ */
+#define FSM_TRANS_SPARE {&fsm_spare, E_BAD, NULL, NULL}
+extern fsm_state_t fsm_spare;
+
 /*
  * action debug macro
  */
 #define ACT_TRACE() do { \
 		if (debug_flag & DBG_DEEP) {				\
 			fsm_state_t *state_p = (fsm_state_t*) arg;	\
-			printf("%s:%s %s\n", worker_get_name(), __func__, state_p->name); \
+			printf("%s:%s %s %s\n", worker_get_name(), __func__, state_p->name, \
+			       fsm_phase_name[fsm_phase_get()]);	\
 		}							\
 	} while(0);
 
@@ -75,6 +293,39 @@ static inline void fsm_init(fsm_trans_t *fsm_p)
 		fsm_p->currst_p->entry_action(fsm_p->currst_p);
 }
 
-extern int fsm_run(fsm_trans_t * fsm_p, fsm_events_t evt_id);
+extern const char *fsm_phase_name[];
+extern enum fsm_phase fsm_phase_get(void);
+extern void *fsm_ctx_get(void);
+extern uint32_t fsm_evt_data(void);
+extern int fsm_post(fsm_events_t evt_id);
+extern bool fsm_profile;
+extern uint32_t dbg_keepalive_ms;
+extern const char *dbg_trans_fmt;
+extern uint32_t fsm_violations;
+extern enum fsm_result fsm_run(fsm_trans_t * fsm_p, fsm_events_t evt_id,
+			       struct fsm_outcome *out_p);
+extern void fsm_prof_show(fsm_trans_t *fsm_p, const char *name);
+extern void fsm_explain(fsm_trans_t *fsm_p, fsm_state_t *initst_p,
+			fsm_state_t *currst_p, const char *name);
+extern int fsm_validate(fsm_trans_t *fsm_p, const char *name);
+extern int fsm_index_build(fsm_trans_t *fsm_p);
+extern void fsm_index_drop(fsm_trans_t *fsm_p);
+extern fsm_trans_t *fsm_clone(fsm_trans_t *fsm_p, fsm_state_t *initst_p);
+extern bool fsm_strict;
+extern void fsm_walk(fsm_trans_t *fsm_p, const char *name, uint32_t steps,
+		     uint32_t nap);
+extern uint32_t fsm_hist_get(struct fsm_hist *hist, uint32_t max);
+extern void fsm_hist_dump(int fd, uint32_t max);
+extern uint32_t fsm_hist_count(void);
+extern int fsm_observe(fsm_trans_t *fsm_p, fsm_observer_fn fn, void *arg);
+extern int fsm_unobserve(fsm_observer_fn fn, void *arg);
+extern fsm_state_t *fsm_shadow_run(fsm_trans_t *fsm_p, fsm_events_t evt_id);
+extern int fsm_trans_add(fsm_trans_t *fsm_p, fsm_state_t *currst_p, fsm_events_t evt_id,
+			 int prio, constraint guard, fsm_state_t *nextst_p);
+extern fsm_state_t *fsm_state_find(fsm_trans_t *fsm_p, const char *name);
+extern int fsm_trans_remove(fsm_trans_t *fsm_p, fsm_state_t *currst_p, fsm_events_t evt_id,
+			    int prio);
+extern int fsm_trans_replace(fsm_trans_t *fsm_p, fsm_state_t *currst_p, fsm_events_t evt_id,
+			     int prio, constraint guard, fsm_state_t *nextst_p);
 
 #endif /* _FSM_H */
@@ -17,12 +17,26 @@
 #include <workers.h>
 
 /************************************ timers ****************************************/
-/* timer ids used in create_timer, set_timer */
+/* timer ids used in create_timer, set_timer, start_timer */
 enum timer_ids {
 	TID_LIGHT,
 	TID_BLINK,
+	TID_CADENCE,
 };
 
+/*
+ * TOPIC_TRAFFIC - the group the stoplight publishes its lights to.  The
+ * stoplight does not know who runs on them, the crosswalk (or an observer)
+ * subscribes with worker_join.
+ */
+#define TOPIC_TRAFFIC "traffic"
+
+/*
+ * GROUP_CROSSWALKS - every crosswalk joins it, the cadence timer is sent
+ * to it so all the blinking crosswalks play the countdown
+ */
+#define GROUP_CROSSWALKS "crosswalks"
+
 /*
  * Set timers relative to the tick commandline arg
  */
@@ -36,11 +50,66 @@ extern uint32_t tick;
  * t_but: timeout after button push (see FSM1)
  * t_blink: timout for crosswalk to start blinking,
  *          indicating that it will soon change to DONT WALK.
+ * t_cadence: period of the crosswalk audio countdown while blinking
  */
 uint32_t t_norm = 10;
 uint32_t t_fast = 3;
 uint32_t t_but = 1;
 uint32_t t_blink = (10-2);
+uint32_t t_cadence = 1;
+
+/**
+ * enum light_mode - stoplight timing mode, requested with the CLI o command
+ * @MODE_NORMAL: normal timing
+ * @MODE_SCHOOL: school hours, S:RED (and S:WALK) lasts twice as long
+ *
+ * A mode change is applied when the stoplight enters S:GREEN so a cycle
+ * in progress keeps its timing.
+ */
+enum light_mode {
+	MODE_NORMAL,
+	MODE_SCHOOL,
+};
+
+/* mode in effect for the current cycle */
+enum light_mode light_mode = MODE_NORMAL;
+
+/**
+ * t_red - S:RED timeout for the current mode
+ */
+static inline uint32_t t_red(void)
+{
+	return((MODE_SCHOOL == light_mode) ? 2 * t_norm : t_norm);
+}
+
+/* S:RED timeout of the cycle in progress, set by green_enter */
+uint32_t t_red_cycle;
+
+/************************************ audio *****************************************/
+
+/**
+ * enum audio_cue - crosswalk accessibility audio output
+ * @AUDIO_WALK: walk chirp, the walk signal is on
+ * @AUDIO_COUNTDOWN: countdown tick while blinking, every t_cadence
+ * @AUDIO_STOP: silence, the walk signal is off
+ */
+enum audio_cue {
+	AUDIO_WALK,
+	AUDIO_COUNTDOWN,
+	AUDIO_STOP,
+};
+
+const char *audio_cue_name[] = {
+	"walk",
+	"countdown",
+	"stop",
+};
+
+/*
+ * audio_fn - crosswalk audio output hook, called with the cue and the
+ * ticks left before the stoplight turns green.  NULL for no audio.
+ */
+void (*audio_fn)(enum audio_cue cue, uint32_t ticks) = NULL;
 
 /************************************** FSM action functions *****************************/
 
@@ -71,15 +140,16 @@ static void act_exit(void *arg)
 static void act_done(void *arg)
 {
 	ACT_TRACE();
-	pthread_exit(NULL);
+	worker_exit();
 }
 
 /**
  * stoplight_init_enter - init stoplight FSM
  *
  * When FSMs are started with E_INIT event, each is responsible to provision
- * itself.  This creates two timers: TID_LIGHT for changing the stoplight
- * and TID_BLINK for crosswalk blinking.  A set of timeout values are configured
+ * itself.  This creates three timers: TID_LIGHT for changing the stoplight,
+ * TID_BLINK for crosswalk blinking and TID_CADENCE for the countdown cadence
+ * of every crosswalk.  A set of timeout values are configured
  * as increments of the command line argument `tick`.
  * - t_norm: normal timeout for light change
  * - t_fast: timeout for yellow light, which is brief
@@ -91,78 +161,181 @@ static void stoplight_init_enter(void *arg)
 	ACT_TRACE();
 
 	/* create timers with event on expiry */
-	create_timer(TID_LIGHT, E_LIGHT);
-	create_timer(TID_BLINK, E_BLINK);
+	create_timer(TID_LIGHT, E_LIGHT, "light");
+	create_timer(TID_BLINK, E_BLINK, "blink");
+	create_timer(TID_CADENCE, E_TIMER, "cadence");
+	route_timer_group(TID_CADENCE, GROUP_CROSSWALKS);
 
 	/* update timer expiry periods to be adjustable */
 	t_norm *= tick;
 	t_fast *= tick;	
 	t_but *= tick;
 	t_blink *= tick;
+	t_cadence *= tick;
 }
 
 /**
- * green_enter - broadcast event and set light timer for normal
- * timeout.
+ * green_enter - publish the light, the light timer is the S:GREEN
+ * timeout.  A requested mode change takes effect here, at the start of
+ * the cycle.
  */
 static void green_enter(void *arg)
 {
 	ACT_TRACE();
-	workers_evt_broadcast(E_GREEN);
-	set_timer(TID_LIGHT, t_norm);
+	if (cli_mode != light_mode && cli_mode <= MODE_SCHOOL) {
+		light_mode = cli_mode;
+		printf("%s: mode %u\n", worker_get_name(), light_mode);
+	}
+	t_red_cycle = t_red();
+	workers_group_broadcast(TOPIC_TRAFFIC, E_GREEN);
 }
 
 /**
- * yellow_enter - broadcast event and set light timer for fast
- * timeout because yellow should be brief.
+ * yellow_enter - publish the light, the S:YELLOW timeout is brief
  */
 static void yellow_enter(void *arg)
 {
 	ACT_TRACE();
-	workers_evt_broadcast(E_YELLOW);
-	set_timer(TID_LIGHT, t_fast);
+	workers_group_broadcast(TOPIC_TRAFFIC, E_YELLOW);
 }
 
 /**
- * red_enter - broadcast event and set light timer for normal
- * timeout.
+ * red_enter - publish the light, the S:RED timeout depends on the mode,
+ * see t_red.
  */
 static void red_enter(void *arg)
 {
 	ACT_TRACE();
-	workers_evt_broadcast(E_RED);
-	set_timer(TID_LIGHT, t_norm);	
+	workers_group_broadcast(TOPIC_TRAFFIC, E_RED);
 }
 
 /**
- * green_but_enter - action entering S:GREEN_BUT state to
- * update the light timer to t_but, which will cause RED/WALK more
- * quicker. 
+ * green_but_enter - action entering S:GREEN_BUT state, its t_but
+ * timeout will cause RED/WALK more quicker.  The E_BUTTON payload is
+ * the pushbutton number, 0 if unknown.
  */
 static void green_but_enter(void *arg)
+{
+	uint32_t button = fsm_evt_data();
+
+	ACT_TRACE();
+	if (button)
+		printf("%s: button %u\n", worker_get_name(), button);
+}
+
+/**
+ * flash_enter - action entering S:FLASH_RED, the safe mode when the
+ * crosswalk is down.  The light cycle stops (no timeout) and traffic
+ * treats the intersection as an all-way stop.
+ */
+static void flash_enter(void *arg)
 {
 	ACT_TRACE();
-	set_timer(TID_LIGHT, t_but);
+	printf("%s: peer %u down, flashing red\n", worker_get_name(), fsm_evt_data());
 }
 
 /**
- * walk_enter - set the walk signal blink timer to expire
+ * struct crosswalk_ctx - crosswalk extended state, see fsm_ctx_get
+ * @walks: number of times the walk signal has been on
+ */
+struct crosswalk_ctx {
+	uint32_t walks;
+};
+
+/*
+ * walking - number of crosswalks in S:WALK, they share the blink timer
+ * walk_mutex - a count change and the timer change it causes are atomic
+ */
+static uint32_t walking;
+static pthread_mutex_t walk_mutex = PTHREAD_MUTEX_INITIALIZER;
+
+/**
+ * walk_enter - set the walk signal blink timer to expire once
  * before the stoplight turns from from RED to GREEN.  When the
  * timer expires, the crosswalk enters the S:BLINKING WALK state.
  */
 static void walk_enter(void *arg)
 {
+	struct crosswalk_ctx *ctx_p = fsm_ctx_get();
+
 	ACT_TRACE();
-	set_timer(TID_BLINK, t_blink);
+	pthread_mutex_lock(&walk_mutex);
+	walking++;
+	start_timer(TID_BLINK, t_blink + t_red() - t_norm, E_BLINK, false);
+	pthread_mutex_unlock(&walk_mutex);
+	if (audio_fn)
+		audio_fn(AUDIO_WALK, get_timer(TID_LIGHT) / tick);
+	if (ctx_p) {
+		ctx_p->walks++;
+		if (debug_flag & DBG_DEEP)
+			printf("walk %u\n", ctx_p->walks);
+	}
 }
 
 /*
- * walk-exit - clear the crosswalk blink timer
+ * walk_exit - cancel the crosswalk blink timer once no crosswalk walks, a
+ * late E_BLINK would start blinking after the walk signal is off
  */
 static void walk_exit(void *arg)
 {
 	ACT_TRACE();
-	set_timer(TID_BLINK, 0);
+	pthread_mutex_lock(&walk_mutex);
+	if (0 == --walking)
+		cancel_timer(TID_BLINK);
+	pthread_mutex_unlock(&walk_mutex);
+}
+
+/*
+ * blinking - number of crosswalks in S:BLINKING WALK, they share the
+ * cadence timer
+ * blink_mutex - a count change and the timer change it causes are atomic
+ */
+static uint32_t blinking;
+static pthread_mutex_t blink_mutex = PTHREAD_MUTEX_INITIALIZER;
+
+/**
+ * blink_enter - play a countdown tick and run the cadence timer
+ *
+ * Each cadence timer expiry is an E_TIMER self transition back into
+ * S:BLINKING WALK, so the countdown follows the timer service.  The
+ * cadence is only sent to the crosswalks (GROUP_CROSSWALKS), the first
+ * one blinking starts it.
+ */
+static void blink_enter(void *arg)
+{
+	ACT_TRACE();
+	if (NULL == audio_fn)
+		return;
+	audio_fn(AUDIO_COUNTDOWN, get_timer(TID_LIGHT) / tick);
+	pthread_mutex_lock(&blink_mutex);
+	if (1 == ++blinking)
+		set_timer(TID_CADENCE, t_cadence);
+	pthread_mutex_unlock(&blink_mutex);
+}
+
+/**
+ * blink_exit - stop the cadence timer when no crosswalk is blinking, e.g.
+ * a torn down crosswalk does not stop the countdown of the others
+ */
+static void blink_exit(void *arg)
+{
+	ACT_TRACE();
+	if (NULL == audio_fn)
+		return;
+	pthread_mutex_lock(&blink_mutex);
+	if (0 == --blinking)
+		set_timer(TID_CADENCE, 0);
+	pthread_mutex_unlock(&blink_mutex);
+}
+
+/**
+ * nowalk_enter - silence the crosswalk audio
+ */
+static void nowalk_enter(void *arg)
+{
+	ACT_TRACE();
+	if (audio_fn)
+		audio_fn(AUDIO_STOP, 0);
 }
 
 /**
@@ -184,6 +357,17 @@ static bool but_constraint(void *arg)
 	return(false);
 }
 
+/**
+ * walk_post - postcondition for the transition to S:WALK
+ *
+ * Return: true if walk_enter armed the blink timer, so the walk signal
+ * will blink before the stoplight turns green
+ */
+static bool walk_post(void *arg)
+{
+	return(get_timer(TID_BLINK) > 0);
+}
+
 
 /********************************* FSM Definitions *******************************/
 
@@ -195,44 +379,115 @@ fsm_state_t s_done = {"S:DONE", act_done, NULL};
  * FSM1, stoplight 
  */
 fsm_state_t s_stoplight_init = {"S:INIT", stoplight_init_enter, act_exit};
-fsm_state_t s_red = {"S:RED", red_enter, act_exit};
-fsm_state_t s_green = {"S:GREEN", green_enter, act_exit};
-fsm_state_t s_yellow = {"S:YELLOW", yellow_enter, act_exit};
-fsm_state_t s_green_but = {"S:GREEN_BUT", green_but_enter, act_exit};
+
+/* superstate of the normal light cycle, each light times out to E_LIGHT */
+fsm_state_t s_normal = {"S:NORMAL", act_enter, act_exit};
+fsm_state_t s_red = {"S:RED", red_enter, act_exit, NULL, &s_normal,
+		     .timeout = {&t_red_cycle, E_LIGHT, TID_LIGHT}};
+fsm_state_t s_green = {"S:GREEN", green_enter, act_exit, NULL, &s_normal,
+		       .timeout = {&t_norm, E_LIGHT, TID_LIGHT}};
+fsm_state_t s_yellow = {"S:YELLOW", yellow_enter, act_exit, NULL, &s_normal,
+			.timeout = {&t_fast, E_LIGHT, TID_LIGHT}};
+fsm_state_t s_green_but = {"S:GREEN_BUT", green_but_enter, act_exit, NULL, &s_normal,
+			   .timeout = {&t_but, E_LIGHT, TID_LIGHT}};
+
+/*
+ * maintenance interrupt, it has no timeout so the light cycle stops.  It
+ * resumes from the light it left through the history, S:GREEN as the
+ * stoplight starts if the light cycle never ran.
+ */
+fsm_state_t s_maint = {"S:MAINT", act_enter, act_exit};
+fsm_state_t s_normal_hist = {"S:NORMAL(H)", NULL, NULL, NULL, &s_normal, HISTORY_SHALLOW,
+			     &s_green};
+
+/* safe mode when the crosswalk FSM is gone */
+fsm_state_t s_flash = {"S:FLASH_RED", flash_enter, act_exit};
 fsm_trans_t FSM1[] = {
 	/* specific init for timers, transition to s_green */
 	{&s_stoplight_init, E_INIT, NULL, &s_green},
 
+	/* NORMAL, for every light state */
+	{&s_normal, E_DONE, NULL, &s_done},
+	{&s_normal, E_MAINT, NULL, &s_maint},
+	{&s_normal, E_PEER_DOWN, NULL, &s_flash},
+
+	/* MAINT */
+	{&s_maint, E_MAINT, NULL, &s_normal_hist},
+	{&s_maint, E_PEER_DOWN, NULL, &s_flash},
+	{&s_maint, E_DONE, NULL, &s_done},
+
+	/* FLASH RED */
+	{&s_flash, E_DONE, NULL, &s_done},
+
 	/* GREEN */
 	{&s_green, E_LIGHT, NULL, &s_yellow},
-	{&s_green, E_DONE, NULL, &s_done},
 	{&s_green, E_BUTTON, but_constraint, &s_green_but},
 
 	/* YELLOW */
 	{&s_yellow, E_LIGHT, NULL, &s_red},
-	{&s_yellow, E_DONE, NULL, &s_done},	
 
 	/* RED */
 	{&s_red, E_LIGHT, NULL, &s_green},
-	{&s_red, E_DONE, NULL, &s_done},
 
 	/* GREEN BUT */
 	{&s_green_but, E_LIGHT, NULL, &s_yellow},
-	// TODO: NO DONE? {&st_green_but, E_DONE, &st_done},
+
+	/* free entries for the CLI u command, see fsm_trans_add */
+	FSM_TRANS_SPARE,
+	FSM_TRANS_SPARE,
+
+	/* end of table */
+	{NULL, E_BAD, NULL, NULL},
+};
+
+/* workers the stoplight light events must reach */
+const char * const stoplight_peers[] = {TOPIC_TRAFFIC "/", NULL};
+
+/**
+ * FSM1_SHADOW - modified stoplight definition for shadow execution (-S)
+ *
+ * A button press in S:GREEN goes straight to S:YELLOW instead of waiting
+ * in S:GREEN_BUT.  The shadow shares the live states but never runs their
+ * actions, see fsm_shadow_run.
+ */
+fsm_trans_t FSM1_SHADOW[] = {
+	{&s_stoplight_init, E_INIT, NULL, &s_green},
+
+	/* NORMAL */
+	{&s_normal, E_DONE, NULL, &s_done},
+
+	/* GREEN */
+	{&s_green, E_LIGHT, NULL, &s_yellow},
+	{&s_green, E_BUTTON, NULL, &s_yellow},
+
+	/* YELLOW */
+	{&s_yellow, E_LIGHT, NULL, &s_red},
+
+	/* RED */
+	{&s_red, E_LIGHT, NULL, &s_green},
+
+	/* end of table */
+	{NULL, E_BAD, NULL, NULL},
 };
 
 /**
  * FSM2, crosswalk 
  */
-fsm_state_t s_nowalk = {"S:DONT_WALK", act_enter, act_exit};
-fsm_state_t s_walk = {"S:WALK", walk_enter, act_exit};
-fsm_state_t s_blink = {"S:BLINKING WALK", act_enter, act_exit};
+fsm_state_t s_nowalk = {"S:DONT_WALK", nowalk_enter, act_exit};
+
+/*
+ * the stoplight must not leave RED while pedestrians are walking, it may
+ * stop for maintenance and resume RED
+ */
+const fsm_events_t walk_expect[] = {E_BUTTON, E_MAINT, E_RED, E_BAD};
+fsm_state_t s_walk = {"S:WALK", walk_enter, walk_exit, walk_expect};
+fsm_state_t s_blink = {"S:BLINKING WALK", blink_enter, blink_exit};
 fsm_trans_t FSM2[] = {
 	/* generic init to s_nowalk */
 	{&s_init, E_INIT, NULL, &s_nowalk},
 
 	/* DONT WALK */
-	{&s_nowalk, E_RED, NULL, &s_walk},
+	{&s_nowalk, E_RED, NULL, &s_walk, walk_post},
 	{&s_nowalk, E_DONE, NULL, &s_done},	
 
 	/* WALK */
@@ -241,9 +496,41 @@ fsm_trans_t FSM2[] = {
 
 	/* BLINKING */
 	{&s_blink, E_GREEN, NULL, &s_nowalk},
+	/* external, blink_enter plays each countdown tick */
+	{&s_blink, E_TIMER, NULL, &s_blink, NULL, TRANS_EXTERNAL},
 	{&s_blink, E_DONE, NULL, &s_done},
+
+	/* free entries for the CLI u command, see fsm_trans_add */
+	FSM_TRANS_SPARE,
+	FSM_TRANS_SPARE,
+
+	/* end of table */
+	{NULL, E_BAD, NULL, NULL},
 };
 
+/**
+ * crosswalk_model - reference model of the crosswalk specification
+ * @st_p: the crosswalk state
+ * @evt_id: the event
+ *
+ * The walk signal follows the stoplight: walk on red, blink when the
+ * blink timer expires, stop walking on green.
+ */
+static fsm_state_t *crosswalk_model(fsm_state_t *st_p, fsm_events_t evt_id)
+{
+	if (evt_id == E_DONE)
+		return(&s_done);
+	if (st_p == &s_init && evt_id == E_INIT)
+		return(&s_nowalk);
+	if (st_p == &s_nowalk && evt_id == E_RED)
+		return(&s_walk);
+	if (st_p == &s_walk && evt_id == E_BLINK)
+		return(&s_blink);
+	if (st_p == &s_blink && evt_id == E_GREEN)
+		return(&s_nowalk);
+	return(st_p);
+}
+
 #endif /* _FSM_DEFS_H */
 
 
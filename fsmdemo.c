@@ -16,12 +16,16 @@
 #include <time.h>        /* clock_gettime, struct timespec, timer_create */
 #include <stdio.h>       /* char I/O */
 #include <signal.h>      /* sigaction */
-#include <string.h>      /* strlen, strsignal,, memset */
+#include <string.h>      /* strlen, memset */
 #include <pthread.h>     /* posix threads */
+#include <fcntl.h>       /* open */
+#include <setjmp.h>      /* setjmp, longjmp */
 #include <utils.h>
 #include <evtq.h>
 #include <fsm.h>
 #include <workers.h>
+#include <export.h>
+#include <workload.h>
 
 #include <fsm_defs.h>
 
@@ -32,13 +36,52 @@ char *arguments = "\n"							\
 	" -t tick: timer tick in msec\n"				\
 	" -s scriptfile: read events from file\n"			\
 	" -n: non-interactive mode (only read from scriptfile)\n"	\
+	" -i cmds: run cmds at startup (e.g. -i g)\n"		\
+	" -S: run the modified stoplight FSM1_SHADOW in shadow\n"	\
+	" -R: check the crosswalk against its reference model\n"	\
+	" -a: crosswalk accessibility audio on stdout\n"		\
+	" -c prefix: write run statistics to prefix_*.csv on exit\n"	\
+	" -g svgfile: write a state timeline to svgfile on exit\n"	\
+	" -P: profile FSM action execution time and allocations\n"	\
+	" -X: strict, transitions for a state and event need distinct\n" \
+	"    priorities even when guarded\n"				\
+	" -v: virtual time, the timers run on a simulated clock moved\n" \
+	"    by the script naps, nothing sleeps\n"			\
+	" -1: single-threaded, the FSMs and timers run on the main\n"	\
+	"    thread in a fixed order, implies -v\n"			\
+	" -C file: crash snapshot file (./fsmdemo.crash)\n"	\
+	" -w file: generate synthetic events from a workload file\n"	\
+	" -W steps: print a random walk script for each FSM and exit\n" \
+	" -j file: write a JSON run summary to file on exit\n"	\
 	" -d hex: set debug_flag to hex level\n"			\
 	"    0x01: debug FSM transitions\n"				\
 	"    0x02: debug event push/pop\n"				\
 	"    0x04: debug timers\n"					\
+	"    0x08: debug FSM state changes only\n"			\
 	"    0x10: debug FSM workers\n"					\
 	"    0x20: debug deep for unit debug\n"				\
-	" -h: this help\n";
+	" -K msec: with 0x08, show an unchanged state every msec\n"	\
+	" -F fmt: with 0x01, transition line template using {ts},\n"	\
+	"    {fsm}, {from}, {event}, {to}, {desc}, {usec}\n"		\
+	"    set FSM_TRACE=name,-name to trace only some workers\n"	\
+	" -T format: trace format, text (default) or json: with 0x01\n" \
+	"    one JSON object per line for each transition, rejected\n" \
+	"    event and timer expiry\n"				\
+	" -I msec: run the worker idle hook after msec without events\n" \
+	" -A msec: report events queued longer than msec as stale\n"	\
+	" -U policy: unhandled events: ignore (default), log, abort,\n" \
+	"    dead (keep in the dead letters), call (fsm_unhandled)\n" \
+	" -D policy: duplicate worker names: error (default), suffix\n" \
+	"    (rename with -N), allow\n"				\
+	" -O: print each state change from an FSM observer\n"	\
+	" -p name: the stoplight also declares worker name as a peer\n" \
+	" -h: this help\n"						\
+	"exit codes:\n"							\
+	"    0: success\n"							\
+	"    1: fatal error, or a worker did not stop on exit\n"	\
+	"    2: FSM protocol violation\n"					\
+	"    3: FSM table or peer validation error\n"				\
+	"    4: unknown or malformed CLI/script command\n";
 
 /**
  *
@@ -53,6 +96,30 @@ uint32_t tick = 1000;
  */
 char scriptfile[64] = "./fsmdemo.script";
 
+/*
+ * single - run the FSM workers and timers on the main thread, see
+ * single_run
+ */
+static bool single = false;
+
+/*
+ * idle_ms - msecs without an event before a worker runs its idle hook,
+ * 0 for never
+ */
+static uint32_t idle_ms = 0;
+
+/*
+ * stale_ms - msecs an event may be queued before it is reported stale,
+ * 0 for never
+ */
+static uint32_t stale_ms = 0;
+
+/*
+ * unhandled_policy - policy for events an FSM has no transition for,
+ * e.g. a stray BUTTON in S:RED is ignored by default
+ */
+static enum unhandled_policy unhandled_policy = UNHANDLED_IGNORE;
+
 /**
  * non_interactive - If false then commands are accepted
  *  from STDIN in an epoll loop.  If true then the given scriptfile
@@ -60,6 +127,85 @@ char scriptfile[64] = "./fsmdemo.script";
  */
 static bool non_interactive = false;
 
+/**
+ * initcmds - CLI commands parsed once all workers are registered and
+ *  before the interactive/script input is read.  This allows a headless
+ *  run to start the FSMs without a human typing 'g'.
+ */
+static char initcmds[64] = "";
+
+/**
+ * shadow - run FSM1_SHADOW alongside the live stoplight and report
+ *  where the two definitions diverge.
+ */
+static bool shadow = false;
+
+/*
+ * reference - check the crosswalk against crosswalk_model and report
+ * each divergence
+ */
+static bool reference = false;
+
+/**
+ * csvprefix - if set, export the transition history and event counts
+ *  to CSV files with this prefix when the run ends.
+ */
+static char csvprefix[64] = "";
+
+/**
+ * svgfile - if set, write a Gantt-style SVG timeline of the FSM states
+ *  to this file when the run ends.
+ */
+static char svgfile[64] = "";
+
+/**
+ * workloadfile - if set, synthetic events are generated by a workload
+ *  thread from the rules in this file, see workload.c
+ */
+static char workloadfile[64] = "";
+
+/**
+ * jsonfile - if set, write a machine-readable run summary to this
+ *  file when the run ends.
+ */
+static char jsonfile[64] = "";
+
+/*
+ * exit codes, documented in arguments.  1 is EXIT_FAILURE used by die.
+ */
+#define EXIT_VIOLATION 2
+#define EXIT_VALIDATION 3
+#define EXIT_CLI 4
+
+/**
+ * crashfile - post-mortem snapshot written by crash_handler
+ */
+static char crashfile[64] = "./fsmdemo.crash";
+
+/* walk_steps - with -W, print a random walk of this many steps and exit */
+static uint32_t walk_steps;
+
+/* observe - print the state changes from fsm_observer, see fsm_observe */
+static bool observe = false;
+
+/* stoplight_peer - with -p, a worker the stoplight declares by name */
+static const char *stoplight_peer;
+
+/* number of transitions written to the crashfile */
+#define CRASH_HIST 64
+
+/**
+ * crosswalk_audio - crosswalk audio output hook, see audio_fn
+ * @cue: the sound to play
+ * @ticks: ticks left before the stoplight turns green
+ *
+ * There is no sound device, the cue is written to stdout.
+ */
+static void crosswalk_audio(enum audio_cue cue, uint32_t ticks)
+{
+	printf("%s: audio %s %u\n", worker_get_name(), audio_cue_name[cue], ticks);
+}
+
 /**
  * cmdline_args - parse command line arguments
  * @argc: argument count (from main)
@@ -76,7 +222,7 @@ int cmdline_args(int argc, char *argv[]) {
 	int opt;
 	int argcnt = 0;
 	
-	while((opt = getopt(argc, argv, "t:s:nd:h")) != -1) {
+	while((opt = getopt(argc, argv, "t:s:ni:SRaPXv1Op:c:g:C:j:w:W:d:K:F:T:I:A:U:D:h")) != -1) {
 		switch(opt) {
 		case 't':
 			tick = strtoul(optarg, NULL, 0);
@@ -89,9 +235,102 @@ int cmdline_args(int argc, char *argv[]) {
 		case 'n':
 			non_interactive = true;
 			break;
+		case 'i':
+			strncpy(initcmds, optarg, sizeof(initcmds)-1);
+			break;
+		case 'S':
+			shadow = true;
+			break;
+		case 'a':
+			audio_fn = crosswalk_audio;
+			break;
+		case 'R':
+			reference = true;
+			break;
+		case 'c':
+			strncpy(csvprefix, optarg, sizeof(csvprefix)-1);
+			break;
+		case 'g':
+			strncpy(svgfile, optarg, sizeof(svgfile)-1);
+			break;
+		case 'P':
+			fsm_profile = true;
+			break;
+		case 'X':
+			fsm_strict = true;
+			break;
+		case 'v':
+			clock_use(&sim_clock);
+			break;
+		case '1':
+			single = true;
+			clock_use(&sim_clock);
+			break;
+		case 'C':
+			strncpy(crashfile, optarg, sizeof(crashfile)-1);
+			break;
+		case 'w':
+			strncpy(workloadfile, optarg, sizeof(workloadfile)-1);
+			break;
+		case 'W':
+			walk_steps = strtoul(optarg, NULL, 0);
+			break;
+		case 'j':
+			strncpy(jsonfile, optarg, sizeof(jsonfile)-1);
+			break;
 		case 'd':
 			debug_flag = strtoul(optarg, NULL, 0);
 			break;
+		case 'U':
+			if (0 == strcmp(optarg, "log"))
+				unhandled_policy = UNHANDLED_LOG;
+			else if (0 == strcmp(optarg, "abort"))
+				unhandled_policy = UNHANDLED_ABORT;
+			else if (0 == strcmp(optarg, "dead"))
+				unhandled_policy = UNHANDLED_DEAD;
+			else if (0 == strcmp(optarg, "call"))
+				unhandled_policy = UNHANDLED_CALL;
+			else if (0 != strcmp(optarg, "ignore")) {
+				printf("%s: unknown unhandled policy\n", optarg);
+				exit(EXIT_CLI);
+			}
+			break;
+		case 'A':
+			stale_ms = strtoul(optarg, NULL, 0);
+			break;
+		case 'I':
+			idle_ms = strtoul(optarg, NULL, 0);
+			break;
+		case 'K':
+			dbg_keepalive_ms = strtoul(optarg, NULL, 0);
+			break;
+		case 'F':
+			dbg_trans_fmt = optarg;
+			break;
+		case 'D':
+			if (0 == strcmp(optarg, "suffix"))
+				dup_policy = DUP_SUFFIX;
+			else if (0 == strcmp(optarg, "allow"))
+				dup_policy = DUP_ALLOW;
+			else if (0 != strcmp(optarg, "error")) {
+				printf("%s: unknown duplicate policy\n", optarg);
+				exit(EXIT_CLI);
+			}
+			break;
+		case 'O':
+			observe = true;
+			break;
+		case 'p':
+			stoplight_peer = optarg;
+			break;
+		case 'T':
+			if (0 == strcmp(optarg, "json"))
+				dbg_json = true;
+			else if (0 != strcmp(optarg, "text")) {
+				printf("%s: unknown trace format\n", optarg);
+				exit(EXIT_CLI);
+			}
+			break;
 		case 'h':
 		default:			
 			fprintf(stderr, "Usage: %s %s\n", argv[0], arguments);
@@ -104,73 +343,446 @@ int cmdline_args(int argc, char *argv[]) {
 /********************** signal handling section **********************/
 
 /**
- * sig_handler - placeholder for SIGINT
+ * sig_name - name of a handled signal, async-signal-safe unlike strsignal
+ * @sig: signal number
+ */
+static const char *sig_name(int sig)
+{
+	switch (sig) {
+	case SIGINT:
+		return("SIGINT");
+	case SIGTERM:
+		return("SIGTERM");
+	case SIGSEGV:
+		return("SIGSEGV");
+	case SIGBUS:
+		return("SIGBUS");
+	case SIGFPE:
+		return("SIGFPE");
+	case SIGILL:
+		return("SIGILL");
+	case SIGABRT:
+		return("SIGABRT");
+	default:
+		return("signal");
+	}
+}
+
+/**
+ * sig_handler - SIGINT and SIGTERM stop the run as the x command does
  * @sig: signal number (man7:signal)
  *
- * Catch the signal, write to stdout and exit.  This cannot use
- * printf due to the internal mutex locking.
+ * Set cli_stop so the main thread leaves the CLI and shuts down in
+ * order (see workers_shutdown) instead of killing the FSMs in the middle
+ * of a transition.  The other threads block the signal (see
+ * thread_create) so it always interrupts the wait of the main thread.
+ * A second signal exits at once, in case the shutdown hangs.  Only
+ * async-signal-safe functions are used, see sig_cat.
  * 
  * This must conform to the struct sigaction prototype as defined
  * for sa_handler (man:sigaction).
  */
 void sig_handler(int sig) {
 	char msg[80];
-	sprintf(msg, "\nCatch %s and exit\n", strsignal(sig));
-	write(1, msg, strlen(msg));
-	exit(0);
+	size_t len;
+
+	len = sig_cat(msg, sizeof(msg), 0, "\nCatch ");
+	len = sig_cat(msg, sizeof(msg), len, sig_name(sig));
+	if (cli_stop) {
+		len = sig_cat(msg, sizeof(msg), len, " again and exit\n");
+		write(1, msg, len);
+		_exit(EXIT_FAILURE);
+	}
+	cli_stop = 1;
+	len = sig_cat(msg, sizeof(msg), len, " and shut down\n");
+	write(1, msg, len);
+}
+
+/**
+ * dump_handler - SIGUSR1 shows the FSM status as the s command does
+ * @sig: signal number (man7:signal)
+ *
+ * Inspect a long running fsmdemo without its stdin.  The status is
+ * printed by the main thread, see cli_signals.
+ */
+void dump_handler(int sig) {
+	cli_dump = 1;
+}
+
+/**
+ * crash_handler - write a post-mortem snapshot when a thread crashes
+ * @sig: signal number (man7:signal)
+ *
+ * Best effort: write the signal, the current state of each worker FSM
+ * and the last CRASH_HIST transitions to crashfile, then restore the
+ * default action and re-raise the signal so a core is still dumped.
+ * Only async-signal-safe functions are used (see sig_cat) because the
+ * crashed thread may hold the printf mutex, and the workers are read
+ * from the lock-free table and their published state, not the list.
+ */
+void crash_handler(int sig) {
+	worker_t *w_p;
+	fsm_state_t *st_p;
+	char msg[120];
+	size_t len;
+	int fd, i;
+
+	if (-1 != (fd = open(crashfile, O_WRONLY|O_CREAT|O_TRUNC, 0644))) {
+		len = sig_cat(msg, sizeof(msg), 0, "crash: ");
+		len = sig_cat(msg, sizeof(msg), len, sig_name(sig));
+		len = sig_cat(msg, sizeof(msg), len, "\nworkers\n");
+		write(fd, msg, len);
+		for (i = 0; i < WORKERS_MAX; i++) {
+			if (NULL == (w_p = __atomic_load_n(&workers.tab[i], __ATOMIC_ACQUIRE)))
+				continue;
+			st_p = w_p->fsm_p ? worker_state(w_p) : NULL;
+			len = sig_cat(msg, sizeof(msg), 0, w_p->name);
+			len = sig_cat(msg, sizeof(msg), len, " ");
+			len = sig_cat(msg, sizeof(msg), len, st_p ? st_p->name : "");
+			len = sig_cat(msg, sizeof(msg), len, "\n");
+			write(fd, msg, len);
+		}
+		write(fd, "history\n", 8);
+		fsm_hist_dump(fd, CRASH_HIST);
+		close(fd);
+	}
+
+	len = sig_cat(msg, sizeof(msg), 0, "\nCatch ");
+	len = sig_cat(msg, sizeof(msg), len, sig_name(sig));
+	len = sig_cat(msg, sizeof(msg), len, ", snapshot in ");
+	len = sig_cat(msg, sizeof(msg), len, crashfile);
+	len = sig_cat(msg, sizeof(msg), len, "\n");
+	write(1, msg, len);
+
+	signal(sig, SIG_DFL);
+	raise(sig);
 }
 
 /**
  * set_sig_handlers - set up the default signal handlers
  *
- * SIGINT uses the old sa_handler action for simplicity.
+ * SIGINT, SIGTERM and SIGUSR1 use the old sa_handler action for
+ * simplicity, without SA_RESTART so a wait of the main thread is
+ * interrupted.  Call before any thread_create, the other threads
+ * block them.
+ * Fatal signals write a post-mortem snapshot, see crash_handler.
+ * Called from the main thread.
  */
 void set_sig_handlers(void) {
 	struct sigaction sa;
+	const int fatal[] = {SIGSEGV, SIGBUS, SIGFPE, SIGILL, SIGABRT};
+	int i;
 
 	sigemptyset(&sa.sa_mask);
 	sa.sa_handler = &sig_handler;
 	sa.sa_flags = 0;
-	if (-1 == sigaction(SIGINT, &sa, NULL))
+	if (-1 == sigaction(SIGINT, &sa, NULL) || -1 == sigaction(SIGTERM, &sa, NULL))
 		die("sigint");
+
+	sa.sa_handler = &dump_handler;
+	if (-1 == sigaction(SIGUSR1, &sa, NULL))
+		die("sigusr1");
+
+	sa.sa_handler = &crash_handler;
+	for (i = 0; i < sizeof(fatal)/sizeof(fatal[0]); i++) {
+		if (-1 == sigaction(fatal[i], &sa, NULL))
+			die("crash handler");
+	}
 }
 
 /********************************** application logic *******************************/
 
+/**
+ * fsm_idle - worker idle hook
+ * @w_p: the idle worker
+ *
+ * Housekeeping when no event has arrived for idle_ms: check the
+ * worker is not stuck with a backlog and report it is alive.
+ */
+static void fsm_idle(worker_t *w_p)
+{
+	uint32_t len = evtq_len(w_p->evtq_p);
+
+	if (len)
+		printf("%s: idle with %u events queued\n", w_p->name, len);
+	else if (debug_flag & DBG_WORKER)
+		printf("%s: idle in %s\n", w_p->name, w_p->fsm_p->currst_p->name);
+}
+
+/**
+ * fsm_observer - the -O observer, see fsm_observe
+ * @rec_p: the state change
+ * @arg: not used
+ *
+ * Runs on the worker thread of every FSM, including the spawned
+ * crosswalks.
+ */
+static void fsm_observer(const struct fsm_hist *rec_p, void *arg)
+{
+	printf("%s: observe evt=%s %s to %s\n", rec_p->name,
+	       evt_name[rec_p->event], rec_p->from_p->name, rec_p->to_p->name);
+}
+
+/**
+ * fsm_unhandled - the -U call hook, see worker_unhandled_call
+ * @w_p: the worker
+ * @out_p: the fsm_run outcome
+ *
+ * Report the event with its payload, unlike -U log it is not kept in
+ * the dead letters.
+ */
+static void fsm_unhandled(worker_t *w_p, struct fsm_outcome *out_p)
+{
+	printf("%s: no transition for evt=%s data=%u in %s\n", w_p->name,
+	       evt_name[out_p->evt_id], w_p->evt_data, out_p->from_p->name);
+}
+
+/**
+ * unhandled_set - apply the -U policy to a worker
+ * @w_p: the worker
+ */
+static void unhandled_set(worker_t *w_p)
+{
+	if (UNHANDLED_CALL == unhandled_policy)
+		worker_unhandled_call(w_p, fsm_unhandled);
+	else
+		w_p->unhandled_policy = unhandled_policy;
+}
+
+/**
+ * fsm_task_run - run one event through the worker FSM
+ * @self_p: the worker
+ * @evt_id: the event
+ * @data: the event payload
+ */
+static void fsm_task_run(worker_t *self_p, fsm_events_t evt_id, uint32_t data)
+{
+	struct fsm_outcome out;
+
+	self_p->evt_data = data;
+	if (FSM_UNHANDLED == fsm_run(self_p->fsm_p, evt_id, &out))
+		worker_unhandled(self_p, &out);
+	worker_shadow_check(self_p, evt_id);
+	worker_ref_check(self_p, evt_id);
+
+	/* the other threads only read the FSM state from the snapshot */
+	worker_publish(self_p, evt_id);
+}
+
+/**
+ * fsm_task_evt - run a dequeued event and the internal events it causes
+ * @self_p: the worker
+ * @evt_id: the event
+ * @age: msecs the event was queued
+ * @data: the event payload
+ */
+static void fsm_task_evt(worker_t *self_p, fsm_events_t evt_id, uint32_t age, uint32_t data)
+{
+	dbg_evts(evt_id);
+	self_p->evt_cnt[evt_id]++;
+	self_p->wait_ms += age;
+	if (worker_stale_drop(self_p, evt_id, data, age))
+		return;
+	fsm_task_run(self_p, evt_id, data);
+
+	/* run to completion: internal events first */
+	while (evtq_len(self_p->intq_p)) {
+		evtq_dequeue_batch(self_p->intq_p, &evt_id, NULL, &data, 1, 0);
+		self_p->evt_cnt[evt_id]++;
+		fsm_task_run(self_p, evt_id, data);
+	}
+}
+
+/**
+ * struct fsm_batch - the events fsm_task dequeued at once
+ * @self_p: the worker
+ * @evts: the events
+ * @data: their payloads
+ * @next: the next event to run
+ * @n: number of events
+ */
+struct fsm_batch {
+	worker_t *self_p;
+	fsm_events_t evts[EVTQ_BATCH];
+	uint32_t data[EVTQ_BATCH];
+	uint32_t next;
+	uint32_t n;
+};
+
+/**
+ * fsm_batch_dead - keep the events of a batch not run in the dead letters
+ * @arg: the fsm_batch
+ *
+ * A pthread cleanup handler of the FSM task: the events dequeued with
+ * the one that stopped the worker (e.g. E_DONE) are never run.
+ */
+static void fsm_batch_dead(void *arg)
+{
+	struct fsm_batch *b_p = (struct fsm_batch *) arg;
+	uint32_t i;
+
+	for (i = b_p->next; i < b_p->n; i++)
+		evtq_dead(b_p->self_p->name, b_p->evts[i], b_p->data[i], DEAD_DOWN, NULL);
+}
+
 /**
  * fsm_task - archetype event consumer thread
  * @arg: worker_t context
  *
  * This is the generic FSM task.  It's a simple infinite loop that
- * - dequeues an event enqueued from another thread (or possibly this thread)
- * - injects the event into the FSM
+ * - dequeues pending events enqueued from another thread (or possibly this thread)
+ * - injects each event into the FSM, then the internal events its
+ *   actions posted (see fsm_post) before the next queued event
  * All context persists in the worker_t instance.
  */
 void *fsm_task(void *arg)
 {
 	worker_t* self_p = (worker_t*) arg;
-        fsm_events_t evt_id;
+	struct fsm_batch batch = {self_p};
+	uint32_t ages[EVTQ_BATCH];
+	uint32_t i;
 
 	/* init the FSM and call the the init state enter functiuon */
 	fsm_init(self_p->fsm_p);
+	worker_publish(self_p, E_INIT);
+
+	/* tell the registry events can now be routed to this FSM */
+	worker_ready(self_p);
+
+	/* on pthread_exit (S:DONE) notify the FSMs using this one */
+	pthread_cleanup_push(worker_down, self_p);
+	pthread_cleanup_push(fsm_batch_dead, &batch);
 
 	/* The main lupe
-	 * dequeue event and call dbg_evts for runtime dump
-	 * fsm_run for the fsm instance, injecting evt_id
+	 * dequeue all pending events (up to EVTQ_BATCH) in one wakeup
+	 * and call dbg_evts for runtime dump
+	 * fsm_run for the fsm instance, injecting each evt_id in order
 	 *
 	 * This is an infinite loop, either ^C (SIGINT) or
-	 * E_DONE event will cause the FSM to call pthread_exit
+	 * E_DONE event will cause the FSM to call worker_exit
 	 */
 	while (true)
 	{
-		evtq_dequeue(self_p->evtq_p, &evt_id);
-		dbg_evts(evt_id);
-		fsm_run(self_p->fsm_p, evt_id);
+		batch.n = evtq_dequeue_batch(self_p->evtq_p, batch.evts, ages, batch.data,
+					     EVTQ_BATCH, idle_ms);
+		if (0 == batch.n && self_p->idle_fn)
+			self_p->idle_fn(self_p);
+		for (i = 0; i < batch.n; i++) {
+			batch.next = i + 1;
+			fsm_task_evt(self_p, batch.evts[i], ages[i], batch.data[i]);
+		}
 	}
+	pthread_cleanup_pop(1);
+	pthread_cleanup_pop(1);
 	
 	dbg("exitting...");
 }
 
+/* where worker_exit returns to in single_run */
+static jmp_buf single_exit;
+
+/**
+ * single_exit_fn - stop a worker of the single-threaded executor
+ * @w_p: the worker running S:DONE
+ */
+static void single_exit_fn(worker_t *w_p)
+{
+	longjmp(single_exit, 1);
+}
+
+/**
+ * single_run - single-threaded executor, run all pending worker events
+ *
+ * The FSM workers have no thread, the events are run on the calling
+ * (CLI) thread in a fixed order: one event from each worker in worker
+ * list order, each run to completion, until all the queues are empty.
+ * The timers are run by timer_advance on the sim clock, so the whole run
+ * is reproducible.  The idle hook is not used, a worker never waits.
+ */
+static void single_run(void)
+{
+	worker_t *w_p;
+	fsm_events_t evt_id;
+	uint32_t age, data;
+	bool busy;
+
+	do {
+		busy = false;
+		nl_list_for_each_entry(w_p, &workers.head.list, list) {
+			if (NULL == w_p->fsm_p || w_p->down || 0 == evtq_len(w_p->evtq_p))
+				continue;
+			evtq_dequeue_batch(w_p->evtq_p, &evt_id, &age, &data, 1, 0);
+			worker_cur = w_p;
+			if (0 == setjmp(single_exit))
+				fsm_task_evt(w_p, evt_id, age, data);
+			else
+				worker_down(w_p);
+			worker_cur = NULL;
+			busy = true;
+		}
+	} while (busy);
+}
+
+/**
+ * single_init - set up a worker for the single-threaded executor
+ * @w_p: the worker, created without a thread
+ *
+ * What fsm_task does before its loop.
+ */
+static void single_init(worker_t *w_p)
+{
+	worker_cur = w_p;
+	fsm_init(w_p->fsm_p);
+	worker_publish(w_p, E_INIT);
+	worker_cur = NULL;
+	w_p->ready = true;
+}
+
+/**
+ * crosswalk_spawn - add a crosswalk at runtime, see the CLI i command
+ * @name: unique worker name
+ *
+ * Each crosswalk runs its own copy of FSM2 and, like the first one,
+ * subscribes to the stoplight lights and joins the intersection.  It is
+ * started at once, the stoplight does not wait for it.
+ *
+ * Return: the worker, NULL if the name is in use, there are too many
+ * workers or out of memory
+ */
+static worker_t *crosswalk_spawn(const char *name)
+{
+	fsm_trans_t *fsm_p;
+	worker_t *w_p;
+
+	if (worker_find_by_name(name)) {
+		printf("%s: worker exists\n", name);
+		return(NULL);
+	}
+	if (workers_full()) {
+		printf("%s: more than %d workers\n", name, WORKERS_MAX);
+		return(NULL);
+	}
+	if (NULL == (fsm_p = fsm_clone(FSM2, &s_init)))
+		return(NULL);
+
+	w_p = worker_fsm_create(single ? NULL : &fsm_task, (char *)name, fsm_p);
+	w_p->fsm_owned = true;
+	w_p->idle_fn = fsm_idle;
+	unhandled_set(w_p);
+	w_p->stale_ms = stale_ms;
+	w_p->stale_policy = STALE_LOG;
+	worker_join(w_p, "intersection1");
+	worker_join(w_p, TOPIC_TRAFFIC);
+	worker_join(w_p, GROUP_CROSSWALKS);
+	if (single)
+		single_init(w_p);
+	/* only the CLI adds a worker at runtime, the check above holds */
+	if (worker_list_add(w_p))
+		die("worker_list_add");
+	worker_start(w_p);
+	return(w_p);
+}
+
 /**
  * main - a simple driver for an event producer/consumer framework (MGMT)
  *
@@ -184,17 +796,32 @@ void *fsm_task(void *arg)
  * - create a worker list
  * - create the worker pthread(s) and add to worker list
  * - call the evt_script | evt_producer function from the main thread
+ * - stop the workload and the timers
+ * - drain the worker queues, stop and join the workers (workers_shutdown)
  * - cancel timer service
- * - wait for consumer thread to terminate
  * - destroy event_queue for the consumer
  */
 int main(int argc, char *argv[])
 {
 	int parsed_args;
+	int exit_code = EXIT_SUCCESS;
+	int shutdown_errors;
 	pthread_t timer_service;
+	pthread_t workload;
+	worker_t *stoplight_p, *crosswalk_p;
+	struct crosswalk_ctx crosswalk_ctx = {0};
 
 	parsed_args = cmdline_args(argc, argv);
 
+	/* workload arrivals are not ordered against the timer expiries */
+	if (clock_is_sim() && workloadfile[0]) {
+		printf("-w cannot be used with -v\n");
+		exit(EXIT_CLI);
+	}
+
+	/* origin of all timestamps */
+	ts_anchor(debug_flag != DBG_NONE);
+
 	/* show commandline arguments that are NOT handled
 	 * by cmdline_args.
 	 */
@@ -205,26 +832,149 @@ int main(int argc, char *argv[])
 	/* all threads in process use this */
 	set_sig_handlers();
 
-	/* create timer service and start it running */
-	if (0 != pthread_create(&timer_service, NULL, timer_service_fn, NULL))
+	/* fail fast on FSM definition errors */
+	if (fsm_validate(FSM1, "stoplight") + fsm_validate(FSM2, "crosswalk") +
+	    (shadow ? fsm_validate(FSM1_SHADOW, "stoplight shadow") : 0))
+		exit(EXIT_VALIDATION);
+
+	/* print the random walk scripts instead of running the FSMs */
+	if (walk_steps) {
+		srandom(1);
+		printf("g n1\n");
+		fsm_walk(FSM1, "stoplight", walk_steps, 1);
+		fsm_walk(FSM2, "crosswalk", walk_steps, 1);
+		printf("x\n");
+		return(EXIT_SUCCESS);
+	}
+
+	/* index the tables so an event lookup does not scan them */
+	if (fsm_index_build(FSM1) || fsm_index_build(FSM2) ||
+	    (shadow && fsm_index_build(FSM1_SHADOW)))
+		die("fsm_index_build");
+
+	/* create timer service and start it running, the single-threaded
+	 * executor runs the timers from timer_advance
+	 */
+	timer_init();
+	if (!single && 0 != thread_create(&timer_service, timer_service_fn, NULL))
 		die("timer_service create");
 
 	worker_list_create();
-	worker_list_add(worker_fsm_create(&fsm_task, "stoplight", FSM1));
-	worker_list_add(worker_fsm_create(&fsm_task, "crosswalk", FSM2));
+	workers_trace = getenv("FSM_TRACE");
+	workers_spawn_fn = crosswalk_spawn;
+	cli_mode_max = MODE_SCHOOL;
+	if (single) {
+		workers_run_fn = single_run;
+		worker_exit_fn = single_exit_fn;
+		stoplight_p = worker_fsm_create(NULL, "stoplight", FSM1);
+		crosswalk_p = worker_fsm_create(NULL, "crosswalk", FSM2);
+	} else {
+		stoplight_p = worker_fsm_create(&fsm_task, "stoplight", FSM1);
+		crosswalk_p = worker_fsm_create(&fsm_task, "crosswalk", FSM2);
+	}
+	stoplight_p->idle_fn = fsm_idle;
+	crosswalk_p->idle_fn = fsm_idle;
+	crosswalk_p->ctx = &crosswalk_ctx;
+	unhandled_set(stoplight_p);
+	unhandled_set(crosswalk_p);
+
+	/* only report stale events, dropping a late E_LIGHT would leave
+	 * the stoplight waiting on a timer that is never set again
+	 */
+	stoplight_p->stale_ms = crosswalk_p->stale_ms = stale_ms;
+	stoplight_p->stale_policy = crosswalk_p->stale_policy = STALE_LOG;
+	if (shadow)
+		worker_shadow(stoplight_p, FSM1_SHADOW);
+	if (reference)
+		worker_reference(crosswalk_p, crosswalk_model);
+	if (single) {
+		single_init(stoplight_p);
+		single_init(crosswalk_p);
+	}
+	if (worker_list_add(stoplight_p) || worker_list_add(crosswalk_p))
+		die("worker_list_add");
+
+	/* the CLI multicasts to the intersection, e.g. e10:intersection1/ */
+	worker_join(stoplight_p, "intersection1");
+	worker_join(crosswalk_p, "intersection1");
+	worker_join(crosswalk_p, TOPIC_TRAFFIC);
+	worker_join(crosswalk_p, GROUP_CROSSWALKS);
+
+	if (stoplight_peer) {
+		static const char *peers[] = {TOPIC_TRAFFIC "/", NULL, NULL};
+
+		peers[1] = stoplight_peer;
+		worker_peers(stoplight_p, peers);
+	} else {
+		worker_peers(stoplight_p, stoplight_peers);
+	}
+	if (workers_peers_check())
+		exit(EXIT_VALIDATION);
+
+	/* crosswalk must be in S:DONT_WALK before the stoplight cycles */
+	worker_start_after(stoplight_p, crosswalk_p);
+
+	if (observe && fsm_observe(NULL, fsm_observer, NULL))
+		die("fsm_observe");
+
+	/* inject startup commands now that all workers are registered */
+	if (initcmds[0])
+		evt_parse_buf(initcmds);
+
+	/* start the synthetic event workload */
+	if (workloadfile[0]) {
+		if (workload_load(workloadfile) < 0)
+			exit(EXIT_CLI);
+		if (0 != thread_create(&workload, workload_fn, NULL))
+			die("workload create");
+	}
 
 	/* loop until 'x' entered */
-	non_interactive ? evt_script() : evt_producer();
+	if (non_interactive)
+		evt_script();
+	else
+		evt_producer();
+
+	/* shutdown: stop the event sources, then the workers */
+	if (workloadfile[0]) {
+		pthread_cancel(workload);
+		pthread_join(workload, NULL);
+	}
+	timer_stop_all();
 
-	/* cancel timer_service thread */
-	dbg("cancel timer_service and join");
-	pthread_cancel(timer_service);
-	pthread_join(timer_service, NULL);
-	
 	dbg("waiting for worker joins");
-	join_workers();
+	if ((shutdown_errors = workers_shutdown()))
+		printf("shutdown: %d errors\n", shutdown_errors);
+	if (observe)
+		fsm_unobserve(fsm_observer, NULL);
+
+	/* cancel timer_service thread */
+	if (!single) {
+		dbg("cancel timer_service and join");
+		pthread_cancel(timer_service);
+		pthread_join(timer_service, NULL);
+	}
+	if (debug_flag & DBG_WORKER)
+		printf("%s: %u walks\n", crosswalk_p->name, crosswalk_ctx.walks);
+	if (evtq_dead_count())
+		show_dead();
+
+	if (csvprefix[0] && export_csv(csvprefix))
+		printf("CSV export to %s failed\n", csvprefix);
+	if (svgfile[0] && export_svg(svgfile))
+		printf("SVG export to %s failed\n", svgfile);
+
+	if (__atomic_load_n(&fsm_violations, __ATOMIC_RELAXED))
+		exit_code = EXIT_VIOLATION;
+	else if (cli_errors)
+		exit_code = EXIT_CLI;
+	else if (shutdown_errors)
+		exit_code = EXIT_FAILURE;
+	if (jsonfile[0] && export_json(jsonfile, exit_code))
+		printf("JSON export to %s failed\n", jsonfile);
 	workers_evtq_destroy();
 
 	dbg("exitting...\n");
+	return(exit_code);
 }
 
@@ -28,16 +28,32 @@ static inline void dbg_timer(fsm_events_t evt_id, const char *msg)
 	if (!(debug_flag&DBG_TIMERS))
 		return;
 
-	clock_gettime(CLOCK_MONOTONIC, &ts);
-	len=snprintf(buf, sizeof(buf), "%s:%ld.%09ld %s\n",
+	clock_now(&ts);
+	len=snprintf(buf, sizeof(buf), "%s:%.3f %s\n",
 		     evt_name[evt_id],
-		     ts.tv_sec, ts.tv_nsec,
+		     ts_ms(&ts) / 1e3,
 		     msg
 		);
 
 	write(1, buf, strlen(buf));
 }
 
+/**
+ * dbg_timer_json - with dbg_json, trace a timer event sent to the FSMs
+ * @timer_p: the timer
+ * @cause: "expire" or "fire" (a kick, see fire_timer)
+ *
+ * Part of the DBG_TRANS trace so a JSON trace shows what drove each
+ * transition.
+ */
+static inline void dbg_timer_json(fsmtimer_t *timer_p, const char *cause)
+{
+	if (!dbg_json || !(debug_flag & DBG_TRANS))
+		return;
+	dbg_json_line("timer", "timer", timer_p->name,
+		      "event", evt_name[timer_p->evtid], "cause", cause, NULL);
+}
+
 /**
  * find_timer_by_id - 
  *
@@ -81,39 +97,105 @@ fsmtimer_t *find_timer_by_pollfd(int pollfd)
 	return(found_p);
 }
 
+/**
+ * find_timer_by_name - 
+ *
+ * @name: the timer name given to create_timer
+ *
+ * Return: a pointer to the fsmtimer_t
+ */
+fsmtimer_t *find_timer_by_name(const char *name)
+{
+	fsmtimer_t* timer_p;
+	fsmtimer_t *found_p = NULL;	
+	pthread_mutex_lock(&timer_list.mutex);
+	nl_list_for_each_entry(timer_p, &timer_list.head.list, list) {
+		if (0 == strncmp(name, timer_p->name, sizeof(timer_p->name))) {
+			found_p = timer_p;
+		}
+	}
+	pthread_mutex_unlock(&timer_list.mutex);
+	return(found_p);
+}
+
+/**
+ * timer_left - msecs to the next expiry of a timer
+ * @timer_p: the timer
+ *
+ * Return: the msecs, 0 if the timer is disarmed
+ */
+static uint64_t timer_left(fsmtimer_t *timer_p)
+{
+	struct itimerspec ts;
+	uint64_t now_ms;
+
+	if (clock_is_sim()) {
+		now_ms = sim_clock_ms();
+		return(timer_p->deadline_ms > now_ms ? timer_p->deadline_ms - now_ms : 0);
+	}
+
+	if (-1 == timerfd_gettime(timer_p->fd, &ts))
+		die("timerfd_gettime");
+	return(ts.it_value.tv_sec * 1000L + ts.it_value.tv_nsec / 1000000L);
+}
+
+/**
+ * show_timers - show all timers, their period and time to next expiry
+ */
 void show_timers(void)
 {
 	fsmtimer_t* timer_p;
-	printf("timers\n%-2s:%-2s %-18s %-9s\n", "id", "fd", "event name", "msec val");
+
+	printf("timers\n%-2s:%-2s %-12s %-18s %-9s %-9s\n", "id", "fd", "name",
+	       "event name", "msec val", "msec left");
 	pthread_mutex_lock(&timer_list.mutex);
 	nl_list_for_each_entry(timer_p, &timer_list.head.list, list) {
-		printf("%2u:%2d evt=%14s msec=%5lu\n", timer_p->timerid,
+		printf("%2u:%2d %-12s evt=%14s msec=%5lu left=%5lu%s", timer_p->timerid,
 		       timer_p->fd,
+		       timer_p->name,
 		       evt_name[timer_p->evtid],
-		       timer_p->tick_ms);
+		       timer_p->tick_ms,
+		       timer_left(timer_p),
+		       timer_p->periodic ? "" : " once");
+		if (timer_p->jitter_ms)
+			printf(" jitter=%u", timer_p->jitter_ms);
+		if (timer_p->group)
+			printf(" to %s/", timer_p->group);
+		printf("%s\n", timer_p->evtq_p ? " routed" : "");
 	}
 	pthread_mutex_unlock(&timer_list.mutex);
 }
 
 /**
  * create_timer
+ * @timerid: unique timer id
+ * @evtid: event broadcast on expiry
+ * @name: unique timer name, used by the CLI and show_timers
  *
  * but don't start it
  */
-int create_timer(uint32_t timerid, fsm_events_t evtid)
+int create_timer(uint32_t timerid, fsm_events_t evtid, const char *name)
 {
-	fsmtimer_t *timer_p = malloc(sizeof(fsmtimer_t));
+	fsmtimer_t *timer_p = fsm_malloc(sizeof(fsmtimer_t));
 	struct epoll_event event;     /* struct to add to the epoll list */
 
-	if (NULL != find_timer_by_id(timerid))
+	if (NULL != find_timer_by_id(timerid) || NULL != find_timer_by_name(name))
 		die("timer exists");
 
-	if (-1 == (timer_p->fd=timerfd_create(CLOCK_MONOTONIC, 0)))
+	/* non-blocking, the expiry may be cleared by cancel_timer */
+	if (-1 == (timer_p->fd=timerfd_create(CLOCK_MONOTONIC, TFD_NONBLOCK)))
 		die("timerfd_create");
 
 	timer_p->timerid = timerid;
+	strncpy(timer_p->name, name, sizeof(timer_p->name)-1);
+	timer_p->name[sizeof(timer_p->name)-1] = '\0';
 	timer_p->evtid = evtid;
 	timer_p->tick_ms = 0;
+	timer_p->periodic = true;
+	timer_p->evtq_p = NULL;
+	timer_p->group = NULL;
+	timer_p->jitter_ms = 0;
+	timer_p->deadline_ms = 0;
 
 	pthread_mutex_lock(&timer_list.mutex);
 
@@ -131,39 +213,61 @@ int create_timer(uint32_t timerid, fsm_events_t evtid)
 }
 
 /**
- * set_timer - set a timer to a new periodic timeout tick_ms in the future
+ * jittered - next timeout of a jittered timer
+ * @timer_p: the timer
+ * @tick_ms: the period
  *
- * @timer_p: pointer to the timer structure
- * @tick_ms: a periodic trigger value in milliseconds
+ * Return: @tick_ms moved by a uniform random offset within the timer
+ * jitter, at least 1 msec
+ */
+static uint64_t jittered(fsmtimer_t *timer_p, uint64_t tick_ms)
+{
+	int64_t ms;
+
+	if (0 == timer_p->jitter_ms || 0 == tick_ms)
+		return(tick_ms);
+	ms = (int64_t)tick_ms - timer_p->jitter_ms +
+		rand_r(&timer_p->seed) % (2 * timer_p->jitter_ms + 1);
+	return(ms > 0 ? ms : 1);
+}
+
+/**
+ * arm_timer - set a timer to a new timeout tick_ms in the future
  *
- * Return: the tick_ms
+ * @timer_p: pointer to the timer structure
+ * @tick_ms: the timeout in milliseconds
+ * @periodic: expire every tick_ms, otherwise only once
  *
- * This is called from the timer thread to set, reset, cancel
- * a timer.  If ms == 0, the timer is cancelled.  If a running 
- * timer is being set, the future timeout is reset to this value.
+ * If ms == 0, the timer is cancelled.  If a running timer is being set,
+ * the future timeout is reset to this value.
  *
  * The current tick_ms is saved to be used by the toggle function 
- * below.
+ * below.  Called with the timer list mutex held, so the timer service
+ * never broadcasts the expiry of a timer that was just cancelled.
+ *
+ * A periodic timer with jitter is armed one period at a time, the timer
+ * service re-arms it on each expiry with a new random period.
+ *
+ * With the sim clock the timerfd is not used, the expiry is kept in
+ * deadline_ms and run by timer_advance.
  */
-int set_timer_p(fsmtimer_t *timer_p, uint64_t tick_ms)
+static void arm_timer(fsmtimer_t *timer_p, uint64_t tick_ms, bool periodic)
 {
 	struct itimerspec ts;
 	time_t sec;
 	long nsec;
 
-	if (NULL == timer_p)
-		die("set_timer unknown timer");
-
 	if (debug_flag & DBG_TIMERS) {
-		printf("%d:%s set to %lu msecs\n",
+		printf("%d:%s set to %lu msecs%s\n",
 		       timer_p->timerid,
 		       evt_name[timer_p->evtid],
-		       tick_ms);
+		       tick_ms, periodic ? "" : " once");
 	}
 
 	/* save current tick before updating, used by toggle function */
 	timer_p->old_tick_ms = timer_p->tick_ms;
 	timer_p->tick_ms = tick_ms;
+	timer_p->periodic = periodic;
 	if (debug_flag & DBG_TIMERS)
 		printf("%d: old=%ld tick=%ld\n", timer_p->timerid,
 		       timer_p->old_tick_ms, timer_p->tick_ms);
@@ -174,15 +278,39 @@ int set_timer_p(fsmtimer_t *timer_p, uint64_t tick_ms)
 	sec = tick_ms ? (tick_ms/1000) : 0;
 	nsec = tick_ms ? (tick_ms%1000)*1e6 : 0;
 
-	ts.it_value.tv_sec = sec;
-	ts.it_value.tv_nsec = nsec;
-	ts.it_interval.tv_sec = sec;
-	ts.it_interval.tv_nsec = nsec;
+	if (clock_is_sim()) {
+		timer_p->deadline_ms = tick_ms ?
+			sim_clock_ms() + jittered(timer_p, tick_ms) : 0;
+		return;
+	}
+
+	ts.it_interval.tv_sec = (periodic && !timer_p->jitter_ms) ? sec : 0;
+	ts.it_interval.tv_nsec = (periodic && !timer_p->jitter_ms) ? nsec : 0;
+
+	tick_ms = jittered(timer_p, tick_ms);
+	ts.it_value.tv_sec = tick_ms/1000;
+	ts.it_value.tv_nsec = (tick_ms%1000)*1e6;
 
 	if (-1 == timerfd_settime(timer_p->fd, 0, &ts, NULL))
 		die("set_timer");
+}
 
+/**
+ * set_timer_p - set a timer to a new periodic timeout tick_ms in the future
+ *
+ * @timer_p: pointer to the timer structure
+ * @tick_ms: a periodic trigger value in milliseconds, 0 to cancel
+ *
+ * Return: 0
+ */
+int set_timer_p(fsmtimer_t *timer_p, uint64_t tick_ms)
+{
+	if (NULL == timer_p)
+		die("set_timer unknown timer");
 
+	pthread_mutex_lock(&timer_list.mutex);
+	arm_timer(timer_p, tick_ms, true);
+	pthread_mutex_unlock(&timer_list.mutex);
 	return(0);
 }
 
@@ -199,7 +327,233 @@ int set_timer(uint32_t timerid, uint64_t tick_ms)
 
 	if (NULL == timer_p)
 		die("stop_timer unknown timer");
-	set_timer_p(timer_p, tick_ms);
+	return(set_timer_p(timer_p, tick_ms));
+}
+
+/**
+ * start_timer - arm a timer for a new event
+ * @timerid: unique timerid in timer list
+ * @tick_ms: timeout in milliseconds
+ * @evtid: event broadcast on expiry, replaces the create_timer event
+ * @periodic: expire every tick_ms, otherwise only once
+ *
+ * A one-shot timer is disarmed (tick_ms 0) after it expires, see
+ * timer_service_fn.
+ *
+ * Return: 0 on success, -1 if the timer is unknown
+ */
+int start_timer(uint32_t timerid, uint64_t tick_ms, fsm_events_t evtid, bool periodic)
+{
+	fsmtimer_t *timer_p = find_timer_by_id(timerid);
+
+	if (NULL == timer_p) {
+		printf("%s: unknown timer %d\n", __func__, timerid);
+		return (-1);
+	}
+
+	pthread_mutex_lock(&timer_list.mutex);
+	timer_p->evtid = evtid;
+	arm_timer(timer_p, tick_ms, periodic);
+	pthread_mutex_unlock(&timer_list.mutex);
+	dbg_timer(evtid, periodic ? "timer start" : "timer start once");
+	return(0);
+}
+
+/**
+ * cancel_timer - disarm a timer and discard a pending expiry
+ * @timerid: unique timerid in timer list
+ *
+ * Once this returns the timer service will not broadcast the timer
+ * event, even if the timer expired while it was being cancelled.  An
+ * event already broadcast is still on the worker queues.
+ *
+ * Return: 0 on success, -1 if the timer is unknown
+ */
+int cancel_timer(uint32_t timerid)
+{
+	fsmtimer_t *timer_p = find_timer_by_id(timerid);
+	uint64_t res;
+
+	if (NULL == timer_p) {
+		printf("%s: unknown timer %d\n", __func__, timerid);
+		return (-1);
+	}
+
+	pthread_mutex_lock(&timer_list.mutex);
+	arm_timer(timer_p, 0, timer_p->periodic);
+	/* clear an expiry count not yet read by the timer service */
+	read(timer_p->fd, &res, sizeof(res));
+	pthread_mutex_unlock(&timer_list.mutex);
+	dbg_timer(timer_p->evtid, "timer cancel");
+	return(0);
+}
+
+/**
+ * stop_timer - stop the timer from generating periodic timeouts
+ * @timerid: unique timerid in timer list
+ *
+ * The current period is saved so toggle_timer can restore it, stopping a
+ * stopped timer keeps the period saved by the first stop.
+ */
+int stop_timer(uint32_t timerid)
+{
+	fsmtimer_t *timer_p = find_timer_by_id(timerid);
+	uint64_t period;
+
+	if (NULL == timer_p) {
+		printf("%s: unknown timer %d\n", __func__, timerid);
+		return (-1);
+	}
+
+	dbg_timer(timer_p->evtid, "timer stop");
+	pthread_mutex_lock(&timer_list.mutex);
+	period = timer_p->tick_ms ? timer_p->tick_ms : timer_p->old_tick_ms;
+	arm_timer(timer_p, 0, timer_p->periodic);
+	timer_p->old_tick_ms = period;
+	pthread_mutex_unlock(&timer_list.mutex);
+	return(0);
+}
+
+/**
+ * route_timer - send the timer event to one queue instead of broadcasting
+ * @timerid: unique timerid in timer list
+ * @evtq_p: the queue, typically the event queue of the FSM worker using
+ *   the timer, NULL to broadcast to all workers
+ *
+ * Return: 0 on success, -1 if the timer is unknown
+ */
+int route_timer(uint32_t timerid, evtq_t *evtq_p)
+{
+	fsmtimer_t *timer_p = find_timer_by_id(timerid);
+
+	if (NULL == timer_p) {
+		printf("%s: unknown timer %d\n", __func__, timerid);
+		return (-1);
+	}
+
+	pthread_mutex_lock(&timer_list.mutex);
+	timer_p->evtq_p = evtq_p;
+	timer_p->group = NULL;
+	pthread_mutex_unlock(&timer_list.mutex);
+	return(0);
+}
+
+/**
+ * unroute_timers - disarm the timers routed to a queue
+ * @evtq_p: the queue, of a worker torn down
+ *
+ * The timers no longer have a consumer, they are disarmed and broadcast
+ * again until the next route_timer.
+ */
+void unroute_timers(evtq_t *evtq_p)
+{
+	fsmtimer_t *timer_p;
+	uint64_t res;
+
+	pthread_mutex_lock(&timer_list.mutex);
+	nl_list_for_each_entry(timer_p, &timer_list.head.list, list) {
+		if (timer_p->evtq_p != evtq_p)
+			continue;
+		if (timer_p->tick_ms)
+			arm_timer(timer_p, 0, timer_p->periodic);
+		read(timer_p->fd, &res, sizeof(res));
+		timer_p->evtq_p = NULL;
+	}
+	pthread_mutex_unlock(&timer_list.mutex);
+}
+
+/**
+ * route_timer_group - send the timer event to a group instead of broadcasting
+ * @timerid: unique timerid in timer list
+ * @group: group name, without the trailing '/', must stay allocated, NULL
+ *   to broadcast to all workers
+ *
+ * The event reaches the workers in the group when the timer expires, so
+ * a worker that joins later or is torn down is handled, unlike a queue
+ * set with route_timer.  See workers_group_broadcast.
+ *
+ * Return: 0 on success, -1 if the timer is unknown
+ */
+int route_timer_group(uint32_t timerid, const char *group)
+{
+	fsmtimer_t *timer_p = find_timer_by_id(timerid);
+
+	if (NULL == timer_p) {
+		printf("%s: unknown timer %d\n", __func__, timerid);
+		return (-1);
+	}
+
+	pthread_mutex_lock(&timer_list.mutex);
+	timer_p->evtq_p = NULL;
+	timer_p->group = group;
+	pthread_mutex_unlock(&timer_list.mutex);
+	return(0);
+}
+
+/**
+ * jitter_timer - randomize the timeouts of a timer
+ * @timerid: unique timerid in timer list
+ * @jitter_ms: max msecs added to or removed from each timeout (each
+ *   period of a periodic timer), 0 for none
+ *
+ * Jitter spreads out timers with the same period (e.g. many FSMs with
+ * the same cadence) instead of expiring in lockstep.  The random
+ * sequence is seeded from the timer id so a run is repeatable.  Applied
+ * from the next set_timer or start_timer.
+ *
+ * Return: 0 on success, -1 if the timer is unknown
+ */
+int jitter_timer(uint32_t timerid, uint32_t jitter_ms)
+{
+	fsmtimer_t *timer_p = find_timer_by_id(timerid);
+
+	if (NULL == timer_p) {
+		printf("%s: unknown timer %d\n", __func__, timerid);
+		return (-1);
+	}
+
+	pthread_mutex_lock(&timer_list.mutex);
+	timer_p->jitter_ms = jitter_ms;
+	timer_p->seed = timerid + 1;
+	pthread_mutex_unlock(&timer_list.mutex);
+	return(0);
+}
+
+/**
+ * timer_stop_all - disarm every timer
+ *
+ * Used on shutdown so no timer event arrives while the workers drain
+ * their queues and stop, see workers_shutdown.  Like cancel_timer, an
+ * expiry the timer service has not read yet is discarded.
+ */
+void timer_stop_all(void)
+{
+	fsmtimer_t *timer_p;
+	uint64_t res;
+
+	pthread_mutex_lock(&timer_list.mutex);
+	nl_list_for_each_entry(timer_p, &timer_list.head.list, list) {
+		if (timer_p->tick_ms)
+			arm_timer(timer_p, 0, timer_p->periodic);
+		/* as in cancel_timer, discard an expiry not yet read */
+		read(timer_p->fd, &res, sizeof(res));
+	}
+	pthread_mutex_unlock(&timer_list.mutex);
+}
+
+/**
+ * timer_send - deliver a timer event to its queue, its group or to all
+ *   workers
+ * @timer_p: the timer
+ */
+static void timer_send(fsmtimer_t *timer_p)
+{
+	if (timer_p->evtq_p)
+		evtq_enqueue(timer_p->evtq_p, timer_p->evtid);
+	else if (timer_p->group)
+		workers_group_broadcast(timer_p->group, timer_p->evtid);
+	else
+		workers_evt_broadcast(timer_p->evtid);
 }
 
 /**
@@ -209,17 +563,12 @@ int set_timer(uint32_t timerid, uint64_t tick_ms)
 uint64_t get_timer(uint32_t timerid)
 {
 	fsmtimer_t *timer_p = find_timer_by_id(timerid);
-	struct itimerspec ts;
 	uint64_t msec;
 	
 	if (NULL == timer_p)
 		die("get_timer unknown timer");
 	
-	if (-1 == timerfd_gettime(timer_p->fd,&ts))
-		die("get_timer");
-
-	/* convert timerfd to msec */	
-	msec = ts.it_value.tv_sec * 1000L + ts.it_value.tv_nsec / 1e6;
+	msec = timer_left(timer_p);
 
 	if (debug_flag & DBG_TIMERS) {
 		printf("%d: remaining msec=%ld\n", timerid, msec);
@@ -240,17 +589,120 @@ int toggle_timer(uint32_t timerid)
 		return (-1);
 	}
 	
+	pthread_mutex_lock(&timer_list.mutex);
 	if (0 != timer_p->tick_ms) {
 		dbg_timer(timer_p->timerid, "timer off");
-		set_timer_p(timer_p, 0);
+		arm_timer(timer_p, 0, timer_p->periodic);
 	} else {
 		dbg_timer(timer_p->timerid, "timer restore");		
-		set_timer_p(timer_p, timer_p->old_tick_ms);
+		arm_timer(timer_p, timer_p->old_tick_ms, timer_p->periodic);
 	}
+	pthread_mutex_unlock(&timer_list.mutex);
 
 	return(0);
 }
 
+/**
+ * fire_timer - deliver a timer event now, as if the timer expired
+ * @timerid: unique timerid in timer list
+ *
+ * Used to hurry an FSM along in demos and tests instead of waiting for
+ * the expiry.  The timer itself is not changed, an FSM transition will
+ * typically reset it in the next state entry action.
+ *
+ * Return: 0 on success, -1 if the timer is unknown
+ */
+int fire_timer(uint32_t timerid)
+{
+	fsmtimer_t *timer_p = find_timer_by_id(timerid);
+
+	if (NULL == timer_p) {
+		printf("%s: unknown timer %d\n", __func__, timerid);
+		return (-1);
+	}
+
+	dbg_timer(timer_p->evtid, "fire");
+	dbg_timer_json(timer_p, "fire");
+	timer_send(timer_p);
+	return(0);
+}
+
+/**
+ * timer_expire - handle a timer expiry
+ * @timer_p: the timer
+ *
+ * A one-shot timer is disarmed, a jittered periodic timer is re-armed
+ * for its next random period and the event is sent.  Called with the
+ * timer list mutex held.
+ */
+static void timer_expire(fsmtimer_t *timer_p)
+{
+	dbg_timer(timer_p->evtid, "expire");
+	dbg_timer_json(timer_p, "expire");
+	if (!timer_p->periodic) {
+		timer_p->old_tick_ms = timer_p->tick_ms;
+		timer_p->tick_ms = 0;
+		timer_p->deadline_ms = 0;
+	} else if (timer_p->jitter_ms || clock_is_sim()) {
+		arm_timer(timer_p, timer_p->tick_ms, true);
+	}
+	timer_send(timer_p);
+}
+
+/**
+ * timer_advance - move the sim clock forward, running the timers due
+ * @ms: simulated msecs
+ *
+ * The timers expire one at a time in deadline order, the clock is set to
+ * each deadline before its event is sent, so a test runs hours of timer
+ * driven FSM time without sleeping.  Timers due at the same time expire
+ * in creation order.  Before each expiry the workers run every pending
+ * event (see workers_settle) so an action setting a timer is always done
+ * before the clock moves on.
+ */
+void timer_advance(uint32_t ms)
+{
+	uint64_t until = sim_clock_ms() + ms;
+	fsmtimer_t *timer_p, *next_p;
+
+	while (true) {
+		workers_settle();
+		pthread_mutex_lock(&timer_list.mutex);
+		next_p = NULL;
+		nl_list_for_each_entry(timer_p, &timer_list.head.list, list) {
+			if (timer_p->deadline_ms && timer_p->deadline_ms <= until &&
+			    (NULL == next_p || timer_p->deadline_ms < next_p->deadline_ms))
+				next_p = timer_p;
+		}
+		if (next_p) {
+			sim_clock_set(next_p->deadline_ms);
+			timer_expire(next_p);
+		}
+		pthread_mutex_unlock(&timer_list.mutex);
+		if (NULL == next_p)
+			break;
+	}
+	sim_clock_set(until);
+	workers_settle();
+}
+
+/**
+ * timer_init - init the timer list and the timer service poll list
+ *
+ * Must be called before the timer service thread is started and before
+ * any create_timer.  The timers can be used without the thread on the
+ * sim clock, see timer_advance.
+ */
+void timer_init(void)
+{
+	pthread_mutex_init(&timer_list.mutex, NULL);
+	NL_INIT_LIST_HEAD(&timer_list.head.list);
+
+	/* create epoll */
+	if (-1 == (fd_epoll=epoll_create1(0)))
+		die("epoll");
+}
+
 /**
  * timer_service_fn - pthread generating timer events to consumer
  * @arg: event queue array created by controlling thread
@@ -266,14 +718,6 @@ void *timer_service_fn(void *arg)
 	struct epoll_event events[MAX_TIMERS];
 	uint64_t res;
 
-	/* init the timer list */
-	pthread_mutex_init(&timer_list.mutex, NULL);
-	NL_INIT_LIST_HEAD(&timer_list.head.list);
-
-	/* create epoll */
-	if (-1 == (fd_epoll=epoll_create1(0)))
-		die("epoll");
-	
 	if (0 != pthread_setcancelstate(PTHREAD_CANCEL_ENABLE, NULL))
 		die("pthread_setcancelstate");
 	
@@ -299,21 +743,33 @@ void *timer_service_fn(void *arg)
 		default:
 		{
 			int i;
-			fsmtimer_t *timer_p;
+			fsmtimer_t *timer_p, *found_p;
 			
 			for (i=0; i<nfds; i++) {
 				/* bad event or corrupted file descriptor */
 				if (!(events[i].events&EPOLLIN))
 					die("bad incoming event");
 
-				timer_p = find_timer_by_pollfd(events[i].data.fd);
-				if (timer_p) {
-					read(events[i].data.fd, &res, sizeof(res));	
-					dbg_timer(timer_p->evtid, "expire");
-					workers_evt_broadcast(timer_p->evtid);
-				} else {
-					die("unknown timer in poll list");
+				/* hold the list mutex until the event is
+				 * broadcast, see cancel_timer.  The read is a
+				 * cancellation point, the thread must not be
+				 * cancelled holding the mutex.
+				 */
+				pthread_setcancelstate(PTHREAD_CANCEL_DISABLE, NULL);
+				pthread_mutex_lock(&timer_list.mutex);
+				found_p = NULL;
+				nl_list_for_each_entry(timer_p, &timer_list.head.list, list) {
+					if (events[i].data.fd == timer_p->fd)
+						found_p = timer_p;
 				}
+				if (NULL == found_p)
+					die("unknown timer in poll list");
+
+				/* nothing to read if cancelled since the poll */
+				if (sizeof(res) == read(found_p->fd, &res, sizeof(res)))
+					timer_expire(found_p);
+				pthread_mutex_unlock(&timer_list.mutex);
+				pthread_setcancelstate(PTHREAD_CANCEL_ENABLE, NULL);
 			}
 		}
 		break;
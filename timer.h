@@ -20,12 +20,22 @@
 #include <libnl3/netlink/list.h> /* kernel-ish linked list */
 #include <evtq.h>
 
+/* max timer name length, including null */
+#define TIMER_NAME_LEN 16
+
 typedef struct fsmtimer {
 	struct nl_list_head list;
 	uint32_t timerid;
+	char name[TIMER_NAME_LEN];
 	fsm_events_t evtid;
 	uint64_t tick_ms;
 	uint64_t old_tick_ms;
+	bool periodic;    /* false for a one-shot timer, see start_timer */
+	evtq_t *evtq_p;   /* queue the event is sent to, NULL to broadcast */
+	const char *group; /* group the event is sent to, see route_timer_group */
+	uint32_t jitter_ms; /* max random msecs added to or removed from each period */
+	unsigned int seed;  /* jitter random state */
+	uint64_t deadline_ms; /* sim clock expiry, 0 if disarmed, see timer_advance */
 	int fd;
 } fsmtimer_t;
 
@@ -34,14 +44,25 @@ typedef struct timer_list {
 	pthread_mutex_t mutex;
 } timer_list_t;
 
-extern int create_timer(uint32_t timerid, fsm_events_t evtid);
+extern int create_timer(uint32_t timerid, fsm_events_t evtid, const char *name);
 extern int set_timer(uint32_t timerid, uint64_t tick_ms);
 extern int stop_timer(uint32_t timerid);
+extern int start_timer(uint32_t timerid, uint64_t tick_ms, fsm_events_t evtid, bool periodic);
+extern int cancel_timer(uint32_t timerid);
+extern int route_timer(uint32_t timerid, evtq_t *evtq_p);
+extern int route_timer_group(uint32_t timerid, const char *group);
+extern void unroute_timers(evtq_t *evtq_p);
+extern int jitter_timer(uint32_t timerid, uint32_t jitter_ms);
+extern void timer_advance(uint32_t ms);
 extern uint64_t get_timer(uint32_t timerid);
 extern int toggle_timer(uint32_t timerid);
+extern int fire_timer(uint32_t timerid);
+extern void timer_init(void);
+extern void timer_stop_all(void);
 extern void* timer_service_fn(void *arg);
 extern fsmtimer_t *find_timer_by_id(uint32_t timerid);
 extern fsmtimer_t *find_timer_by_pollfd(int pollfd);
+extern fsmtimer_t *find_timer_by_name(const char *name);
 extern void show_timers(void);
 
 static inline uint64_t get_msec(uint32_t timerid)
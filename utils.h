@@ -8,19 +8,27 @@
  * die: test program will fail so exit with an error
  * nap: sleep for N milliseconds
  * relax: stop running the thread and put it at tail of run queue
+ * thread_create: start a thread that leaves the process signals to main
+ * ts_anchor, ts_ms: run clock for all timestamps, see clock.h
  * dbg: function, timestamp, msg write to stdout
+ * dbg_json_line: timestamped JSON object write to stdout
  */
 
 #ifndef _UTILS_H
 #define _UTILS_H
 
 #include <inttypes.h>    /* include stdint.h, PRI macros, integer conversions */
+#include <stdbool.h>     /* bool type */
+#include <stdarg.h>      /* va_list */
 #include <unistd.h>      /* write */
 #include <stdio.h>       /* snprintf */
 #include <sched.h>       /* sched_yield */
 #include <time.h>        /* nanosleep, clock_gettime */
 #include <string.h>      /* strlen */
+#include <stdlib.h>      /* malloc, calloc */
 #include <pthread.h>     /* pthread_self */
+#include <signal.h>      /* pthread_sigmask */
+#include <clock.h>       /* clock_now */
 
 /**
  * die - terminate task with a descriptive error message
@@ -31,11 +39,43 @@
  */
 #define die(msg) do { perror(msg); exit(EXIT_FAILURE); } while (0)
 
+/*
+ * fsm_allocs - allocations made by the calling thread through fsm_malloc
+ * and fsm_calloc, only counted when fsm_profile is set so the action
+ * profile can show what each transition allocates
+ */
+extern bool fsm_profile;
+extern __thread uint32_t fsm_allocs;
+
+/**
+ * fsm_malloc - malloc counted in fsm_allocs
+ * @size: number of bytes
+ */
+inline static void *fsm_malloc(size_t size)
+{
+	if (fsm_profile)
+		fsm_allocs++;
+	return(malloc(size));
+}
+
+/**
+ * fsm_calloc - calloc counted in fsm_allocs
+ * @nmemb: number of elements
+ * @size: bytes in each element
+ */
+inline static void *fsm_calloc(size_t nmemb, size_t size)
+{
+	if (fsm_profile)
+		fsm_allocs++;
+	return(calloc(nmemb, size));
+}
+
 /**
  * nap - small sleep
  * @ms: number of msecs to nap
  *
- * useful for very small delays, causes thread switch
+ * useful for very small delays, causes thread switch.  Always real
+ * time, see clock_nap for a wait on the run clock.
  */
 inline static void nap(uint32_t ms)
 {
@@ -46,6 +86,90 @@ inline static void nap(uint32_t ms)
 	nanosleep(&t, NULL);
 }
 
+/*
+ * ts_start - run clock time of ts_anchor, the origin of every
+ * timestamp in the debug output and the exported records
+ */
+extern struct timespec ts_start;
+
+/**
+ * ts_anchor - start the run clock
+ * @show: write the wall-clock time of the origin
+ *
+ * Timestamps are run clock (CLOCK_MONOTONIC unless simulated) msecs since
+ * this call, so records from different threads compare and do not jump
+ * with the wall clock (NTP.)  The wall-clock origin is written once to
+ * map them back to real time.
+ */
+inline static void ts_anchor(bool show)
+{
+	struct timespec wall;
+	char buf[32];
+
+	clock_now(&ts_start);
+	clock_gettime(CLOCK_REALTIME, &wall);
+	if (show) {
+		strftime(buf, sizeof(buf), "%F %T", localtime(&wall.tv_sec));
+		printf("ts=0.000 is %s.%03ld%s\n", buf, wall.tv_nsec / 1000000,
+		       clock_is_sim() ? " (sim clock)" : "");
+	}
+}
+
+/**
+ * ts_ms - msecs from ts_start to a run clock time
+ * @ts_p: the timestamp
+ */
+inline static double ts_ms(const struct timespec *ts_p)
+{
+	return((ts_p->tv_sec - ts_start.tv_sec) * 1e3 +
+	       (ts_p->tv_nsec - ts_start.tv_nsec) / 1e6);
+}
+
+/**
+ * sig_cat - append a string to a buffer, async-signal-safe
+ * @buf: the buffer
+ * @size: size of @buf
+ * @len: current length of the text in @buf
+ * @s: the string
+ *
+ * For a signal handler, which cannot use snprintf.  The text is
+ * truncated to fit and is not NUL terminated, write @buf up to the
+ * returned length.
+ *
+ * Return: the new length
+ */
+inline static size_t sig_cat(char *buf, size_t size, size_t len, const char *s)
+{
+	while (*s && len < size)
+		buf[len++] = *s++;
+	return(len);
+}
+
+/**
+ * sig_utoa - append a decimal number to a buffer, async-signal-safe
+ * @buf: the buffer
+ * @size: size of @buf
+ * @len: current length of the text in @buf
+ * @val: the number
+ * @width: minimum number of digits, zero padded
+ *
+ * Return: the new length, see sig_cat
+ */
+inline static size_t sig_utoa(char *buf, size_t size, size_t len, uint64_t val,
+			      uint32_t width)
+{
+	char digits[24];
+	uint32_t n = 0;
+
+	do {
+		digits[n++] = '0' + val % 10;
+		val /= 10;
+	} while (val || n < width);
+	while (n && len < size)
+		buf[len++] = digits[--n];
+	return(len);
+}
+
 /**
  * relax - calling thread is rescheduled so waiting threads can run
  * 
@@ -56,6 +180,34 @@ inline static void relax()
 	sched_yield();
 }
 
+/**
+ * thread_create - start a thread with the process signals blocked
+ * @id_p: the new thread id
+ * @fn: the thread function
+ * @arg: argument of @fn
+ *
+ * SIGINT, SIGTERM and SIGUSR1 are blocked in the new thread so they are
+ * only delivered to the main thread, and a blocking call in a worker or
+ * the timer service (e.g. the timerfd read) is never interrupted.  The
+ * mask of the caller is restored.
+ *
+ * Return: 0 or the pthread_create error
+ */
+inline static int thread_create(pthread_t *id_p, void *(*fn)(void *), void *arg)
+{
+	sigset_t set, old;
+	int ret;
+
+	sigemptyset(&set);
+	sigaddset(&set, SIGINT);
+	sigaddset(&set, SIGTERM);
+	sigaddset(&set, SIGUSR1);
+	pthread_sigmask(SIG_BLOCK, &set, &old);
+	ret = pthread_create(id_p, NULL, fn, arg);
+	pthread_sigmask(SIG_SETMASK, &old, NULL);
+	return(ret);
+}
+
 /* 
  * _dbg_func - dump debug info to stdout
  * @func: calling function
@@ -72,8 +224,8 @@ inline static void _dbg_func(const char *func, const char *msg)
 	char buf[120];
 	int len;
 	
-	clock_gettime(CLOCK_MONOTONIC, &ts);
-	len=snprintf(buf, sizeof(buf), "%lu:%s ts=%ld.%09ld %s\n", pthread_self(), func, ts.tv_sec, ts.tv_nsec, msg);
+	clock_now(&ts);
+	len=snprintf(buf, sizeof(buf), "%lu:%s ts=%.3f %s\n", pthread_self(), func, ts_ms(&ts) / 1e3, msg);
 	/* if cannot fit entire string into buffer, force a newline and null at end */
 	if (len >= sizeof(buf)) {
 		buf[118] = '\n';
@@ -83,17 +235,77 @@ inline static void _dbg_func(const char *func, const char *msg)
 	write(1, buf, strlen(buf));
 }
 
-volatile uint32_t debug_flag;
+extern volatile uint32_t debug_flag;
 #define DBG_NONE    0x00
 #define DBG_TRANS   0x01  
 #define DBG_EVTS    0x02
 #define DBG_TIMERS  0x04
+#define DBG_CHANGE  0x08
 #define DBG_WORKER  0x10
 #define DBG_DEEP    0x20
 
 #define dbg_verbose(msg) if (debug_flag & DBG_DEEP) _dbg_func(__func__, msg)
 #define dbg(msg) _dbg_func(__func__, msg)
 
+/*
+ * dbg_json - write the DBG_TRANS trace (transitions, rejected events and
+ * timer expiries) and the DBG_CHANGE state changes as JSON lines, see
+ * dbg_json_line
+ */
+extern bool dbg_json;
+
+/**
+ * dbg_json_line - write a JSON object on one line to stdout
+ * @type: value of the "type" key
+ * @...: NULL terminated key, value string pairs, a NULL value is null
+ *
+ * The object starts with the run clock "ts" (secs, as the text trace)
+ * and "type", e.g.
+ *  {"ts":1.000,"type":"trans","fsm":"stoplight","from":"S:GREEN",...}
+ * The line is written with a single write so the lines of the threads do
+ * not mix.  A value too long for the line is cut.
+ */
+inline static void dbg_json_line(const char *type, ...)
+{
+	struct timespec ts;
+	char buf[256];
+	const char *key, *val;
+	va_list ap;
+	size_t len;
+
+	clock_now(&ts);
+	len = snprintf(buf, sizeof(buf), "{\"ts\":%.3f,\"type\":\"%s\"",
+		       ts_ms(&ts) / 1e3, type);
+
+	va_start(ap, type);
+	while (len < sizeof(buf) - 40 && (key = va_arg(ap, const char *))) {
+		val = va_arg(ap, const char *);
+		len += snprintf(buf + len, sizeof(buf) - len, ",\"%s\":", key);
+		if (NULL == val) {
+			len += snprintf(buf + len, sizeof(buf) - len, "null");
+			continue;
+		}
+		/* quote the string, escaping what JSON requires */
+		buf[len++] = '"';
+		for (; *val && len < sizeof(buf) - 12; val++) {
+			if ('"' == *val || '\\' == *val) {
+				buf[len++] = '\\';
+				buf[len++] = *val;
+			} else if ((unsigned char)*val < 0x20) {
+				len += snprintf(buf + len, sizeof(buf) - len,
+						"\\u%04x", *val);
+			} else {
+				buf[len++] = *val;
+			}
+		}
+		buf[len++] = '"';
+	}
+	va_end(ap);
+
+	len += snprintf(buf + len, sizeof(buf) - len, "}\n");
+	write(1, buf, len);
+}
+
 #endif /* _UTILS_H */
 
 
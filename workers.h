@@ -13,48 +13,489 @@
 #include <inttypes.h>    /* include stdint.h, PRI macros, integer conversions */
 #include <unistd.h>      /* write */
 #include <stdlib.h>
+#include <errno.h>       /* ETIMEDOUT */
 #include <ctype.h>       /* isalnum */
 #include <stdio.h>
 #include <string.h>
 #include <pthread.h>     /* posix threads */
+#include <sched.h>       /* sched_yield */
 #include <libnl3/netlink/list.h> /* kernel-ish linked list */
 #include <evtq.h>
+#include <timer.h>       /* unroute_timers */
 #include <fsm.h>
 
+/* max msecs worker_start waits for an FSM to leave its init state */
+#define START_WAIT_MS 1000
+
+/* max msecs workers_shutdown waits for the queues to drain and for each
+ * worker to stop
+ */
+#define STOP_WAIT_MS 2000
+
+/* max workers in the lock-free table read by a signal handler */
+#define WORKERS_MAX 64
+
+/**
+ * enum worker_start - where a worker is in its startup
+ * @START_IDLE: not started, or its FSM is back in the init state
+ * @START_PENDING: E_INIT sent, worker_start waits for the FSM to leave the
+ *   init state
+ * @START_DONE: started
+ */
+enum worker_start {
+	START_IDLE,
+	START_PENDING,
+	START_DONE,
+};
+
+/**
+ * enum stale_policy - how a worker handles an event queued too long
+ * @STALE_RUN: run the event
+ * @STALE_LOG: run the event and report it
+ * @STALE_DROP: report and discard the event
+ *
+ * Control events (e.g. E_DONE) are always run.
+ */
+enum stale_policy {
+	STALE_RUN,
+	STALE_LOG,
+	STALE_DROP,
+};
+
+/**
+ * enum unhandled_policy - how a worker handles an event its FSM has no
+ *   transition for in the current state
+ * @UNHANDLED_IGNORE: discard the event silently
+ * @UNHANDLED_LOG: report the event and discard it
+ * @UNHANDLED_ABORT: report the event and abort, writing a crash snapshot
+ * @UNHANDLED_CALL: call the worker @unhandled_fn
+ * @UNHANDLED_DEAD: keep the event in the dead letters, see evtq_dead
+ *
+ * UNHANDLED_LOG and UNHANDLED_ABORT keep the event in the dead letters too.
+ */
+enum unhandled_policy {
+	UNHANDLED_IGNORE,
+	UNHANDLED_LOG,
+	UNHANDLED_ABORT,
+	UNHANDLED_CALL,
+	UNHANDLED_DEAD,
+};
+
+/**
+ * struct worker_snap - worker FSM state seen by the other threads
+ * @currst_p: FSM current state, NULL before the worker is created
+ * @shadowst_p: shadow FSM current state, NULL if no shadow
+ * @evt_id: last event run by the FSM
+ * @shadow_diffs: see worker_t
+ * @ref_diffs: see worker_t
+ *
+ * Only the worker thread runs its FSM, so it owns the live state and
+ * copies it here between events.  A reader never blocks the worker.
+ */
+struct worker_snap {
+	fsm_state_t *currst_p;
+	fsm_state_t *shadowst_p;
+	fsm_events_t evt_id;
+	uint32_t shadow_diffs;
+	uint32_t ref_diffs;
+};
+
+/* max number of groups a worker joins, see worker_join */
+#define WORKER_GROUPS 4
+
+/**
+ * typedef worker - a worker thread and its event queue
+ * @list: kernel-style linked list node
+ * @name: worker name for debugging and lookup
+ * @id: worker number, unique for the run and never reused, assigned by
+ *   worker_list_add
+ * @worker_id: pthread id
+ * @fsm_p: FSM table run by the worker, NULL for a plain consumer
+ * @fsm_owned: @fsm_p is a copy made for the worker (see fsm_clone), freed
+ *   with it on exit
+ * @evtq_p: event queue for the worker
+ * @intq_p: internal events posted by the FSM actions, all run before the
+ *   next @evtq_p event (run to completion), see fsm_post
+ * @initst_p: FSM init state, used to detect when the FSM has started
+ * @depends_p: worker that must be started before this one
+ * @start: where the worker is in its startup, see worker_start
+ * @ready: worker thread is initialized and looping on its event queue
+ * @ready_mutex: mutex guarding @ready and @start
+ * @ready_cond: condition set when @ready or @start changes
+ * @shadow_p: optional modified FSM table run in shadow of @fsm_p
+ * @shadow_diffs: number of events where the shadow state differed
+ * @ref_fn: optional reference model checked against @fsm_p
+ * @ref_st_p: reference model state
+ * @ref_diffs: number of events where the FSM diverged from the model
+ * @evt_cnt: number of each event dequeued by the worker
+ * @snap_seq: odd while @snap is being published, see worker_publish
+ * @snap: FSM state published after each event for the other threads
+ * @phase: where the worker FSM is in a transition, see fsm_phase_get
+ * @last_pub: time of the last DBG_CHANGE state publication
+ * @idle_fn: optional hook called when no event arrives for idle_ms
+ * @stale_ms: an event queued longer than this is stale, 0 for never
+ * @stale_policy: what to do with a stale event
+ * @ctx: optional FSM extended state, see fsm_ctx_get
+ * @unhandled_policy: what to do with an event the FSM does not handle
+ * @unhandled_fn: callback for UNHANDLED_CALL
+ * @evt_data: payload of the event being run, see fsm_evt_data
+ * @wait_ms: total msecs the dequeued events waited on @evtq_p
+ * @peers: optional NULL terminated names of the workers (or groups, with
+ *   a trailing '/') the FSM actions send to, see workers_peers_check
+ * @groups: multicast groups joined with worker_join, besides the group of
+ *   a hierarchical name, see worker_in_group
+ * @down: the worker thread has exited, see worker_down
+ * @retiring: the worker is being torn down, its peers are sent
+ *   E_PEER_DOWN by worker_teardown instead of worker_down, set under the
+ *   write lock
+ * @retired: node of the workers retired list, see worker_teardown
+ * @quiet: the DBG_TRANS and DBG_CHANGE lines of the worker are not
+ *   written, see workers_trace and the CLI v command, atomic since
+ *   the CLI sets it while the worker runs
+ */
 typedef struct worker {
 	struct nl_list_head list;
 	char name[32];
+	uint32_t id;
 	pthread_t worker_id;
 	fsm_trans_t *fsm_p;
+	bool fsm_owned;
 	evtq_t *evtq_p;
+	evtq_t *intq_p;
+	fsm_state_t *initst_p;
+	struct worker *depends_p;
+	enum worker_start start;
+	bool ready;
+	pthread_mutex_t ready_mutex;
+	pthread_cond_t ready_cond;
+	fsm_trans_t *shadow_p;
+	uint32_t shadow_diffs;
+	fsm_model ref_fn;
+	fsm_state_t *ref_st_p;
+	uint32_t ref_diffs;
+	uint32_t evt_cnt[E_LAST];
+	uint32_t snap_seq;
+	struct worker_snap snap;
+	enum fsm_phase phase;
+	struct timespec last_pub;
+	void (*idle_fn)(struct worker *w_p);
+	uint32_t stale_ms;
+	enum stale_policy stale_policy;
+	void *ctx;
+	enum unhandled_policy unhandled_policy;
+	void (*unhandled_fn)(struct worker *w_p, struct fsm_outcome *out_p);
+	uint32_t evt_data;
+	uint64_t wait_ms;
+	const char * const *peers;
+	const char *groups[WORKER_GROUPS];
+	bool down;
+	bool retiring;
+	struct nl_list_head retired;
+	bool quiet;
 } worker_t;
 
+/**
+ * struct workers - the worker list
+ * @head: list head
+ * @start: run clock time the list was created, for throughput
+ * @retired: workers removed by worker_teardown, freed on exit
+ * @lock: guards @head, @last_id and @tab, written by worker_list_add and
+ *   worker_teardown only, see workers_rdlock
+ * @last_id: id of the last worker added, see worker_t
+ * @tab: the live workers, NULL for a free entry.  Each entry is a single
+ *   atomic store so a signal handler (see crash_handler) reads it without
+ *   walking @head.
+ * @pub_busy: number of worker_publish in progress
+ * @pub_gen: number of worker_publish completed, with @pub_busy a sequence
+ *   lock over every worker snapshot, see workers_snapshot
+ */
 typedef struct workers {
 	worker_t head;
+	struct timespec start;
+	struct nl_list_head retired;
+	pthread_rwlock_t lock;
+	uint32_t last_id;
+	worker_t *tab[WORKERS_MAX];
+	uint32_t pub_busy;
+	uint32_t pub_gen;
 } workers_t;
 
-workers_t workers;
+extern workers_t workers;
+
+/**
+ * workers_rdlock - lock the worker list for a walk
+ *
+ * A walk that can run on another thread than the one adding or tearing
+ * down workers (the CLI thread) takes the read lock, e.g. a broadcast
+ * from an FSM action or the timer service.  The readers do not exclude
+ * each other and a nested read lock is allowed.
+ */
+inline static void workers_rdlock(void)
+{
+	pthread_rwlock_rdlock(&workers.lock);
+}
+
+/**
+ * workers_wrlock - lock the worker list to change it
+ *
+ * Never held while waiting on a worker thread, which may need the read
+ * lock to finish.
+ */
+inline static void workers_wrlock(void)
+{
+	pthread_rwlock_wrlock(&workers.lock);
+}
+
+inline static void workers_unlock(void)
+{
+	pthread_rwlock_unlock(&workers.lock);
+}
+
+/*
+ * workers_run_fn - set by a single-threaded executor: run the pending
+ *   events of all the workers on the calling thread, NULL when each
+ *   worker has its own thread
+ * worker_exit_fn - set by a single-threaded executor: stop the worker
+ *   whose event is running, see worker_exit
+ * worker_cur - the worker the single-threaded executor is running, per
+ *   thread so no other thread takes it for its own, see worker_self
+ */
+extern void (*workers_run_fn)(void);
+extern void (*worker_exit_fn)(struct worker *w_p);
+extern __thread worker_t *worker_cur;
+
+/*
+ * workers_spawn_fn - set by the program: create, register and start a
+ *   worker at runtime, see the CLI i command
+ */
+extern worker_t *(*workers_spawn_fn)(const char *name);
+
+/**
+ * enum dup_policy - how a duplicate worker name is handled, see
+ *   worker_name_set
+ * @DUP_ERROR: exit with an error
+ * @DUP_SUFFIX: rename the new worker with a -N suffix
+ * @DUP_ALLOW: register anyway, name lookups will report ambiguity
+ */
+enum dup_policy {
+	DUP_ERROR,
+	DUP_SUFFIX,
+	DUP_ALLOW,
+};
+
+/* dup_policy - set with the fsmdemo -D option */
+extern enum dup_policy dup_policy;
+
+/*
+ * workers_trace - comma separated names of the workers whose transitions
+ *   are traced, a name with a leading '-' is not traced, NULL to trace
+ *   them all.  Set from the FSM_TRACE environment variable, e.g.
+ *   FSM_TRACE=stoplight or FSM_TRACE=-crosswalk
+ */
+extern const char *workers_trace;
+
+/**
+ * worker_traced - check a worker name against workers_trace
+ * @name: the worker name
+ *
+ * An excluded name is not traced.  Otherwise, if workers_trace lists
+ * any name to include, only those are traced.
+ *
+ * Return: true if the worker transitions are traced
+ */
+inline static bool worker_traced(const char *name)
+{
+	const char *sp = workers_trace;
+	bool include = false;
+	bool neg;
+	size_t len;
+
+	if (NULL == sp)
+		return(true);
+	while (*sp) {
+		neg = ('-' == *sp);
+		if (neg)
+			sp++;
+		len = strcspn(sp, ",");
+		if (len && strlen(name) == len && 0 == strncmp(sp, name, len))
+			return(!neg);
+		if (len && !neg)
+			include = true;
+		sp += len;
+		if (',' == *sp)
+			sp++;
+	}
+	return(!include);
+}
+
+/**
+ * worker_ready_init - initialize the readiness handshake
+ * @w_p: worker, must be called before the thread is created
+ */
+inline static void worker_ready_init(worker_t *w_p)
+{
+	pthread_condattr_t attr;
+
+	w_p->ready = false;
+	w_p->start = START_IDLE;
+	pthread_mutex_init(&w_p->ready_mutex, NULL);
+	pthread_condattr_init(&attr);
+	pthread_condattr_setclock(&attr, CLOCK_MONOTONIC);
+	pthread_cond_init(&w_p->ready_cond, &attr);
+	pthread_condattr_destroy(&attr);
+}
+
+/**
+ * worker_ready - signal the worker thread is ready for events
+ * @w_p: the calling worker
+ *
+ * Called by the worker thread after any initialization and before
+ * entering its event loop.
+ */
+inline static void worker_ready(worker_t *w_p)
+{
+	pthread_mutex_lock(&w_p->ready_mutex);
+	w_p->ready = true;
+	pthread_cond_signal(&w_p->ready_cond);
+	pthread_mutex_unlock(&w_p->ready_mutex);
+}
+
+/**
+ * worker_wait_ready - block until a worker thread calls worker_ready
+ * @w_p: the worker to wait on
+ *
+ * Events enqueued before this are held on the worker event queue
+ * and are consumed once the worker starts looping.
+ */
+inline static void worker_wait_ready(worker_t *w_p)
+{
+	pthread_mutex_lock(&w_p->ready_mutex);
+	while (!w_p->ready)
+		pthread_cond_wait(&w_p->ready_cond, &w_p->ready_mutex);
+	pthread_mutex_unlock(&w_p->ready_mutex);
+	if (debug_flag & DBG_WORKER)
+		printf("%s: ready\n", w_p->name);
+}
+
+/**
+ * worker_find_by_name - lookup a worker by name
+ * @name: worker name
+ *
+ * Return: the worker or NULL if not found or the name is ambiguous
+ * (only possible with the DUP_ALLOW policy)
+ */
+inline static worker_t *worker_find_by_name(const char *name)
+{
+	worker_t *w_p;
+	worker_t *found_p = NULL;
+	bool ambiguous = false;
+
+	workers_rdlock();
+	nl_list_for_each_entry(w_p, &workers.head.list, list) {
+		if (0 == strncmp(w_p->name, name, sizeof(w_p->name))) {
+			if (found_p) {
+				ambiguous = true;
+				break;
+			}
+			found_p = w_p;
+		}
+	}
+	workers_unlock();
+
+	if (ambiguous) {
+		printf("%s: ambiguous worker name\n", name);
+		return(NULL);
+	}
+	return(found_p);
+}
+
+/* max -N suffixes tried for a duplicate name, see worker_name_set */
+#define WORKER_DUP_MAX 100
+
+/**
+ * worker_name_set - name a new worker, applying dup_policy
+ * @w_p: the worker, before its thread is created
+ * @name: requested name
+ *
+ * A duplicate is resolved here rather than in worker_list_add so the
+ * worker thread only ever sees its final name.  A long name is cut to
+ * make room for the suffix, after WORKER_DUP_MAX suffixes in use the
+ * duplicate is an error.
+ */
+inline static void worker_name_set(worker_t *w_p, const char *name)
+{
+	char alt[sizeof(w_p->name)], suffix[12];
+	size_t len, slen;
+	int n;
+
+	strncpy(w_p->name, name, sizeof(w_p->name) - 1);
+	w_p->name[sizeof(w_p->name) - 1] = '\0';
+	if (NULL == worker_find_by_name(w_p->name))
+		return;
+
+	switch (dup_policy) {
+	case DUP_ERROR:
+		fprintf(stderr, "%s: ", w_p->name);
+		die("duplicate worker name");
+		break;
+	case DUP_SUFFIX:
+		for (n = 2; n < 2 + WORKER_DUP_MAX; n++) {
+			slen = snprintf(suffix, sizeof(suffix), "-%d", n);
+			len = strlen(w_p->name);
+			if (len > sizeof(alt) - 1 - slen)
+				len = sizeof(alt) - 1 - slen;
+			memcpy(alt, w_p->name, len);
+			memcpy(alt + len, suffix, slen + 1);
+			if (NULL == worker_find_by_name(alt))
+				break;
+		}
+		if (n == 2 + WORKER_DUP_MAX) {
+			fprintf(stderr, "%s: ", w_p->name);
+			die("no free worker name suffix");
+		}
+		printf("%s: renamed to %s\n", w_p->name, alt);
+		memcpy(w_p->name, alt, sizeof(alt));
+		break;
+	case DUP_ALLOW:
+		break;
+	}
+}
 
 inline static worker_t * worker_create(void *(*startfn_p)(void*), char* name)
 {
-	worker_t *w_p = malloc(sizeof(worker_t));
+	worker_t *w_p = fsm_calloc(1, sizeof(worker_t));
 
-	strncpy(w_p->name, name, sizeof(w_p->name));
+	worker_name_set(w_p, name);
 	w_p->fsm_p = NULL;
+	w_p->initst_p = NULL;
+	w_p->depends_p = NULL;
+	w_p->shadow_p = NULL;
+	w_p->shadow_diffs = 0;
 	w_p->evtq_p = evtq_create();
-	if (0 != pthread_create(&w_p->worker_id, NULL, startfn_p, (void *)w_p))
+	worker_ready_init(w_p);
+	if (0 != thread_create(&w_p->worker_id, startfn_p, (void *)w_p))
 		die("worker_create");
 	return (w_p);
 }
 
 inline static worker_t *worker_fsm_create(void *(*startfn_p)(void*), char* name, fsm_trans_t* fsm_p)
 {
-	worker_t *w_p = malloc(sizeof(worker_t));	
+	worker_t *w_p = fsm_calloc(1, sizeof(worker_t));
 
-	strncpy(w_p->name, name, sizeof(w_p->name));
+	worker_name_set(w_p, name);
 	w_p->fsm_p = fsm_p; /* must set this before starting thread fsm_init */
+	w_p->initst_p = fsm_p->currst_p;
+	w_p->depends_p = NULL;
+	w_p->shadow_p = NULL;
+	w_p->shadow_diffs = 0;
 	w_p->evtq_p = evtq_create();
-	if (0 != pthread_create(&w_p->worker_id, NULL, startfn_p, (void *)w_p))
+	w_p->intq_p = evtq_create();
+	w_p->snap.currst_p = fsm_p->currst_p;
+	w_p->snap.evt_id = E_BAD;
+	worker_ready_init(w_p);
+
+	/* no thread for the single-threaded executor */
+	if (startfn_p && 0 != thread_create(&w_p->worker_id, startfn_p, (void *)w_p))
 		die("worker_create");
 	return(w_p);
 }
@@ -62,11 +503,56 @@ inline static worker_t *worker_fsm_create(void *(*startfn_p)(void*), char* name,
 inline static void worker_list_create()
 {
 	NL_INIT_LIST_HEAD(&workers.head.list);
+	NL_INIT_LIST_HEAD(&workers.retired);
+	pthread_rwlock_init(&workers.lock, NULL);
+	clock_now(&workers.start);
 }
 
-inline static void worker_list_add(worker_t *w_p)
+/**
+ * worker_list_add - register a worker and wait for it to be ready
+ * @w_p: the worker
+ *
+ * The worker is added to the list first so the thread can look itself
+ * up (e.g. worker_get_name) during initialization.
+ *
+ * Return: 0 on success, -1 if all WORKERS_MAX entries are in use, the
+ * worker is not added
+ */
+inline static int worker_list_add(worker_t *w_p)
 {
+	int i;
+
+	w_p->quiet = !worker_traced(w_p->name);
+	workers_wrlock();
+	for (i = 0; i < WORKERS_MAX && workers.tab[i]; i++)
+		;
+	if (i == WORKERS_MAX) {
+		workers_unlock();
+		printf("%s: more than %d workers\n", w_p->name, WORKERS_MAX);
+		return(-1);
+	}
+	w_p->id = ++workers.last_id;
 	nl_list_add_tail(&w_p->list, &workers.head.list);
+	__atomic_store_n(&workers.tab[i], w_p, __ATOMIC_RELEASE);
+	workers_unlock();
+	worker_wait_ready(w_p);
+	return(0);
+}
+
+/**
+ * workers_full - check a worker can be added
+ *
+ * Return: true if all WORKERS_MAX entries are in use, see worker_list_add
+ */
+inline static bool workers_full(void)
+{
+	int i;
+
+	workers_rdlock();
+	for (i = 0; i < WORKERS_MAX && workers.tab[i]; i++)
+		;
+	workers_unlock();
+	return(i == WORKERS_MAX);
 }
 
 inline static worker_t *worker_first()
@@ -79,69 +565,1071 @@ inline static worker_t *worker_first()
 inline static worker_t *worker_find_id(pthread_t id)
 {
 	worker_t *w_p;
+	worker_t *found_p = NULL;
+
+	workers_rdlock();
 	nl_list_for_each_entry(w_p, &workers.head.list, list) {
-		if (w_p->worker_id == id)
-			return(w_p);
+		if (w_p->worker_id == id) {
+			found_p = w_p;
+			break;
+		}
 	}
-	return(NULL);
+	workers_unlock();
+	return(found_p);
 }
 
 inline static worker_t *worker_self(void)
 {
+	if (worker_cur)
+		return(worker_cur);
 	return worker_find_id(pthread_self());
 }
 
+/**
+ * worker_get_name - name of the calling worker
+ *
+ * Return: the worker name, "main" when not called from a worker, e.g.
+ * the CLI or the timer thread
+ */
 inline static const char* worker_get_name(void)
 {
 	worker_t *w_p = worker_self();
 	if (w_p)
 		return w_p->name;
+	return "main";
 }
 
-inline static worker_t *worker_find_by_name(const char *name)
+
+/**
+ * workers_evt_broadcast - send an event to all workers
+ * @evt_id: the event
+ *
+ * A worker whose thread has exited is skipped and the event is kept in
+ * the dead letters for it.
+ */
+inline static void workers_evt_broadcast(fsm_events_t evt_id)
 {
-	worker_t *w_p;	
+	worker_t *w_p;
+
+	workers_rdlock();
 	nl_list_for_each_entry(w_p, &workers.head.list, list) {
-		if (0 == strncmp(w_p->name, name, sizeof(w_p->name)))
-			return(w_p);
+		if (w_p->down)
+			evtq_dead(w_p->name, evt_id, 0, DEAD_DOWN, NULL);
+		else
+			evtq_enqueue(w_p->evtq_p, evt_id);
 	}
-	return(NULL);
+	workers_unlock();
 }
 
-inline static void workers_evt_broadcast(fsm_events_t evt_id)
+/**
+ * workers_evt_broadcast_data - send an event with a payload to all workers
+ * @evt_id: the event
+ * @data: the payload, see fsm_evt_data
+ *
+ * A down worker is skipped as in workers_evt_broadcast.
+ */
+inline static void workers_evt_broadcast_data(fsm_events_t evt_id, uint32_t data)
 {
 	worker_t *w_p;
+
+	workers_rdlock();
 	nl_list_for_each_entry(w_p, &workers.head.list, list) {
+		if (w_p->down)
+			evtq_dead(w_p->name, evt_id, data, DEAD_DOWN, NULL);
+		else
+			evtq_enqueue_data(w_p->evtq_p, evt_id, data);
+	}
+	workers_unlock();
+}
+
+/**
+ * worker_in_group - check if a worker is in a group
+ * @w_p: the worker
+ * @group: group name, without the trailing '/'
+ *
+ * A worker name may be hierarchical, group/name (e.g. intersection1/crosswalk),
+ * and groups may nest (e.g. city/intersection1/crosswalk is in both city and
+ * city/intersection1.)
+ */
+inline static bool worker_in_group(worker_t *w_p, const char *group)
+{
+	size_t len = strlen(group);
+	int i;
+
+	if (0 == strncmp(w_p->name, group, len) && w_p->name[len] == '/')
+		return(true);
+	for (i = 0; i < WORKER_GROUPS; i++) {
+		if (w_p->groups[i] && 0 == strcmp(w_p->groups[i], group))
+			return(true);
+	}
+	return(false);
+}
+
+/**
+ * worker_join - add a worker to a multicast group
+ * @w_p: the worker
+ * @group: group name, without the trailing '/', must stay allocated
+ *
+ * A worker in several groups (e.g. intersection1 and all the crosswalks
+ * of a city) receives a group send to any of them, whatever its name.
+ * Groups are joined before the workers are started.
+ *
+ * Return: 0 on success, -1 if the worker is in WORKER_GROUPS groups
+ */
+inline static int worker_join(worker_t *w_p, const char *group)
+{
+	int i;
+
+	if (worker_in_group(w_p, group))
+		return(0);
+	for (i = 0; i < WORKER_GROUPS; i++) {
+		if (NULL == w_p->groups[i]) {
+			w_p->groups[i] = group;
+			return(0);
+		}
+	}
+	printf("%s: too many groups for %s\n", w_p->name, group);
+	return(-1);
+}
+
+/**
+ * worker_leave - remove a worker from a multicast group
+ * @w_p: the worker
+ * @group: group name given to worker_join
+ */
+inline static void worker_leave(worker_t *w_p, const char *group)
+{
+	int i;
+
+	for (i = 0; i < WORKER_GROUPS; i++) {
+		if (w_p->groups[i] && 0 == strcmp(w_p->groups[i], group))
+			w_p->groups[i] = NULL;
+	}
+}
+
+/**
+ * workers_group_broadcast - send an event to every worker in a group
+ * @group: group name, without the trailing '/'
+ * @evt_id: the event, E_DONE for a group shutdown
+ *
+ * A down member is skipped, and a group with no live member is not an
+ * error of the sender, the event is kept in the dead letters for each.
+ *
+ * Return: the number of workers the event was sent to
+ */
+inline static int workers_group_broadcast(const char *group, fsm_events_t evt_id)
+{
+	worker_t *w_p;
+	char target[sizeof(w_p->name) + 1];
+	int n = 0;
+
+	workers_rdlock();
+	nl_list_for_each_entry(w_p, &workers.head.list, list) {
+		if (!worker_in_group(w_p, group))
+			continue;
+		if (w_p->down) {
+			evtq_dead(w_p->name, evt_id, 0, DEAD_DOWN, NULL);
+			continue;
+		}
 		evtq_enqueue(w_p->evtq_p, evt_id);
+		n++;
+	}
+	workers_unlock();
+	if (0 == n) {
+		snprintf(target, sizeof(target), "%s/", group);
+		evtq_dead(target, evt_id, 0, DEAD_NO_TARGET, NULL);
 	}
+	return(n);
 }
 
-inline static void workers_evtq_destroy(void)
+/**
+ * struct worker_send - an event to send to a named worker
+ * @name: worker name
+ * @evt_id: event to send
+ * @sent: set by workers_evt_send_all when the event is queued
+ */
+struct worker_send {
+	const char *name;
+	fsm_events_t evt_id;
+	bool sent;
+};
+
+/**
+ * workers_evt_send_all - send events to several named workers
+ * @sends: the events and their workers, @sent is the delivery report
+ * @n: number of entries in @sends
+ *
+ * A name ending in '/' is a group (e.g. intersection1/) and the event is
+ * sent to every worker in it, see workers_group_broadcast.
+ *
+ * Every send is attempted, a failed one (unknown, ambiguous or down
+ * worker, empty group) does not stop the remaining sends and is kept in
+ * the dead letters.  This is for an action that
+ * must notify several FSMs (e.g. all crosswalks at an intersection.)
+ *
+ * Return: the number of failed sends
+ */
+inline static int workers_evt_send_all(struct worker_send *sends, int n)
+{
+	worker_t *w_p;
+	char group[sizeof(w_p->name)];
+	size_t len;
+	int i, failed = 0;
+
+	for (i = 0; i < n; i++) {
+		sends[i].sent = false;
+		w_p = NULL;
+		len = strlen(sends[i].name);
+		if (len && len < sizeof(group) && sends[i].name[len-1] == '/') {
+			memcpy(group, sends[i].name, len-1);
+			group[len-1] = '\0';
+			if (workers_group_broadcast(group, sends[i].evt_id)) {
+				sends[i].sent = true;
+			} else {
+				/* the dead letter is kept by workers_group_broadcast */
+				printf("%s: send %s failed\n", sends[i].name,
+				       evt_name[sends[i].evt_id]);
+				failed++;
+			}
+			continue;
+		} else if ((w_p = worker_find_by_name(sends[i].name)) && !w_p->down) {
+			evtq_enqueue(w_p->evtq_p, sends[i].evt_id);
+			sends[i].sent = true;
+			continue;
+		}
+		printf("%s: send %s failed\n", sends[i].name, evt_name[sends[i].evt_id]);
+		evtq_dead(sends[i].name, sends[i].evt_id, 0,
+			  (w_p && w_p->down) ? DEAD_DOWN : DEAD_NO_TARGET, NULL);
+		failed++;
+	}
+	return(failed);
+}
+
+/**
+ * worker_start_update - track the FSM leaving or returning to its init state
+ * @w_p: the calling worker
+ *
+ * Called when the state is published: leaving the init state completes a
+ * pending worker_start, back in the init state the worker can be started
+ * again.  The mutex is only taken when @start changes.
+ */
+inline static void worker_start_update(worker_t *w_p)
+{
+	bool left = (w_p->fsm_p->currst_p != w_p->initst_p);
+
+	if (left == (START_DONE == __atomic_load_n(&w_p->start, __ATOMIC_ACQUIRE)))
+		return;
+	pthread_mutex_lock(&w_p->ready_mutex);
+	if (left || START_DONE == w_p->start) {
+		w_p->start = left ? START_DONE : START_IDLE;
+		pthread_cond_broadcast(&w_p->ready_cond);
+	}
+	pthread_mutex_unlock(&w_p->ready_mutex);
+}
+
+/**
+ * worker_publish - publish the worker FSM state after an event
+ * @w_p: the calling worker
+ * @evt_id: the event just run
+ *
+ * A sequence lock: @snap_seq is odd while the fields are written, a
+ * reader retries if it changed during its copy (see worker_snapshot.)
+ * Only the worker thread writes its snapshot so there is no lock.  The
+ * publication is also counted in workers.pub_busy and workers.pub_gen for
+ * a reader of every snapshot.
+ */
+inline static void worker_publish(worker_t *w_p, fsm_events_t evt_id)
+{
+	uint32_t seq = w_p->snap_seq;
+
+	__atomic_add_fetch(&workers.pub_busy, 1, __ATOMIC_SEQ_CST);
+	__atomic_store_n(&w_p->snap_seq, seq + 1, __ATOMIC_RELAXED);
+	__atomic_thread_fence(__ATOMIC_RELEASE);
+	__atomic_store_n(&w_p->snap.currst_p, w_p->fsm_p->currst_p, __ATOMIC_RELAXED);
+	__atomic_store_n(&w_p->snap.shadowst_p,
+			 w_p->shadow_p ? w_p->shadow_p->currst_p : NULL, __ATOMIC_RELAXED);
+	__atomic_store_n(&w_p->snap.evt_id, evt_id, __ATOMIC_RELAXED);
+	__atomic_store_n(&w_p->snap.shadow_diffs, w_p->shadow_diffs, __ATOMIC_RELAXED);
+	__atomic_store_n(&w_p->snap.ref_diffs, w_p->ref_diffs, __ATOMIC_RELAXED);
+	__atomic_store_n(&w_p->snap_seq, seq + 2, __ATOMIC_RELEASE);
+	__atomic_add_fetch(&workers.pub_gen, 1, __ATOMIC_SEQ_CST);
+	__atomic_sub_fetch(&workers.pub_busy, 1, __ATOMIC_SEQ_CST);
+	worker_start_update(w_p);
+}
+
+/**
+ * worker_snapshot - consistent copy of a worker FSM state
+ * @w_p: the worker, any thread
+ * @snap_p: updated with the last published state
+ *
+ * Safe to call from an FSM action, including for its own worker.
+ */
+inline static void worker_snapshot(worker_t *w_p, struct worker_snap *snap_p)
+{
+	uint32_t seq;
+
+	do {
+		while ((seq = __atomic_load_n(&w_p->snap_seq, __ATOMIC_ACQUIRE)) & 1)
+			sched_yield();
+		snap_p->currst_p = __atomic_load_n(&w_p->snap.currst_p, __ATOMIC_RELAXED);
+		snap_p->shadowst_p = __atomic_load_n(&w_p->snap.shadowst_p, __ATOMIC_RELAXED);
+		snap_p->evt_id = __atomic_load_n(&w_p->snap.evt_id, __ATOMIC_RELAXED);
+		snap_p->shadow_diffs = __atomic_load_n(&w_p->snap.shadow_diffs, __ATOMIC_RELAXED);
+		snap_p->ref_diffs = __atomic_load_n(&w_p->snap.ref_diffs, __ATOMIC_RELAXED);
+		__atomic_thread_fence(__ATOMIC_ACQUIRE);
+	} while (seq != __atomic_load_n(&w_p->snap_seq, __ATOMIC_RELAXED));
+}
+
+/* max copies workers_snapshot makes while the workers keep publishing */
+#define SNAP_TRIES 1000
+
+/**
+ * workers_snapshot - consistent copy of every worker FSM state
+ * @snap: updated with the last published state of each worker, in list
+ *   order
+ *
+ * The copies are retried until no worker published during them, so all
+ * the states are as of one instant.  Called with the workers read lock.
+ *
+ * Return: true if consistent, false if the workers kept publishing for
+ * SNAP_TRIES copies and @snap is only each worker's own snapshot
+ */
+inline static bool workers_snapshot(struct worker_snap *snap)
+{
+	worker_t *w_p;
+	uint32_t gen;
+	int i, tries;
+
+	for (tries = 0; tries < SNAP_TRIES; tries++) {
+		gen = __atomic_load_n(&workers.pub_gen, __ATOMIC_SEQ_CST);
+		if (__atomic_load_n(&workers.pub_busy, __ATOMIC_SEQ_CST)) {
+			sched_yield();
+			continue;
+		}
+		i = 0;
+		nl_list_for_each_entry(w_p, &workers.head.list, list)
+			worker_snapshot(w_p, &snap[i++]);
+		if (0 == __atomic_load_n(&workers.pub_busy, __ATOMIC_SEQ_CST) &&
+		    gen == __atomic_load_n(&workers.pub_gen, __ATOMIC_SEQ_CST))
+			return(true);
+	}
+	i = 0;
+	nl_list_for_each_entry(w_p, &workers.head.list, list)
+		worker_snapshot(w_p, &snap[i++]);
+	return(false);
+}
+
+/**
+ * worker_state - last published FSM current state of a worker
+ * @w_p: the worker, any thread
+ *
+ * A single atomic load, unlike worker_snapshot this never waits so it
+ * can be used from a signal handler.
+ *
+ * Return: the state, NULL if not an FSM worker
+ */
+inline static fsm_state_t *worker_state(worker_t *w_p)
+{
+	return(__atomic_load_n(&w_p->snap.currst_p, __ATOMIC_ACQUIRE));
+}
+
+/**
+ * worker_start_after - declare a startup dependency between workers
+ * @w_p: the dependent worker
+ * @dep_p: worker that must be started first
+ *
+ * e.g. the stoplight depends on the crosswalk being in S:DONT_WALK before
+ * the stoplight starts broadcasting light events.
+ */
+inline static void worker_start_after(worker_t *w_p, worker_t *dep_p)
+{
+	w_p->depends_p = dep_p;
+}
+
+/**
+ * worker_start - send E_INIT to a worker, starting its dependency first
+ * @w_p: worker to start
+ *
+ * For an FSM worker, wait until the FSM has left its init state so
+ * a dependent worker is guaranteed a provisioned peer.  A worker already
+ * started, or being started, is skipped, which also breaks dependency
+ * cycles.  Once its FSM is back in the init state it can be started
+ * again, see worker_start_update.
+ */
+inline static void worker_start(worker_t *w_p)
+{
+	struct timespec abs;
+
+	pthread_mutex_lock(&w_p->ready_mutex);
+	if (START_IDLE != w_p->start) {
+		pthread_mutex_unlock(&w_p->ready_mutex);
+		return;
+	}
+	w_p->start = w_p->fsm_p ? START_PENDING : START_DONE;
+	pthread_mutex_unlock(&w_p->ready_mutex);
+
+	if (w_p->depends_p)
+		worker_start(w_p->depends_p);
+
+	evtq_enqueue(w_p->evtq_p, E_INIT);
+	if (NULL == w_p->fsm_p)
+		return;
+	if (workers_run_fn)
+		workers_run_fn();
+
+	clock_gettime(CLOCK_MONOTONIC, &abs);
+	abs.tv_sec += START_WAIT_MS / 1000;
+	abs.tv_nsec += (START_WAIT_MS % 1000) * 1000000L;
+	if (abs.tv_nsec >= 1000000000L) {
+		abs.tv_sec++;
+		abs.tv_nsec -= 1000000000L;
+	}
+
+	pthread_mutex_lock(&w_p->ready_mutex);
+	while (START_PENDING == w_p->start) {
+		if (ETIMEDOUT == pthread_cond_timedwait(&w_p->ready_cond, &w_p->ready_mutex, &abs)) {
+			printf("%s: start timeout\n", w_p->name);
+			w_p->start = START_IDLE;
+		}
+	}
+	pthread_mutex_unlock(&w_p->ready_mutex);
+}
+
+/**
+ * workers_settled - check every FSM worker has run all its events
+ *
+ * Return: true if every live FSM worker is blocked on an empty queue and
+ * no event was sent during the check
+ */
+inline static bool workers_settled(void)
+{
+	worker_t *w_p;
+	uint32_t activity = evtq_activity();
+	bool idle = true;
+
+	workers_rdlock();
+	nl_list_for_each_entry(w_p, &workers.head.list, list) {
+		if (w_p->fsm_p && !w_p->down && !evtq_idle(w_p->evtq_p)) {
+			idle = false;
+			break;
+		}
+	}
+	workers_unlock();
+	return(idle && activity == evtq_activity());
+}
+
+/**
+ * workers_settle - wait until every FSM worker has run all its events
+ *
+ * Used with the sim clock (see timer_advance) so the events caused by a
+ * timer expiry, and the events they cause in turn, all run before the
+ * next expiry: the order of the run depends on the simulated time only.
+ * A worker whose thread has exited is ignored.  With the single-threaded
+ * executor the events are run here, on the calling thread.
+ */
+inline static void workers_settle(void)
+{
+	if (workers_run_fn) {
+		workers_run_fn();
+		return;
+	}
+
+	while (!workers_settled())
+		nap(1);
+}
+
+/**
+ * workers_start - start all workers in dependency order
+ *
+ * This replaces a broadcast of E_INIT, which starts the workers in
+ * whatever order the threads are scheduled.
+ */
+inline static void workers_start(void)
+{
+	worker_t *w_p;
+	nl_list_for_each_entry(w_p, &workers.head.list, list) {
+		worker_start(w_p);
+	}
+}
+
+/**
+ * worker_shadow - run a modified FSM definition in shadow of the worker FSM
+ * @w_p: the worker
+ * @shadow_p: the shadow FSM table, must start in the same init state
+ */
+inline static void worker_shadow(worker_t *w_p, fsm_trans_t *shadow_p)
+{
+	w_p->shadow_p = shadow_p;
+}
+
+/**
+ * worker_peers - declare the workers the FSM actions send events to
+ * @w_p: the worker
+ * @peers: NULL terminated worker or group names
+ */
+inline static void worker_peers(worker_t *w_p, const char * const *peers)
+{
+	w_p->peers = peers;
+}
+
+/**
+ * worker_reference - check the worker FSM against a reference model
+ * @w_p: the worker
+ * @ref_fn: the model, starting in the worker FSM init state
+ */
+inline static void worker_reference(worker_t *w_p, fsm_model ref_fn)
+{
+	w_p->ref_fn = ref_fn;
+	w_p->ref_st_p = w_p->initst_p;
+}
+
+/**
+ * worker_stale_drop - apply the worker stale policy to a dequeued event
+ * @w_p: the worker
+ * @evt_id: the event
+ * @data: the event payload
+ * @age_ms: msecs the event was queued
+ *
+ * A dropped event is kept in the dead letters.
+ *
+ * Return: true if the event is to be discarded
+ */
+inline static bool worker_stale_drop(worker_t *w_p, fsm_events_t evt_id, uint32_t data,
+				     uint32_t age_ms)
+{
+	if (0 == w_p->stale_ms || age_ms <= w_p->stale_ms || evt_is_ctl(evt_id) ||
+	    STALE_RUN == w_p->stale_policy)
+		return(false);
+
+	printf("%s: stale evt=%s queued %u msec%s\n", w_p->name, evt_name[evt_id],
+	       age_ms, (STALE_DROP == w_p->stale_policy) ? ", dropped" : "");
+	if (STALE_DROP != w_p->stale_policy)
+		return(false);
+	evtq_dead(w_p->name, evt_id, data, DEAD_STALE, NULL);
+	return(true);
+}
+
+/**
+ * worker_unhandled_call - call a function for each unhandled event
+ * @w_p: the worker, before it is started
+ * @fn: called on the worker thread with the fsm_run outcome
+ *
+ * Sets the UNHANDLED_CALL policy.
+ */
+inline static void worker_unhandled_call(worker_t *w_p,
+					 void (*fn)(worker_t *w_p, struct fsm_outcome *out_p))
+{
+	w_p->unhandled_fn = fn;
+	w_p->unhandled_policy = UNHANDLED_CALL;
+}
+
+/**
+ * worker_unhandled - apply the worker unhandled event policy
+ * @w_p: the worker
+ * @out_p: the fsm_run outcome, @out_p->result is FSM_UNHANDLED
+ */
+inline static void worker_unhandled(worker_t *w_p, struct fsm_outcome *out_p)
+{
+	switch (w_p->unhandled_policy) {
+	case UNHANDLED_IGNORE:
+		break;
+	case UNHANDLED_CALL:
+		if (w_p->unhandled_fn)
+			w_p->unhandled_fn(w_p, out_p);
+		break;
+	case UNHANDLED_LOG:
+	case UNHANDLED_ABORT:
+		printf("%s: unhandled evt=%s in %s\n", w_p->name,
+		       evt_name[out_p->evt_id], out_p->from_p->name);
+		evtq_dead(w_p->name, out_p->evt_id, w_p->evt_data, DEAD_UNHANDLED,
+			  out_p->from_p->name);
+		if (UNHANDLED_ABORT == w_p->unhandled_policy)
+			abort();
+		break;
+	case UNHANDLED_DEAD:
+		evtq_dead(w_p->name, out_p->evt_id, w_p->evt_data, DEAD_UNHANDLED,
+			  out_p->from_p->name);
+		break;
+	}
+}
+
+/**
+ * worker_shadow_check - feed an event to the shadow FSM and diff the states
+ * @w_p: the worker
+ * @evt_id: the event just run by the live FSM
+ *
+ * Called from the worker thread after fsm_run.  A difference between the
+ * live and shadow state is reported and counted.  The shadow table shares
+ * the live states, they are compared by pointer since names may repeat.
+ */
+inline static void worker_shadow_check(worker_t *w_p, fsm_events_t evt_id)
+{
+	fsm_state_t *shadowst_p;
+
+	if (NULL == w_p->shadow_p)
+		return;
+
+	shadowst_p = fsm_shadow_run(w_p->shadow_p, evt_id);
+	if (shadowst_p != w_p->fsm_p->currst_p) {
+		w_p->shadow_diffs++;
+		printf("%s: SHADOW diff evt=%s live=%s shadow=%s\n", w_p->name,
+		       evt_name[evt_id], w_p->fsm_p->currst_p->name, shadowst_p->name);
+	}
+}
+
+/**
+ * worker_ref_check - step the reference model and diff it with the FSM
+ * @w_p: the worker
+ * @evt_id: the event just run by the FSM
+ *
+ * Called from the worker thread after fsm_run.  Each divergence is
+ * reported with its step, then the model is resynced to the FSM state so
+ * the next step is checked on its own.
+ */
+inline static void worker_ref_check(worker_t *w_p, fsm_events_t evt_id)
+{
+	fsm_state_t *refst_p;
+	uint32_t step = 0;
+	int i;
+
+	if (NULL == w_p->ref_fn)
+		return;
+
+	refst_p = w_p->ref_fn(w_p->ref_st_p, evt_id);
+	if (refst_p != w_p->fsm_p->currst_p) {
+		for (i = E_BAD; i < E_LAST; i++)
+			step += w_p->evt_cnt[i];
+		w_p->ref_diffs++;
+		printf("%s: REFERENCE diff step=%u evt=%s from=%s live=%s ref=%s\n",
+		       w_p->name, step, evt_name[evt_id], w_p->ref_st_p->name,
+		       w_p->fsm_p->currst_p->name, refst_p->name);
+	}
+	w_p->ref_st_p = w_p->fsm_p->currst_p;
+}
+
+/**
+ * workers_peers_check - check every declared peer is registered
+ *
+ * Called once all workers are added and before they are started, so a
+ * misspelled peer fails the run instead of its events being dropped by
+ * workers_evt_send_all.
+ *
+ * Return: the number of unknown peers
+ */
+inline static int workers_peers_check(void)
+{
+	worker_t *w_p, *p_p;
+	const char * const *name_pp;
+	char group[sizeof(w_p->name)];
+	size_t len;
+	int n, missing = 0;
+
+	nl_list_for_each_entry(w_p, &workers.head.list, list) {
+		for (name_pp = w_p->peers; name_pp && *name_pp; name_pp++) {
+			len = strlen(*name_pp);
+			if (len && len < sizeof(group) && (*name_pp)[len-1] == '/') {
+				memcpy(group, *name_pp, len-1);
+				group[len-1] = '\0';
+				n = 0;
+				nl_list_for_each_entry(p_p, &workers.head.list, list)
+					n += worker_in_group(p_p, group);
+			} else {
+				n = (NULL != worker_find_by_name(*name_pp));
+			}
+			if (0 == n) {
+				printf("%s: unknown peer %s\n", w_p->name, *name_pp);
+				missing++;
+			}
+		}
+	}
+	return(missing);
+}
+
+/**
+ * worker_is_peer - check if a worker is a declared peer of another
+ * @w_p: the worker declaring peers
+ * @p_p: the possible peer
+ */
+inline static bool worker_is_peer(worker_t *w_p, worker_t *p_p)
+{
+	const char * const *name_pp;
+	char group[sizeof(w_p->name)];
+	size_t len;
+
+	for (name_pp = w_p->peers; name_pp && *name_pp; name_pp++) {
+		len = strlen(*name_pp);
+		if (len && len < sizeof(group) && (*name_pp)[len-1] == '/') {
+			memcpy(group, *name_pp, len-1);
+			group[len-1] = '\0';
+			if (worker_in_group(p_p, group))
+				return(true);
+		} else if (0 == strncmp(p_p->name, *name_pp, sizeof(p_p->name))) {
+			return(true);
+		}
+	}
+	return(false);
+}
+
+/**
+ * worker_down - tell the peers of a worker its thread has exited
+ * @arg: the worker
+ *
+ * A pthread cleanup handler of the FSM task, run when the FSM enters
+ * S:DONE or the thread is cancelled.  Unless it is torn down (see
+ * worker_peer_lost), every live worker declaring it as a peer is sent
+ * E_PEER_DOWN, with the id of the dead worker as the
+ * payload (it does not change when workers are spawned or torn down, unlike
+ * the list position), so it can enter a safe mode instead of sending
+ * events nobody will run.  The final FSM state is published first, the
+ * event that stopped the worker never returned to fsm_task.
+ */
+inline static void worker_down(void *arg)
+{
+	worker_t *w_p = (worker_t *) arg;
+	worker_t *p_p;
+
+	if (w_p->fsm_p)
+		worker_publish(w_p, w_p->snap.evt_id);
+	w_p->down = true;
+	workers_rdlock();
+	nl_list_for_each_entry(p_p, &workers.head.list, list) {
+		if (!w_p->retiring && !p_p->down && worker_is_peer(p_p, w_p))
+			evtq_enqueue_data(p_p->evtq_p, E_PEER_DOWN, w_p->id);
+	}
+	workers_unlock();
+	if (debug_flag & DBG_WORKER)
+		printf("%s: down\n", w_p->name);
+}
+
+/**
+ * worker_exit - stop the calling FSM worker, e.g. on S:DONE
+ *
+ * The worker thread exits, running worker_down as its cleanup handler.
+ * The single-threaded executor has no thread to exit, it returns to its
+ * loop instead and marks the worker down.
+ */
+inline static void worker_exit(void)
+{
+	if (worker_exit_fn)
+		worker_exit_fn(worker_cur);
+	pthread_exit(NULL);
+}
+
+/* a worker receiving more than this times the mean events is hot */
+#define ROUTER_HOT_FACTOR 2
+
+/**
+ * show_router - event routing metrics for each worker
+ *
+ * For each target worker: events received and per second, mean msecs an
+ * event waited on the queue, current and maximum queue length.  A worker
+ * receiving more than ROUTER_HOT_FACTOR times the mean of the other
+ * workers is flagged HOT,
+ * it is the one to look at for a routing imbalance (e.g. a group
+ * broadcast that should be a named send.)
+ */
+inline static void show_router(void)
 {
 	worker_t *w_p;
+	struct timespec now;
+	double secs;
+	uint64_t events, total = 0;
+	int i, n = 0;
+
+	clock_now(&now);
+	secs = (now.tv_sec - workers.start.tv_sec) +
+		(now.tv_nsec - workers.start.tv_nsec) / 1e9;
+
+	nl_list_for_each_entry(w_p, &workers.head.list, list) {
+		for (i = E_BAD; i < E_LAST; i++)
+			total += w_p->evt_cnt[i];
+		n++;
+	}
+
+	printf("router %.1f secs\n%-12s %8s %8s %8s %6s %6s\n", secs,
+	       "name", "events", "evt/s", "wait ms", "qlen", "qmax");
+	nl_list_for_each_entry(w_p, &workers.head.list, list) {
+		for (events = 0, i = E_BAD; i < E_LAST; i++)
+			events += w_p->evt_cnt[i];
+		printf("%-12s %8" PRIu64 " %8.1f %8.1f %6u %6d%s\n", w_p->name, events,
+		       secs > 0 ? events / secs : 0.0,
+		       events ? (double)w_p->wait_ms / events : 0.0,
+		       evtq_len(w_p->evtq_p), w_p->evtq_p->max_len,
+		       (n > 1 && events * (n - 1) > ROUTER_HOT_FACTOR * (total - events)) ?
+		       " HOT" : "");
+	}
+}
+
+inline static void workers_evtq_destroy(void)
+{
+	worker_t *w_p, *n_p;
 	nl_list_for_each_entry(w_p, &workers.head.list, list) {
 		evtq_destroy(w_p->evtq_p);
+		evtq_destroy(w_p->intq_p);
+		if (w_p->fsm_owned) {
+			fsm_index_drop(w_p->fsm_p);
+			free(w_p->fsm_p->resume_p);
+			free(w_p->fsm_p);
+		}
+	}
+	nl_list_for_each_entry_safe(w_p, n_p, &workers.retired, retired) {
+		evtq_destroy(w_p->evtq_p);
+		evtq_destroy(w_p->intq_p);
+		if (w_p->fsm_owned) {
+			free(w_p->fsm_p->resume_p);
+			free(w_p->fsm_p);
+		}
+		free(w_p);
 	}
 }	
 
-inline static void join_workers(void)
+/**
+ * worker_peer_lost - check if a worker torn down leaves a peer missing
+ * @w_p: the worker declaring peers
+ * @p_p: the worker torn down, no longer in the list
+ * @groups: the groups @p_p was in before worker_teardown
+ *
+ * A peer declared by name is lost with its worker, a peer group (e.g.
+ * traffic/) only with its last live member, the other members still
+ * receive the events.
+ */
+inline static bool worker_peer_lost(worker_t *w_p, worker_t *p_p,
+				    const char * const *groups)
+{
+	const char * const *name_pp;
+	char group[sizeof(w_p->name)];
+	worker_t *q_p;
+	size_t len;
+	bool in;
+	int i;
+
+	for (name_pp = w_p->peers; name_pp && *name_pp; name_pp++) {
+		len = strlen(*name_pp);
+		if (!len || len >= sizeof(group) || (*name_pp)[len-1] != '/') {
+			if (0 == strncmp(p_p->name, *name_pp, sizeof(p_p->name)))
+				return(true);
+			continue;
+		}
+		memcpy(group, *name_pp, len-1);
+		group[len-1] = '\0';
+		in = (0 == strncmp(p_p->name, group, len-1) && p_p->name[len-1] == '/');
+		for (i = 0; i < WORKER_GROUPS; i++)
+			in |= (groups[i] && 0 == strcmp(groups[i], group));
+		if (!in)
+			continue;
+		in = false;
+		nl_list_for_each_entry(q_p, &workers.head.list, list)
+			in |= (!q_p->down && worker_in_group(q_p, group));
+		if (!in)
+			return(true);
+	}
+	return(false);
+}
+
+/**
+ * worker_teardown - stop a worker at runtime and remove it from the list
+ * @w_p: the worker
+ *
+ * The worker is marked retiring and leaves its groups first, so no
+ * broadcast reaches it while it stops, then it is sent E_DONE and its
+ * thread is joined.  Once it is out of the list, each live worker losing
+ * a peer (see worker_peer_lost) is sent E_PEER_DOWN as by worker_down,
+ * e.g. the stoplight when the last crosswalk is torn down.  A worker that
+ * does not stop within STOP_WAIT_MS (e.g. its table was edited so E_DONE
+ * no longer reaches S:DONE) is not cancelled, it could be holding a queue
+ * or an action mutex: it rejoins its groups and is left running.
+ * Otherwise it is unlinked under the write lock, so no other thread is
+ * walking the list, and kept on the retired list with its FSM table until
+ * exit: a worker found before (e.g. worker_find_by_name) is still valid
+ * and an event sent to it is never run.  Only the table index is dropped,
+ * for the next spawn, and a timer routed to its queue is disarmed.
+ *
+ * Return: 0 on success, -1 if the worker did not stop
+ */
+inline static int worker_teardown(worker_t *w_p)
+{
+	const char *groups[WORKER_GROUPS];
+	worker_t *p_p;
+	uint32_t ms;
+	int i;
+
+	workers_wrlock();
+	w_p->retiring = true;
+	memcpy(groups, w_p->groups, sizeof(groups));
+	memset(w_p->groups, 0, sizeof(w_p->groups));
+	workers_unlock();
+	if (!w_p->down)
+		evtq_enqueue(w_p->evtq_p, E_DONE);
+	if (workers_run_fn) {
+		workers_run_fn();
+	} else {
+		for (ms = 0; w_p->fsm_p && !w_p->down && ms < STOP_WAIT_MS; ms++)
+			nap(1);
+		if (w_p->fsm_p && !w_p->down) {
+			printf("%s: did not stop, left running\n", w_p->name);
+			workers_wrlock();
+			w_p->retiring = false;
+			memcpy(w_p->groups, groups, sizeof(groups));
+			workers_unlock();
+			return(-1);
+		}
+		pthread_join(w_p->worker_id, NULL);
+	}
+
+	workers_wrlock();
+	for (i = 0; i < WORKERS_MAX; i++) {
+		if (workers.tab[i] == w_p)
+			__atomic_store_n(&workers.tab[i], NULL, __ATOMIC_RELEASE);
+	}
+	nl_list_del(&w_p->list);
+	nl_list_add_tail(&w_p->retired, &workers.retired);
+	nl_list_for_each_entry(p_p, &workers.head.list, list) {
+		if (!p_p->down && worker_peer_lost(p_p, w_p, groups))
+			evtq_enqueue_data(p_p->evtq_p, E_PEER_DOWN, w_p->id);
+	}
+	workers_unlock();
+	if (w_p->fsm_p)
+		fsm_index_drop(w_p->fsm_p);
+	unroute_timers(w_p->evtq_p);
+	if (debug_flag & DBG_WORKER)
+		printf("%s: torn down\n", w_p->name);
+	return(0);
+}
+
+/**
+ * workers_shutdown - stop every worker gracefully
+ *
+ * Called once the event sources (CLI, workload, timers) are stopped:
+ * - wait for the FSM workers to run the events already queued, E_DONE
+ *   is a control event and would jump ahead of them
+ * - send E_DONE to every worker
+ * - join every worker thread, a worker that does not stop in time is
+ *   cancelled
+ *
+ * Return: the number of errors, a queue not drained or a worker not
+ * stopping within STOP_WAIT_MS
+ */
+inline static int workers_shutdown(void)
 {
 	worker_t *w_p;
+	uint32_t ms;
+	int errors = 0;
+
+	if (workers_run_fn) {
+		workers_run_fn();
+	} else {
+		for (ms = 0; !workers_settled(); ms++) {
+			if (ms == STOP_WAIT_MS) {
+				nl_list_for_each_entry(w_p, &workers.head.list, list) {
+					if (w_p->fsm_p && !w_p->down && !evtq_idle(w_p->evtq_p))
+						printf("%s: %u events not run\n", w_p->name,
+						       evtq_len(w_p->evtq_p));
+				}
+				errors++;
+				break;
+			}
+			nap(1);
+		}
+	}
+
+	nl_list_for_each_entry(w_p, &workers.head.list, list) {
+		if (!w_p->down)
+			evtq_enqueue(w_p->evtq_p, E_DONE);
+	}
+
+	/* the single-threaded executor has no worker threads */
+	if (workers_run_fn) {
+		workers_run_fn();
+		return(errors);
+	}
+
 	nl_list_for_each_entry(w_p, &workers.head.list, list) {
+		for (ms = 0; w_p->fsm_p && !w_p->down && ms < STOP_WAIT_MS; ms++)
+			nap(1);
+		if (w_p->fsm_p && !w_p->down) {
+			printf("%s: did not stop, cancelled\n", w_p->name);
+			pthread_cancel(w_p->worker_id);
+			errors++;
+		}
 		pthread_join(w_p->worker_id, NULL);
 		if (debug_flag & DBG_WORKER)
 			printf("%s: joined\n", w_p->name);
 	}
+	return(errors);
 }
 
 inline static void show_workers(void)
 {
 	worker_t *w_p;
+	struct worker_snap *snap;
+	int i, j, n = 0;
+	bool consistent;
+
+	workers_rdlock();
+	nl_list_for_each_entry(w_p, &workers.head.list, list)
+		n++;
+	if (NULL == (snap = fsm_calloc(n ? n : 1, sizeof(*snap)))) {
+		workers_unlock();
+		printf("workers: out of memory\n");
+		return;
+	}
 
-	printf("workers\n%-15s:%-12s %-14s\n", "id", "name", "[curr_state]");
+	/* every FSM state as of one instant, taken without stopping the
+	 * workers
+	 */
+	consistent = workers_snapshot(snap);
+
+	printf("workers%s\n%-15s:%-12s %-14s\n",
+	       consistent ? "" : " (still changing, not one instant)",
+	       "id", "name", "[curr_state]");
+	i = 0;
 	nl_list_for_each_entry(w_p, &workers.head.list, list) {
 		printf("%ld:%-12s ", w_p->worker_id, w_p->name);
-		w_p->fsm_p ? printf("%s\n", w_p->fsm_p->currst_p->name) : printf("\n");
+		if (snap[i].currst_p)
+			printf("%s", snap[i].currst_p->name);
+		printf(" id=%u", w_p->id);
+		if (snap[i].shadowst_p)
+			printf(" shadow=%s diffs=%u", snap[i].shadowst_p->name,
+			       snap[i].shadow_diffs);
+		if (w_p->ref_fn)
+			printf(" ref_diffs=%u", snap[i].ref_diffs);
+		for (j = 0; j < WORKER_GROUPS; j++) {
+			if (w_p->groups[j])
+				printf(" %s/", w_p->groups[j]);
+		}
+		if (__atomic_load_n(&w_p->quiet, __ATOMIC_RELAXED))
+			printf(" (quiet)");
+		printf("\n");
+		i++;
+	}
+	workers_unlock();
+	free(snap);
+}
+
+/**
+ * show_explain - describe each FSM worker from its transition table
+ */
+inline static void show_explain(void)
+{
+	worker_t *w_p;
+
+	nl_list_for_each_entry(w_p, &workers.head.list, list) {
+		if (w_p->fsm_p)
+			fsm_explain(w_p->fsm_p, w_p->initst_p,
+				    worker_state(w_p), w_p->name);
+	}
+}
+
+/**
+ * show_prof - show the action profile for each FSM worker
+ */
+inline static void show_prof(void)
+{
+	worker_t *w_p;
+
+	nl_list_for_each_entry(w_p, &workers.head.list, list) {
+		if (w_p->fsm_p)
+			fsm_prof_show(w_p->fsm_p, w_p->name);
 	}
 }
 
@@ -0,0 +1,204 @@
+/*
+ * SPDX-License-Identifier: GPL-2.0
+ * Copyright (C) 2021 Dahetral Systems
+ * Author: David Turvene (dturvene@dahetral.com)
+ *
+ * synthetic event workload generator
+ *
+ * A workload file describes event arrivals, one rule per line:
+ *
+ *  # comment
+ *  seed 42                    # random seed, for repeatable runs
+ *  button poisson 0.2         # mean 0.2 BUTTON events per tick
+ *  button burst 3 20          # 3 BUTTON events every 20 ticks
+ *  button diurnal 0.5 100     # poisson with a peak rate of 0.5 per tick,
+ *                             # rising and falling over 100 ticks
+ *
+ * An event is a single-word event name (case insensitive) or event id.
+ * All times are in ticks so the workload scales with the -t argument.
+ */
+
+#include <math.h>        /* log, cos */
+#include <utils.h>
+#include <workers.h>
+#include <workload.h>
+
+extern uint32_t tick;
+
+static wl_rule_t rules[MAX_RULES];
+static int nrules;
+
+/**
+ * parse_evt - map an event name or id to the event
+ * @str: the event string
+ *
+ * Return: the event id or E_BAD if unknown
+ */
+static fsm_events_t parse_evt(const char *str)
+{
+	int evt_id;
+
+	if (isdigit(*str)) {
+		evt_id = atoi(str);
+		return ((evt_id > E_BAD && evt_id < E_LAST) ? evt_id : E_BAD);
+	}
+	for (evt_id = E_BAD + 1; evt_id < E_LAST; evt_id++) {
+		if (0 == strcasecmp(str, evt_name[evt_id]))
+			return (evt_id);
+	}
+	return (E_BAD);
+}
+
+/**
+ * next_arrival - msecs until the next arrival at a mean rate
+ * @rate: mean events per tick
+ *
+ * Exponential inter-arrival time for a poisson process
+ */
+static double next_arrival(double rate)
+{
+	return (-log(1.0 - drand48()) / rate * tick);
+}
+
+/**
+ * workload_load - parse a workload file into the rules
+ * @fname: workload file name
+ *
+ * Return: number of rules, -1 on a parse error
+ */
+int workload_load(const char *fname)
+{
+	FILE *fin;
+	char buf[120], evt[32], pattern[16];
+	double rate;
+	uint32_t count, period;
+	long seed;
+	wl_rule_t *r_p;
+	int line = 0;
+
+	if (NULL == (fin=fopen(fname, "r")))
+		die("unknown workload fname");
+
+	srand48(0);
+	while (NULL != fgets(buf, sizeof(buf), fin)) {
+		line++;
+
+		/* skip comments, empty lines */
+		if (buf[0] == '#' || 1 != sscanf(buf, "%31s", evt))
+			continue;
+
+		if (0 == strcmp(evt, "seed") && 1 == sscanf(buf, "%*s %ld", &seed)) {
+			srand48(seed);
+			continue;
+		}
+
+		if (nrules == MAX_RULES) {
+			printf("%s:%d: too many rules\n", fname, line);
+			goto err;
+		}
+		r_p = &rules[nrules];
+		memset(r_p, 0, sizeof(*r_p));
+
+		if (E_BAD == (r_p->evt_id = parse_evt(evt))) {
+			printf("%s:%d: unknown event %s\n", fname, line, evt);
+			goto err;
+		}
+
+		if (2 == sscanf(buf, "%*s %15s %lf %u", pattern, &rate, &period) &&
+		    0 == strcmp(pattern, "poisson") && rate > 0) {
+			r_p->pattern = WL_POISSON;
+			r_p->rate = rate;
+		} else if (3 == sscanf(buf, "%*s %15s %u %u", pattern, &count, &period) &&
+			   0 == strcmp(pattern, "burst") && period > 0) {
+			r_p->pattern = WL_BURST;
+			r_p->count = count;
+			r_p->period = period;
+		} else if (3 == sscanf(buf, "%*s %15s %lf %u", pattern, &rate, &period) &&
+			   0 == strcmp(pattern, "diurnal") && rate > 0 && period > 0) {
+			r_p->pattern = WL_DIURNAL;
+			r_p->rate = rate;
+			r_p->period = period;
+		} else {
+			printf("%s:%d: bad rule %s", fname, line, buf);
+			goto err;
+		}
+		nrules++;
+	}
+
+	fclose(fin);
+	return (nrules);
+err:
+	fclose(fin);
+	return (-1);
+}
+
+/**
+ * rule_fire - broadcast the rule events due now and schedule the next
+ * @r_p: the rule
+ * @now_ms: msecs since the workload start
+ */
+static void rule_fire(wl_rule_t *r_p, double now_ms)
+{
+	double phase;
+	uint32_t i;
+
+	switch (r_p->pattern) {
+	case WL_POISSON:
+		workers_evt_broadcast(r_p->evt_id);
+		r_p->next_ms = now_ms + next_arrival(r_p->rate);
+		break;
+	case WL_BURST:
+		for (i = 0; i < r_p->count; i++)
+			workers_evt_broadcast(r_p->evt_id);
+		r_p->next_ms = now_ms + (double)r_p->period * tick;
+		break;
+	case WL_DIURNAL:
+		/* thinning: arrivals at the peak rate, accepted with
+		 * probability rate(t)/peak
+		 */
+		phase = 2 * M_PI * now_ms / ((double)r_p->period * tick);
+		if (drand48() < (1 - cos(phase)) / 2)
+			workers_evt_broadcast(r_p->evt_id);
+		r_p->next_ms = now_ms + next_arrival(r_p->rate);
+		break;
+	}
+}
+
+/**
+ * workload_fn - pthread generating the workload events
+ * @arg: unused
+ *
+ * Sleep until the earliest rule is due, broadcast its event(s) and
+ * reschedule it.  The thread loops until a pthread_cancel is sent to it.
+ */
+void *workload_fn(void *arg)
+{
+	struct timespec start, now;
+	double now_ms;
+	wl_rule_t *next_p;
+	int i;
+
+	clock_now(&start);
+	for (i = 0; i < nrules; i++)
+		rules[i].next_ms = (rules[i].pattern == WL_BURST) ?
+			(double)rules[i].period * tick : next_arrival(rules[i].rate);
+
+	while (nrules) {
+		next_p = &rules[0];
+		for (i = 1; i < nrules; i++) {
+			if (rules[i].next_ms < next_p->next_ms)
+				next_p = &rules[i];
+		}
+
+		clock_now(&now);
+		now_ms = (now.tv_sec - start.tv_sec) * 1e3 +
+			(now.tv_nsec - start.tv_nsec) / 1e6;
+		if (next_p->next_ms > now_ms)
+			clock_nap(next_p->next_ms - now_ms);
+
+		if (debug_flag & DBG_EVTS)
+			printf("workload %s\n", evt_name[next_p->evt_id]);
+		rule_fire(next_p, next_p->next_ms);
+	}
+	return (NULL);
+}
@@ -0,0 +1,50 @@
+/*
+ * SPDX-License-Identifier: GPL-2.0
+ * Copyright (C) 2021 Dahetral Systems
+ * Author: David Turvene (dturvene@dahetral.com)
+ *
+ * synthetic event workload generator
+ */
+
+#ifndef _WORKLOAD_H
+#define _WORKLOAD_H
+
+#include <evtq.h>
+
+/* max number of rules in a workload file */
+#define MAX_RULES 8
+
+/**
+ * enum wl_pattern - how a workload rule generates events
+ * @WL_POISSON: random arrivals at a mean rate
+ * @WL_BURST: a fixed count of events at a fixed period
+ * @WL_DIURNAL: random arrivals with a rate following a raised cosine
+ */
+enum wl_pattern {
+	WL_POISSON,
+	WL_BURST,
+	WL_DIURNAL,
+};
+
+/**
+ * typedef wl_rule - one line of a workload file
+ * @evt_id: event to broadcast
+ * @pattern: arrival pattern
+ * @rate: poisson/diurnal peak events per tick
+ * @count: events per burst
+ * @period: burst period or diurnal period in ticks
+ * @next_ms: time of the next arrival, msecs since the workload start
+ */
+typedef struct wl_rule {
+	fsm_events_t evt_id;
+	enum wl_pattern pattern;
+	double rate;
+	uint32_t count;
+	uint32_t period;
+	double next_ms;
+} wl_rule_t;
+
+extern int workload_load(const char *fname);
+extern void *workload_fn(void *arg);
+
+#endif /* _WORKLOAD_H */